@@ -0,0 +1,495 @@
+use crate::bitbang::Spi;
+use crate::chip::{Avr, AvrReady, Chip, Fuse, FuseField};
+use crate::progress::Tracker;
+use crate::voltage;
+use anyhow::{anyhow, bail, Result};
+use rusb::{DeviceHandle, UsbContext};
+use std::fmt;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+// Serial programming instructions are four bytes. The first two select the
+// operation, then come the address and data, and the chip answers in the
+// last byte.
+const PROGRAMMING_ENABLE: [u8; 2] = [0xac, 0x53];
+const CHIP_ERASE: [u8; 2] = [0xac, 0x80];
+// Not on every part, see `AvrReady`
+const POLL_READY: u8 = 0xf0;
+const READ_SIGNATURE: u8 = 0x30;
+const READ_FLASH_LOW: u8 = 0x20;
+const READ_FLASH_HIGH: u8 = 0x28;
+const LOAD_PAGE_LOW: u8 = 0x40;
+const LOAD_PAGE_HIGH: u8 = 0x48;
+const WRITE_PAGE: u8 = 0x4c;
+const READ_EEPROM: u8 = 0xa0;
+const WRITE_EEPROM: u8 = 0xc0;
+
+// The programming enable echo comes back in the third byte
+const ECHO: u8 = 0x53;
+const ENABLE_TRIES: u32 = 8;
+// After RESET goes low, before programming enable
+const RESET_DELAY: Duration = Duration::from_millis(20);
+
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+const ERASE_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl Fuse {
+    pub const ALL: &'static [Fuse] =
+        &[Fuse::Low, Fuse::High, Fuse::Extended, Fuse::Lock];
+
+    fn read_instruction(self) -> [u8; 2] {
+        match self {
+            Fuse::Low => [0x50, 0x00],
+            Fuse::High => [0x58, 0x08],
+            Fuse::Extended => [0x50, 0x08],
+            Fuse::Lock => [0x58, 0x00],
+        }
+    }
+
+    fn write_instruction(self) -> [u8; 2] {
+        match self {
+            Fuse::Low => [0xac, 0xa0],
+            Fuse::High => [0xac, 0xa8],
+            Fuse::Extended => [0xac, 0xa4],
+            Fuse::Lock => [0xac, 0xe0],
+        }
+    }
+}
+
+impl fmt::Display for Fuse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Fuse::Low => "low",
+            Fuse::High => "high",
+            Fuse::Extended => "extended",
+            Fuse::Lock => "lock",
+        };
+        f.pad(name)
+    }
+}
+
+/// Parses "low", "high", "ext"/"extended" or "lock".
+pub fn parse_fuse(s: &str) -> Result<Fuse> {
+    match s {
+        "low" => Ok(Fuse::Low),
+        "high" => Ok(Fuse::High),
+        "ext" | "extended" => Ok(Fuse::Extended),
+        "lock" => Ok(Fuse::Lock),
+        _ => bail!("Unknown fuse byte: {}", s),
+    }
+}
+
+/// The fuse and lock bytes as read from a chip.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fuses {
+    pub low: u8,
+    pub high: u8,
+    /// `None` on parts without an extended fuse byte
+    pub extended: Option<u8>,
+    pub lock: u8,
+}
+
+impl Fuses {
+    pub fn get(&self, fuse: Fuse) -> Option<u8> {
+        match fuse {
+            Fuse::Low => Some(self.low),
+            Fuse::High => Some(self.high),
+            Fuse::Extended => self.extended,
+            Fuse::Lock => Some(self.lock),
+        }
+    }
+
+    /// One line per byte, with every field decoded.
+    pub fn decode(&self, avr: &Avr) -> String {
+        Fuse::ALL
+            .iter()
+            .filter_map(|&fuse| {
+                self.get(fuse).map(|value| decode(avr, fuse, value))
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// `value` as a fuse byte of `avr`: single bits as programmed or not,
+/// groups of bits as their binary value.
+pub fn decode(avr: &Avr, fuse: Fuse, value: u8) -> String {
+    let fields: Vec<String> = fields(avr, fuse)
+        .map(|field| {
+            let bits = (value >> field.shift) & ((1 << field.width) - 1);
+            if field.width == 1 {
+                let state = if bits == 0 { "programmed" } else { "unset" };
+                format!("{} {}", field.name, state)
+            } else {
+                format!(
+                    "{}={:0width$b}",
+                    field.name,
+                    bits,
+                    width = usize::from(field.width)
+                )
+            }
+        })
+        .collect();
+    format!("{:<8} {:02x}: {}", fuse, value, fields.join(", "))
+}
+
+/// What writing `value` to `fuse` would cost: settings that stop the chip
+/// being programmed over this interface again.
+pub fn warnings(avr: &Avr, fuse: Fuse, value: u8) -> Vec<&'static str> {
+    let programmed = |name| {
+        fields(avr, fuse)
+            .find(|f| f.name == name)
+            .map(|f| value & (1 << f.shift) == 0)
+    };
+    let mut warnings = vec![];
+    if programmed("SPIEN") == Some(false) {
+        warnings.push("SPIEN unset disables serial programming");
+    }
+    if programmed("RSTDISBL") == Some(true) {
+        warnings.push("RSTDISBL turns RESET into an I/O pin, ending ISP");
+    }
+    if programmed("DWEN") == Some(true) {
+        warnings.push("DWEN enables debugWIRE, which takes over RESET");
+    }
+    if fields(avr, fuse).any(|f| f.name == "CKSEL") && value & 0x0f == 0 {
+        warnings.push("CKSEL=0000 needs an external clock to program again");
+    }
+    if fuse == Fuse::Lock && value & 0x03 != 0x03 {
+        warnings.push("Lock bits can only be cleared by a chip erase");
+    }
+    warnings
+}
+
+fn fields(avr: &Avr, fuse: Fuse) -> impl Iterator<Item = &FuseField> {
+    avr.fuse_fields.iter().filter(move |f| f.fuse == fuse)
+}
+
+/// The three signature bytes.
+pub fn signature<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+) -> Result<[u8; 3]> {
+    programming(handle, chip, |spi| {
+        let mut signature = [0; 3];
+        for (i, byte) in signature.iter_mut().enumerate() {
+            *byte = instruction(spi, [READ_SIGNATURE, 0, i as u8, 0])?;
+        }
+        Ok(signature)
+    })
+}
+
+/// Reads the first `len` bytes of flash, words low byte first.
+pub fn read_flash<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    len: usize,
+    tracker: &mut Tracker,
+) -> Result<Vec<u8>> {
+    programming(handle, chip, |spi| {
+        let mut image = Vec::with_capacity(len);
+        for addr in 0..len {
+            let [high, low] = ((addr / 2) as u16).to_be_bytes();
+            let read = if addr % 2 == 0 {
+                READ_FLASH_LOW
+            } else {
+                READ_FLASH_HIGH
+            };
+            image.push(instruction(spi, [read, high, low, 0])?);
+            if (addr + 1) % 64 == 0 || addr + 1 == len {
+                tracker.update(addr as u64 + 1, addr as u32)?;
+            }
+        }
+        Ok(image)
+    })
+}
+
+/// Programs `image` into flash a page at a time, skipping pages that are
+/// all `ff`. Flash must already be erased.
+pub fn program_flash<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    avr: &Avr,
+    image: &[u8],
+    tracker: &mut Tracker,
+) -> Result<()> {
+    let page_size = avr.page_size as usize;
+    programming(handle, chip, |spi| {
+        for (page, bytes) in image.chunks(page_size).enumerate() {
+            let base = page * page_size;
+            if bytes.iter().any(|&b| b != 0xff) {
+                for (i, &byte) in bytes.iter().enumerate() {
+                    let load = if i % 2 == 0 {
+                        LOAD_PAGE_LOW
+                    } else {
+                        LOAD_PAGE_HIGH
+                    };
+                    let [high, low] = ((i / 2) as u16).to_be_bytes();
+                    instruction(spi, [load, high, low, byte])?;
+                }
+                let [high, low] = ((base / 2) as u16).to_be_bytes();
+                instruction(spi, [WRITE_PAGE, high, low, 0])?;
+                wait(spi, avr, Write::Flash)?;
+            }
+            let end = base + bytes.len();
+            tracker.update(end as u64, end as u32 - 1)?;
+        }
+        Ok(())
+    })
+}
+
+pub fn read_eeprom<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    avr: &Avr,
+    tracker: &mut Tracker,
+) -> Result<Vec<u8>> {
+    programming(handle, chip, |spi| {
+        (0..avr.eeprom_size)
+            .map(|addr| {
+                let [_, _, high, low] = addr.to_be_bytes();
+                let byte = instruction(spi, [READ_EEPROM, high, low, 0])?;
+                tracker.update(u64::from(addr) + 1, addr)?;
+                Ok(byte)
+            })
+            .collect()
+    })
+}
+
+/// Writes the bytes of `image` that differ from `current`, one byte write
+/// each. EEPROM needs no erase.
+pub fn program_eeprom<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    avr: &Avr,
+    image: &[u8],
+    current: &[u8],
+    tracker: &mut Tracker,
+) -> Result<()> {
+    programming(handle, chip, |spi| {
+        for (addr, (&wanted, &had)) in image.iter().zip(current).enumerate() {
+            if wanted != had {
+                let [high, low] = (addr as u16).to_be_bytes();
+                instruction(spi, [WRITE_EEPROM, high, low, wanted])?;
+                wait(spi, avr, Write::Eeprom)?;
+            }
+            tracker.update(addr as u64 + 1, addr as u32)?;
+        }
+        Ok(())
+    })
+}
+
+/// Erases flash and EEPROM (unless EESAVE is programmed) and clears the
+/// lock bits. Fuses are left alone.
+pub fn chip_erase<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    avr: &Avr,
+) -> Result<()> {
+    programming(handle, chip, |spi| {
+        instruction(spi, [CHIP_ERASE[0], CHIP_ERASE[1], 0, 0])?;
+        wait(spi, avr, Write::Erase)
+    })
+}
+
+pub fn read_fuses<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    avr: &Avr,
+) -> Result<Fuses> {
+    programming(handle, chip, |spi| {
+        let mut read = |fuse: Fuse| {
+            let [a, b] = fuse.read_instruction();
+            instruction(spi, [a, b, 0, 0])
+        };
+        Ok(Fuses {
+            low: read(Fuse::Low)?,
+            high: read(Fuse::High)?,
+            extended: if avr.extended_fuse {
+                Some(read(Fuse::Extended)?)
+            } else {
+                None
+            },
+            lock: read(Fuse::Lock)?,
+        })
+    })
+}
+
+/// Writes one fuse or lock byte and returns what reads back. Nothing here
+/// asks first; callers are expected to have checked `warnings` with
+/// whoever asked for the write.
+pub fn write_fuse<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    avr: &Avr,
+    fuse: Fuse,
+    value: u8,
+) -> Result<u8> {
+    if fuse == Fuse::Extended && !avr.extended_fuse {
+        bail!("{} has no extended fuse byte", chip.name);
+    }
+    programming(handle, chip, |spi| {
+        let [a, b] = fuse.write_instruction();
+        instruction(spi, [a, b, 0, value])?;
+        wait(spi, avr, Write::Fuse)?;
+        let [a, b] = fuse.read_instruction();
+        instruction(spi, [a, b, 0, 0])
+    })
+}
+
+// One instruction, returning the byte the chip answers with at the end
+fn instruction<T: UsbContext>(spi: &mut Spi<T>, bytes: [u8; 4]) -> Result<u8> {
+    let mut last = 0;
+    for &byte in &bytes {
+        last = spi.transfer(byte)?;
+    }
+    Ok(last)
+}
+
+// What a wait is for, which sets the timeout when polling and the delay
+// otherwise
+#[derive(Clone, Copy, PartialEq)]
+enum Write {
+    Flash,
+    Eeprom,
+    Fuse,
+    Erase,
+}
+
+fn wait<T: UsbContext>(
+    spi: &mut Spi<T>,
+    avr: &Avr,
+    write: Write,
+) -> Result<()> {
+    let delays = match avr.ready {
+        AvrReady::Poll if write == Write::Erase => {
+            return poll(spi, ERASE_TIMEOUT)
+        }
+        AvrReady::Poll => return poll(spi, WRITE_TIMEOUT),
+        AvrReady::Delays(delays) => delays,
+    };
+    sleep(match write {
+        Write::Flash => delays.flash,
+        Write::Eeprom => delays.eeprom,
+        Write::Fuse => delays.fuse,
+        Write::Erase => delays.erase,
+    });
+    Ok(())
+}
+
+fn poll<T: UsbContext>(spi: &mut Spi<T>, timeout: Duration) -> Result<()> {
+    let start = Instant::now();
+    while instruction(spi, [POLL_READY, 0, 0, 0])? & 0x01 > 0 {
+        if start.elapsed() > timeout {
+            bail!("Still busy after {:?}", timeout);
+        }
+    }
+    Ok(())
+}
+
+// Powers the chip with RESET low, gets it into programming mode and runs
+// `f`. The enable instruction is only echoed once the chip is in step with
+// our clock, so each miss gets a RESET pulse and another try. VCC is off
+// and RESET released afterwards, whatever happens.
+fn programming<T: UsbContext, R>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    f: impl FnOnce(&mut Spi<T>) -> Result<R>,
+) -> Result<R> {
+    let level = *chip
+        .vcc
+        .first()
+        .ok_or_else(|| anyhow!("{} has no supply voltage", chip.name))?;
+    let mut spi = Spi::new(handle, &chip.pin_config()?)?;
+    spi.select(true)?;
+    voltage::set_vcc(spi.handle(), chip, level)?;
+    voltage::vcc_on(spi.handle())?;
+
+    let result = enable(&mut spi, chip).and_then(|()| f(&mut spi));
+    spi.select(false)?;
+    voltage::vcc_off(spi.handle())?;
+    result
+}
+
+fn enable<T: UsbContext>(spi: &mut Spi<T>, chip: &Chip) -> Result<()> {
+    for _ in 0..ENABLE_TRIES {
+        sleep(RESET_DELAY);
+        let [a, b] = PROGRAMMING_ENABLE;
+        spi.transfer(a)?;
+        spi.transfer(b)?;
+        let echo = spi.transfer(0)?;
+        spi.transfer(0)?;
+        if echo == ECHO {
+            return Ok(());
+        }
+        spi.select(false)?;
+        spi.select(true)?;
+    }
+    bail!(
+        "{} didn't enter programming mode; check it's seated and that its \
+         clock fuses don't ask for a crystal that isn't there",
+        chip.name
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip::{self, ChipKind};
+
+    fn avr(name: &str) -> &'static Avr {
+        match chip::find(name).unwrap().kind {
+            ChipKind::Avr(a) => a,
+            _ => panic!("{} isn't an AVR", name),
+        }
+    }
+
+    #[test]
+    fn decodes_fields() {
+        assert_eq!(
+            decode(avr("ATmega328P"), Fuse::Low, 0x62),
+            "low      62: CKDIV8 programmed, CKOUT unset, SUT=10, CKSEL=0010"
+        );
+        assert_eq!(
+            decode(avr("ATmega8"), Fuse::High, 0xd9),
+            "high     d9: RSTDISBL unset, WDTON unset, SPIEN programmed, \
+             CKOPT unset, EESAVE unset, BOOTSZ=00, BOOTRST unset"
+        );
+    }
+
+    #[test]
+    fn decodes_only_bytes_the_part_has() {
+        let fuses = Fuses {
+            low: 0xe1,
+            high: 0xd9,
+            extended: None,
+            lock: 0xff,
+        };
+        let decoded = fuses.decode(avr("ATmega8"));
+        assert_eq!(decoded.lines().count(), 3);
+        assert!(!decoded.contains("extended"));
+    }
+
+    #[test]
+    fn warns_about_lockouts() {
+        let a = avr("ATmega328P");
+        assert!(warnings(a, Fuse::High, 0xd9).is_empty());
+        assert_eq!(
+            warnings(a, Fuse::High, 0xf9),
+            vec!["SPIEN unset disables serial programming"]
+        );
+        assert_eq!(warnings(a, Fuse::High, 0x59).len(), 1);
+        assert_eq!(warnings(a, Fuse::High, 0x99).len(), 1);
+        assert_eq!(warnings(a, Fuse::Low, 0x60).len(), 1);
+        assert!(warnings(a, Fuse::Low, 0x62).is_empty());
+        assert_eq!(warnings(a, Fuse::Lock, 0xfc).len(), 1);
+        assert!(warnings(a, Fuse::Lock, 0xff).is_empty());
+    }
+
+    #[test]
+    fn atmega8_waits_instead_of_polling() {
+        assert!(matches!(avr("ATmega8").ready, AvrReady::Delays(_)));
+        for name in &["ATtiny85", "ATtiny2313", "ATmega168", "ATmega328P"] {
+            assert_eq!(avr(name).ready, AvrReady::Poll, "{}", name);
+        }
+    }
+}
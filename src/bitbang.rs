@@ -0,0 +1,258 @@
+use crate::pins::{
+    configure_pins, drive_pins, sense_pins, PinConfig, PinLevels, PinRole,
+};
+use anyhow::{anyhow, Result};
+use rusb::{DeviceHandle, UsbContext};
+
+/// Pin at a time control of the logic drivers, for the serial protocols.
+/// Every level change is one transfer and every sense reads all five
+/// banks, so a bit costs a handful of transfers and a byte a few
+/// milliseconds.
+pub struct BitBang<'a, T: UsbContext> {
+    handle: &'a mut DeviceHandle<T>,
+    levels: PinLevels,
+}
+
+impl<'a, T: UsbContext> BitBang<'a, T> {
+    /// Loads `config` and drives every output high.
+    pub fn new(
+        handle: &'a mut DeviceHandle<T>,
+        config: &PinConfig,
+    ) -> Result<Self> {
+        let mut bus = BitBang {
+            handle,
+            levels: PinLevels(!0),
+        };
+        bus.configure(config)?;
+        Ok(bus)
+    }
+
+    pub fn handle(&mut self) -> &mut DeviceHandle<T> {
+        self.handle
+    }
+
+    /// Reloads the pin drivers, e.g. to turn a data pin round. Output
+    /// levels are kept.
+    pub fn configure(&mut self, config: &PinConfig) -> Result<()> {
+        configure_pins(self.handle, config)?;
+        drive_pins(self.handle, self.levels)
+    }
+
+    pub fn set(&mut self, pin: u8, high: bool) -> Result<()> {
+        self.set_all(&[(pin, high)])
+    }
+
+    /// Changes several pins in a single transfer.
+    pub fn set_all(&mut self, pins: &[(u8, bool)]) -> Result<()> {
+        for &(pin, high) in pins {
+            self.levels.set(pin, high)?;
+        }
+        drive_pins(self.handle, self.levels)
+    }
+
+    pub fn get(&mut self, pin: u8) -> Result<bool> {
+        sense_pins(self.handle)?.is_high(pin)
+    }
+}
+
+/// SPI mode 0 master: the clock idles low, both sides sample on the rising
+/// edge and shift on the falling one. Pins come from the config's
+/// `ChipEnable`, `Clock`, `SerialIn` and `SerialOut` roles.
+pub struct Spi<'a, T: UsbContext> {
+    bus: BitBang<'a, T>,
+    select: u8,
+    clock: u8,
+    mosi: u8,
+    miso: u8,
+}
+
+impl<'a, T: UsbContext> Spi<'a, T> {
+    /// Loads `config` with the chip deselected and the clock low.
+    pub fn new(
+        handle: &'a mut DeviceHandle<T>,
+        config: &PinConfig,
+    ) -> Result<Self> {
+        let pin = |role| {
+            config
+                .pins_with(role)
+                .first()
+                .copied()
+                .ok_or_else(|| anyhow!("No {} pin in the config", role))
+        };
+        let (select, clock) = (pin(PinRole::ChipEnable)?, pin(PinRole::Clock)?);
+        let (mosi, miso) = (pin(PinRole::SerialIn)?, pin(PinRole::SerialOut)?);
+
+        let mut bus = BitBang::new(handle, config)?;
+        bus.set_all(&[(select, true), (clock, false)])?;
+        Ok(Spi {
+            bus,
+            select,
+            clock,
+            mosi,
+            miso,
+        })
+    }
+
+    pub fn handle(&mut self) -> &mut DeviceHandle<T> {
+        self.bus.handle()
+    }
+
+    /// Drives the select pin, low while `active`.
+    pub fn select(&mut self, active: bool) -> Result<()> {
+        self.bus.set(self.select, !active)
+    }
+
+    /// Clocks a byte out MSB first and returns the byte clocked in.
+    pub fn transfer(&mut self, byte: u8) -> Result<u8> {
+        let mut read = 0;
+        for bit in (0..8).rev() {
+            self.clock_out(byte & (1 << bit) > 0)?;
+            if self.bus.get(self.miso)? {
+                read |= 1 << bit;
+            }
+        }
+        self.bus.set(self.clock, false)?;
+        Ok(read)
+    }
+
+    /// Clocks bytes out without sensing, which halves the cost.
+    pub fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        for byte in bytes {
+            for bit in (0..8).rev() {
+                self.clock_out(byte & (1 << bit) > 0)?;
+            }
+        }
+        self.bus.set(self.clock, false)
+    }
+
+    pub fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+        (0..len).map(|_| self.transfer(0x00)).collect()
+    }
+
+    /// A whole transaction: select, write `command`, read `len` bytes and
+    /// deselect.
+    pub fn command(&mut self, command: &[u8], len: usize) -> Result<Vec<u8>> {
+        self.select(true)?;
+        self.write(command)?;
+        let read = self.read(len)?;
+        self.select(false)?;
+        Ok(read)
+    }
+
+    // Falling edge with the bit set up, then the rising edge that latches it
+    fn clock_out(&mut self, bit: bool) -> Result<()> {
+        self.bus.set_all(&[(self.clock, false), (self.mosi, bit)])?;
+        self.bus.set(self.clock, true)
+    }
+}
+
+/// I2C master. SDA is driven while we send and switched to the weak pull-up
+/// while the chip answers, which costs a pin driver reload each way. Pins
+/// come from the config's `Clock` and `SerialData` roles. There's no clock
+/// stretching; nothing this drives is fast enough to need it.
+pub struct I2c<'a, T: UsbContext> {
+    bus: BitBang<'a, T>,
+    driving: PinConfig,
+    released: PinConfig,
+    clock: u8,
+    data: u8,
+    is_released: bool,
+}
+
+impl<'a, T: UsbContext> I2c<'a, T> {
+    /// Loads `config` with both lines idle high.
+    pub fn new(
+        handle: &'a mut DeviceHandle<T>,
+        config: &PinConfig,
+    ) -> Result<Self> {
+        let pin = |role| {
+            config
+                .pins_with(role)
+                .first()
+                .copied()
+                .ok_or_else(|| anyhow!("No {} pin in the config", role))
+        };
+        let (clock, data) = (pin(PinRole::Clock)?, pin(PinRole::SerialData)?);
+        let mut released = config.clone();
+        released.set(data, PinRole::PullUp)?;
+
+        let bus = BitBang::new(handle, config)?;
+        Ok(I2c {
+            bus,
+            driving: config.clone(),
+            released,
+            clock,
+            data,
+            is_released: false,
+        })
+    }
+
+    pub fn handle(&mut self) -> &mut DeviceHandle<T> {
+        self.bus.handle()
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+        self.release(false)?;
+        self.bus.set_all(&[(self.data, true), (self.clock, true)])?;
+        self.bus.set(self.data, false)?;
+        self.bus.set(self.clock, false)
+    }
+
+    pub fn stop(&mut self) -> Result<()> {
+        self.release(false)?;
+        self.bus.set(self.data, false)?;
+        self.bus.set(self.clock, true)?;
+        self.bus.set(self.data, true)
+    }
+
+    /// Sends a byte MSB first. Returns whether the chip acknowledged it.
+    pub fn write(&mut self, byte: u8) -> Result<bool> {
+        self.release(false)?;
+        for bit in (0..8).rev() {
+            self.bus.set_all(&[
+                (self.clock, false),
+                (self.data, byte & (1 << bit) > 0),
+            ])?;
+            self.bus.set(self.clock, true)?;
+        }
+        self.bus.set(self.clock, false)?;
+
+        self.release(true)?;
+        self.bus.set(self.clock, true)?;
+        let ack = !self.bus.get(self.data)?;
+        self.bus.set(self.clock, false)?;
+        Ok(ack)
+    }
+
+    /// Reads a byte, acknowledging it if `ack` (more bytes wanted).
+    pub fn read(&mut self, ack: bool) -> Result<u8> {
+        self.release(true)?;
+        let mut byte = 0;
+        for bit in (0..8).rev() {
+            self.bus.set(self.clock, true)?;
+            if self.bus.get(self.data)? {
+                byte |= 1 << bit;
+            }
+            self.bus.set(self.clock, false)?;
+        }
+
+        self.bus.set(self.data, !ack)?;
+        self.release(false)?;
+        self.bus.set(self.clock, true)?;
+        self.bus.set(self.clock, false)?;
+        Ok(byte)
+    }
+
+    fn release(&mut self, release: bool) -> Result<()> {
+        if self.is_released != release {
+            let config = if release {
+                &self.released
+            } else {
+                &self.driving
+            };
+            self.bus.configure(config)?;
+            self.is_released = release;
+        }
+        Ok(())
+    }
+}
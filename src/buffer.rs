@@ -0,0 +1,306 @@
+use anyhow::{anyhow, bail, Result};
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+// Snapshots kept for undo; each is a whole image, so not too many
+const UNDO_DEPTH: usize = 32;
+// Bytes per line in `view`
+const LINE: usize = 16;
+
+/// An image being edited before programming, with undo. Ranges are
+/// inclusive, as in a hex editor: `fill(0, 0xff, ..)` covers 256 bytes.
+#[derive(Debug, Clone, Default)]
+pub struct Buffer {
+    data: Vec<u8>,
+    undo: Vec<Vec<u8>>,
+}
+
+impl Buffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Replaces the whole image, e.g. with a file or a chip's contents.
+    pub fn replace(&mut self, data: Vec<u8>) {
+        self.snapshot();
+        self.data = data;
+    }
+
+    pub fn fill(&mut self, start: u32, end: u32, value: u8) -> Result<()> {
+        let range = self.range(start, end)?;
+        self.snapshot();
+        self.data[range].iter_mut().for_each(|b| *b = value);
+        Ok(())
+    }
+
+    /// Copies `start..=end` to `dest`. The ranges may overlap.
+    pub fn copy(&mut self, start: u32, end: u32, dest: u32) -> Result<()> {
+        let range = self.range(start, end)?;
+        self.span(dest, range.len())?;
+        self.snapshot();
+        self.data.copy_within(range, dest as usize);
+        Ok(())
+    }
+
+    /// Copies `start..=end` to `dest` and blanks (ff) whatever of the
+    /// source the copy didn't land on.
+    pub fn move_range(
+        &mut self,
+        start: u32,
+        end: u32,
+        dest: u32,
+    ) -> Result<()> {
+        let range = self.range(start, end)?;
+        let target = self.span(dest, range.len())?;
+        self.snapshot();
+        self.data.copy_within(range.clone(), dest as usize);
+        for i in range.filter(|i| !target.contains(i)) {
+            self.data[i] = 0xff;
+        }
+        Ok(())
+    }
+
+    /// Overwrites bytes from `address`.
+    pub fn set(&mut self, address: u32, bytes: &[u8]) -> Result<()> {
+        if bytes.is_empty() {
+            bail!("Nothing to set");
+        }
+        let range = self.span(address, bytes.len())?;
+        self.snapshot();
+        self.data[range].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Flips every bit in `start..=end`.
+    pub fn invert(&mut self, start: u32, end: u32) -> Result<()> {
+        let range = self.range(start, end)?;
+        self.snapshot();
+        self.data[range].iter_mut().for_each(|b| *b = !*b);
+        Ok(())
+    }
+
+    /// Every address `pattern` starts at.
+    pub fn search(&self, pattern: &[u8]) -> Vec<u32> {
+        if pattern.is_empty() {
+            return vec![];
+        }
+        self.data
+            .windows(pattern.len())
+            .enumerate()
+            .filter(|(_, w)| *w == pattern)
+            .map(|(i, _)| i as u32)
+            .collect()
+    }
+
+    /// Swaps the bytes of every 16-bit word, for a chip wired the other
+    /// way round on the bus.
+    pub fn swap16(&mut self) -> Result<()> {
+        let mut data = self.data.clone();
+        swap16(&mut data)?;
+        self.replace(data);
+        Ok(())
+    }
+
+    /// Keeps only `lane` of `ways`, to program one chip of a set.
+    pub fn keep_lane(&mut self, ways: usize, lane: usize) -> Result<()> {
+        let mut lanes = split(&self.data, ways)?;
+        if lane >= ways {
+            bail!("No lane {} in {}", lane, ways);
+        }
+        self.replace(lanes.swap_remove(lane));
+        Ok(())
+    }
+
+    /// Reverts the last change.
+    pub fn undo(&mut self) -> Result<()> {
+        self.data =
+            self.undo.pop().ok_or_else(|| anyhow!("Nothing to undo"))?;
+        Ok(())
+    }
+
+    /// Hex and ASCII of `len` bytes from `start`, sixteen to a line.
+    pub fn view(&self, start: u32, len: u32) -> Result<String> {
+        let range = self.span(start, len.max(1) as usize)?;
+        let mut out = String::new();
+        let first = range.start;
+        for (n, line) in self.data[range].chunks(LINE).enumerate() {
+            let _ = write!(out, "{:08x}:", first + n * LINE);
+            for byte in line {
+                let _ = write!(out, " {:02x}", byte);
+            }
+            let pad = (LINE - line.len()) * 3;
+            let ascii: String = line
+                .iter()
+                .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+                .collect();
+            let _ = writeln!(out, "{:pad$}  {}", "", ascii, pad = pad);
+        }
+        out.pop();
+        Ok(out)
+    }
+
+    fn range(&self, start: u32, end: u32) -> Result<std::ops::Range<usize>> {
+        if start > end {
+            bail!("Range {:#x}-{:#x} is backwards", start, end);
+        }
+        if end as usize >= self.data.len() {
+            bail!(
+                "{:#x} is past the end of the {} byte buffer",
+                end,
+                self.data.len()
+            );
+        }
+        Ok(start as usize..end as usize + 1)
+    }
+
+    // `len` bytes from `start`, which must be at least one
+    fn span(&self, start: u32, len: usize) -> Result<std::ops::Range<usize>> {
+        let end = u32::try_from(len - 1)
+            .ok()
+            .and_then(|n| start.checked_add(n))
+            .ok_or_else(|| {
+                anyhow!(
+                    "{:#x} is past the end of the {} byte buffer",
+                    start,
+                    self.data.len()
+                )
+            })?;
+        self.range(start, end)
+    }
+
+    fn snapshot(&mut self) {
+        if self.undo.len() == UNDO_DEPTH {
+            self.undo.remove(0);
+        }
+        self.undo.push(self.data.clone());
+    }
+}
+
+/// A search or set pattern: text in double quotes, or hex bytes with or
+/// without spaces between them.
+pub fn parse_pattern(s: &str) -> Result<Vec<u8>> {
+    let s = s.trim();
+    if let Some(text) = s.strip_prefix('"') {
+        let text = text
+            .strip_suffix('"')
+            .ok_or_else(|| anyhow!("Unterminated string"))?;
+        return Ok(text.as_bytes().to_vec());
+    }
+    let digits: String = s.split_whitespace().collect();
+    Ok(hex::decode(digits)?)
+}
+
+/// Splits an image for a set of `ways` 8-bit chips on a 16 or 32-bit bus:
+/// lane 0 gets bytes 0, `ways`, 2 * `ways`..., lane 1 the next, and so on.
+pub fn split(image: &[u8], ways: usize) -> Result<Vec<Vec<u8>>> {
+    check_ways(ways)?;
+    if !image.len().is_multiple_of(ways) {
+        bail!("{} bytes don't split evenly {} ways", image.len(), ways);
+    }
+    Ok((0..ways)
+        .map(|lane| image.iter().skip(lane).step_by(ways).copied().collect())
+        .collect())
+}
+
+/// Interleaves lane images back into one, the reverse of `split`.
+pub fn merge(lanes: &[Vec<u8>]) -> Result<Vec<u8>> {
+    check_ways(lanes.len())?;
+    let len = lanes[0].len();
+    if lanes.iter().any(|l| l.len() != len) {
+        bail!("Lanes differ in length");
+    }
+    Ok((0..len)
+        .flat_map(|i| lanes.iter().map(move |l| l[i]))
+        .collect())
+}
+
+/// Swaps the bytes of every 16-bit word.
+pub fn swap16(image: &mut [u8]) -> Result<()> {
+    if !image.len().is_multiple_of(2) {
+        bail!("{} bytes isn't a whole number of words", image.len());
+    }
+    image.chunks_exact_mut(2).for_each(|w| w.swap(0, 1));
+    Ok(())
+}
+
+/// Where `split` puts each lane of `path`: firmware.bin becomes
+/// firmware.0.bin, firmware.1.bin...
+pub fn lane_paths(path: &Path, ways: usize) -> Vec<PathBuf> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    (0..ways)
+        .map(|lane| {
+            let mut name = format!("{}.{}", stem, lane);
+            if let Some(extension) = path.extension() {
+                name.push('.');
+                name.push_str(&extension.to_string_lossy());
+            }
+            path.with_file_name(name)
+        })
+        .collect()
+}
+
+fn check_ways(ways: usize) -> Result<()> {
+    match ways {
+        2 | 4 => Ok(()),
+        _ => bail!("Images split 2 or 4 ways, not {}", ways),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_interleaves() {
+        let lanes = split(&[0, 1, 2, 3, 4, 5, 6, 7], 4).unwrap();
+        assert_eq!(lanes, [vec![0, 4], vec![1, 5], vec![2, 6], vec![3, 7]]);
+    }
+
+    #[test]
+    fn split_then_merge() {
+        let image: Vec<u8> = (0..=255).collect();
+        for &ways in &[2, 4] {
+            assert_eq!(merge(&split(&image, ways).unwrap()).unwrap(), image);
+        }
+    }
+
+    #[test]
+    fn split_checks_ways_and_length() {
+        assert!(split(&[0; 6], 3).is_err());
+        assert!(split(&[0; 6], 4).is_err());
+        assert!(merge(&[vec![0; 2], vec![0; 3]]).is_err());
+    }
+
+    #[test]
+    fn swap16_swaps_words() {
+        let mut image = [0x12, 0x34, 0x56, 0x78];
+        swap16(&mut image).unwrap();
+        assert_eq!(image, [0x34, 0x12, 0x78, 0x56]);
+        assert!(swap16(&mut [0; 3]).is_err());
+    }
+
+    #[test]
+    fn lane_paths_keep_extension() {
+        let paths = lane_paths(Path::new("out/firmware.bin"), 2);
+        assert_eq!(
+            paths,
+            [
+                PathBuf::from("out/firmware.0.bin"),
+                PathBuf::from("out/firmware.1.bin")
+            ]
+        );
+    }
+}
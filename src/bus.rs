@@ -0,0 +1,239 @@
+use crate::chip::{Chip, ReadyCheck};
+use crate::pins::{
+    configure_pins, drive_pins, sense_pins, PinConfig, PinLevels, PinRole,
+    ZIF_PINS,
+};
+use crate::voltage::{self, Vcc};
+use anyhow::{bail, Result};
+use rusb::{DeviceHandle, UsbContext};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Byte wide bus cycles on a parallel chip, built from driving and sensing
+/// pins. Every cycle takes several transfers, so this is far slower than
+/// `read_block`, but it can run any sequence of reads and writes, which
+/// programming needs.
+///
+/// The strobes are active low and idle high. Pulse widths are a minimum:
+/// the strobe stays asserted for at least as long as asked, plus however
+/// long the next transfer takes.
+pub struct ParallelBus<'a, T: UsbContext> {
+    handle: &'a mut DeviceHandle<T>,
+    config: PinConfig,
+    // (bit, ZIF pin)
+    address: Vec<(u8, u8)>,
+    data: Vec<(u8, u8)>,
+    chip_enable: Option<u8>,
+    output_enable: Option<u8>,
+    write_enable: Option<u8>,
+    // Whether the data pins are currently loaded as outputs
+    driving: Option<bool>,
+}
+
+impl<'a, T: UsbContext> ParallelBus<'a, T> {
+    /// A bus over the pins `config` gives address, data and strobe roles.
+    /// The config is loaded straight away with the data pins as inputs, so
+    /// it's in place before any supply is switched on.
+    pub fn new(
+        handle: &'a mut DeviceHandle<T>,
+        config: PinConfig,
+    ) -> Result<Self> {
+        let mut bus = ParallelBus {
+            handle,
+            config: PinConfig::new(),
+            address: vec![],
+            data: vec![],
+            chip_enable: None,
+            output_enable: None,
+            write_enable: None,
+            driving: None,
+        };
+        bus.reconfigure(config)?;
+        Ok(bus)
+    }
+
+    /// Switches to a different config for the same chip, e.g. to take VPP
+    /// off a shared /OE pin for a verify read. It's loaded straight away
+    /// with the data pins as inputs.
+    pub fn reconfigure(&mut self, config: PinConfig) -> Result<()> {
+        self.address.clear();
+        self.data.clear();
+        self.chip_enable = None;
+        self.output_enable = None;
+        self.write_enable = None;
+
+        for pin in 1..=ZIF_PINS {
+            match config.role(pin).unwrap_or(PinRole::HighZ) {
+                PinRole::Address(bit) => self.address.push((bit, pin)),
+                PinRole::Data(bit) => self.data.push((bit, pin)),
+                PinRole::ChipEnable => self.chip_enable = Some(pin),
+                PinRole::OutputEnable => self.output_enable = Some(pin),
+                PinRole::WriteEnable => self.write_enable = Some(pin),
+                _ => {}
+            }
+        }
+
+        self.config = config;
+        self.driving = None;
+        self.direction(false)
+    }
+
+    pub fn handle(&mut self) -> &mut DeviceHandle<T> {
+        self.handle
+    }
+
+    /// Reads the byte at `addr` with /CE and /OE asserted.
+    pub fn read(&mut self, addr: u32) -> Result<u8> {
+        self.direction(false)?;
+        let strobes = [self.chip_enable, self.output_enable];
+        drive_pins(self.handle, self.levels(addr, 0xff, &strobes)?)?;
+        let levels = sense_pins(self.handle)?;
+
+        let mut value = 0;
+        for &(bit, pin) in &self.data {
+            if levels.is_high(pin)? {
+                value |= 1 << bit;
+            }
+        }
+
+        self.idle(addr)?;
+        Ok(value)
+    }
+
+    /// Writes `value` to `addr` with a /WE pulse inside /CE, the way flash
+    /// and EEPROM commands are written. Data is latched on the rising edge.
+    pub fn write(&mut self, addr: u32, value: u8) -> Result<()> {
+        let write_enable = match self.write_enable {
+            Some(pin) => pin,
+            None => bail!("This chip has no /WE pin"),
+        };
+
+        self.direction(true)?;
+        let ce = [self.chip_enable];
+        drive_pins(self.handle, self.levels(addr, value, &ce)?)?;
+        let strobes = [self.chip_enable, Some(write_enable)];
+        drive_pins(self.handle, self.levels(addr, value, &strobes)?)?;
+        drive_pins(self.handle, self.levels(addr, value, &ce)?)?;
+        self.idle(addr)
+    }
+
+    /// An EPROM program pulse: address and data held, /OE high, and /PGM
+    /// pulsed low for `width` inside /CE. Chips without a /PGM pin are
+    /// programmed by pulsing /CE itself.
+    pub fn program_pulse(
+        &mut self,
+        addr: u32,
+        value: u8,
+        width: Duration,
+    ) -> Result<()> {
+        self.direction(true)?;
+        let (held, pulsed) = match self.write_enable {
+            Some(pgm) => (self.chip_enable, Some(pgm)),
+            None => (None, self.chip_enable),
+        };
+
+        drive_pins(self.handle, self.levels(addr, value, &[held])?)?;
+        drive_pins(self.handle, self.levels(addr, value, &[held, pulsed])?)?;
+        sleep(width);
+        drive_pins(self.handle, self.levels(addr, value, &[held])?)?;
+        self.idle(addr)
+    }
+
+    // Deasserts every strobe, keeping the address
+    fn idle(&mut self, addr: u32) -> Result<()> {
+        drive_pins(self.handle, self.levels(addr, 0xff, &[])?)
+    }
+
+    // Loads the config with the data pins as outputs or inputs. Strobes are
+    // released first so the chip isn't driving the pins as they turn round.
+    fn direction(&mut self, drive: bool) -> Result<()> {
+        if self.driving == Some(drive) {
+            return Ok(());
+        }
+
+        drive_pins(self.handle, PinLevels(!0))?;
+        let mut config = self.config.clone();
+        if !drive {
+            for &(_, pin) in &self.data {
+                config.set(pin, PinRole::HighZ)?;
+            }
+        }
+        configure_pins(self.handle, &config)?;
+        self.driving = Some(drive);
+        Ok(())
+    }
+
+    fn levels(
+        &self,
+        addr: u32,
+        value: u8,
+        asserted: &[Option<u8>],
+    ) -> Result<PinLevels> {
+        let mut levels = PinLevels(!0);
+        for &(bit, pin) in &self.address {
+            levels.set(pin, addr & (1 << bit) > 0)?;
+        }
+        for &(bit, pin) in &self.data {
+            levels.set(pin, value & (1 << bit) > 0)?;
+        }
+        for pin in asserted.iter().flatten() {
+            levels.set(*pin, false)?;
+        }
+        Ok(levels)
+    }
+}
+
+/// Loads `chip`'s read mode pins and runs `f` at 5 V, switching VCC off
+/// afterwards whatever happens.
+pub(crate) fn powered<T: UsbContext, R>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    f: impl FnOnce(&mut ParallelBus<T>) -> Result<R>,
+) -> Result<R> {
+    let mut bus = ParallelBus::new(handle, chip.pin_config()?)?;
+    voltage::set_vcc(bus.handle(), chip, Vcc::V5_0)?;
+    voltage::vcc_on(bus.handle())?;
+    let result = f(&mut bus);
+    voltage::vcc_off(bus.handle())?;
+    result
+}
+
+/// Polls until an embedded program or erase at `addr` finishes. `value` is
+/// what the byte should end up as, which data polling compares DQ7 against.
+pub(crate) fn wait_ready<T: UsbContext>(
+    bus: &mut ParallelBus<T>,
+    check: ReadyCheck,
+    addr: u32,
+    value: u8,
+    timeout: Duration,
+) -> Result<()> {
+    if !poll_ready(bus, check, addr, value, timeout)? {
+        bail!("Timed out after {:?} waiting on {:#x}", timeout, addr);
+    }
+    Ok(())
+}
+
+/// `wait_ready`, but a timeout is `Ok(false)` rather than an error.
+pub(crate) fn poll_ready<T: UsbContext>(
+    bus: &mut ParallelBus<T>,
+    check: ReadyCheck,
+    addr: u32,
+    value: u8,
+    timeout: Duration,
+) -> Result<bool> {
+    let start = Instant::now();
+    loop {
+        let done = match check {
+            ReadyCheck::DataPolling => (bus.read(addr)? ^ value) & 0x80 == 0,
+            ReadyCheck::ToggleBit => {
+                (bus.read(addr)? ^ bus.read(addr)?) & 0x40 == 0
+            }
+        };
+        if done {
+            return Ok(true);
+        }
+        if start.elapsed() > timeout {
+            return Ok(false);
+        }
+    }
+}
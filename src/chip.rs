@@ -0,0 +1,1216 @@
+use crate::pins::{zif_pin, PinConfig, PinRole, PinRole::*};
+use crate::voltage::{Vcc, Vpp};
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChipKind {
+    Eprom,
+    Flash(&'static Flash),
+    Eeprom(&'static Eeprom),
+    SpiFlash(&'static SpiFlash),
+    I2cEeprom(&'static I2cEeprom),
+    Microwire(&'static Microwire),
+    Pic(&'static Pic),
+    Avr(&'static Avr),
+    Gal(&'static Gal),
+}
+
+impl ChipKind {
+    /// Whether the chip sits on the parallel address and data bus the
+    /// firmware's block read drives.
+    pub fn is_parallel(self) -> bool {
+        matches!(
+            self,
+            ChipKind::Eprom | ChipKind::Flash(_) | ChipKind::Eeprom(_)
+        )
+    }
+
+    /// Whether bytes can be rewritten in place, without an erase first.
+    pub fn is_rewritable(self) -> bool {
+        matches!(
+            self,
+            ChipKind::Eeprom(_)
+                | ChipKind::I2cEeprom(_)
+                | ChipKind::Microwire(_)
+        )
+    }
+}
+
+/// What's needed to erase and program a JEDEC style flash part.
+#[derive(Debug, PartialEq)]
+pub struct Flash {
+    /// Answer to the product ID command
+    pub signature: Signature,
+    /// Sector sizes in address order. Empty if the chip only has chip
+    /// erase.
+    pub sectors: &'static [u32],
+    pub ready: ReadyCheck,
+}
+
+/// Write parameters of a parallel EEPROM.
+#[derive(Debug, PartialEq)]
+pub struct Eeprom {
+    /// Bytes that can be loaded in one write cycle, 1 for byte write only
+    pub page_size: u32,
+    /// Supports the AA/55 software data protection sequences
+    pub sdp: bool,
+}
+
+/// A 25xx series SPI flash. `spiflash::sfdp` reads what the chip itself
+/// says, and `discovered_spi_flash` builds an entry from it.
+#[derive(Debug, PartialEq)]
+pub struct SpiFlash {
+    /// Answer to the JEDEC ID command
+    pub signature: Signature,
+    pub page_size: u32,
+    /// (size in bytes, opcode) of each erase, smallest first
+    pub erase_types: &'static [(u32, u8)],
+}
+
+/// A 24xx series I2C EEPROM.
+#[derive(Debug, PartialEq)]
+pub struct I2cEeprom {
+    /// Word address bytes sent after the device address, 1 or 2
+    pub address_bytes: u8,
+    /// Bytes one write can fill; a write wraps within its page
+    pub page_size: u32,
+    /// Low device address bits that select a 256 byte block, on parts too
+    /// big for their one byte word address
+    pub block_bits: u8,
+}
+
+/// A 93xx series Microwire EEPROM in one of its two organisations.
+#[derive(Debug, PartialEq)]
+pub struct Microwire {
+    /// 8 or 16, as strapped by the ORG pin
+    pub word_bits: u8,
+    /// Address bits in a command, for that organisation
+    pub address_bits: u8,
+}
+
+/// A midrange (14 bit core) PIC programmed over ICSP. `Chip::size` is
+/// program memory in bytes, two to a word as in MPLAB's hex files.
+#[derive(Debug, PartialEq)]
+pub struct Pic {
+    /// The device ID word at 2006 with the revision bits masked off
+    pub device_id: u16,
+    pub eeprom_size: u32,
+    /// Bulk erases only start with a begin programming command (16F84A)
+    pub erase_begin: bool,
+    /// Factory OSCCAL word at the top of program memory and band gap bits
+    /// in the config word, which have to survive an erase
+    pub calibration: bool,
+}
+
+/// An AVR programmed over its SPI programming interface, with RESET held
+/// low.
+#[derive(Debug, PartialEq)]
+pub struct Avr {
+    /// The three signature bytes, 1e first
+    pub signature: [u8; 3],
+    /// Flash page in bytes
+    pub page_size: u32,
+    pub eeprom_size: u32,
+    pub extended_fuse: bool,
+    /// What the bits of the fuse bytes mean, for decoding
+    pub fuse_fields: &'static [FuseField],
+    pub ready: AvrReady,
+}
+
+/// How to tell an AVR's write or erase has finished.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AvrReady {
+    /// Poll RDY/BSY (f0) until it reads 0
+    Poll,
+    /// No RDY/BSY instruction, wait the datasheet's minimum delays
+    Delays(WriteDelays),
+}
+
+/// The tWD_* minimum wait delays after each kind of write.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WriteDelays {
+    pub flash: Duration,
+    pub eeprom: Duration,
+    pub fuse: Duration,
+    pub erase: Duration,
+}
+
+/// The fuse and lock bytes of an AVR.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fuse {
+    Low,
+    High,
+    Extended,
+    Lock,
+}
+
+/// A named bit or group of bits in a fuse byte. A programmed fuse bit
+/// reads as 0.
+#[derive(Debug, PartialEq)]
+pub struct FuseField {
+    pub fuse: Fuse,
+    pub name: &'static str,
+    pub shift: u8,
+    pub width: u8,
+}
+
+/// A GAL's JEDEC fuse map and how it's laid out in edit mode. Array fuse
+/// `n` is in row `n % rows`, at bit `n / rows` of the row.
+#[derive(Debug, PartialEq)]
+pub struct Gal {
+    /// Fuses in the JEDEC map (QF)
+    pub fuses: u32,
+    /// AND array rows, addressed on RA0-RA5
+    pub rows: u8,
+    /// Bits shifted per array row
+    pub row_bits: u8,
+    pub ues_row: u8,
+    /// First fuse of the 64 bit user electronic signature
+    pub ues_fuse: u32,
+    pub config_row: u8,
+    /// (first fuse, count) runs of the architecture fuses, in shift order
+    pub config_fuses: &'static [(u32, u32)],
+    /// Programmer electronic signature, read only
+    pub pes_row: u8,
+    /// Pulsing this row erases the array, UES and architecture fuses
+    pub erase_row: u8,
+    /// Pulsing this row sets the security fuse
+    pub security_row: u8,
+    /// Programming VPP in mV by PES algorithm, see `gal::programming_vpp`
+    pub vpp: &'static [u32],
+}
+
+/// How to tell an embedded program or erase has finished.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadyCheck {
+    /// DQ7 reads inverted until the operation is done
+    DataPolling,
+    /// DQ6 toggles on every read until the operation is done
+    ToggleBit,
+}
+
+/// Everything we need to know to put a chip in the socket safely.
+#[derive(Debug)]
+pub struct Chip {
+    pub name: &'static str,
+    pub kind: ChipKind,
+    /// Size in bytes
+    pub size: u32,
+    /// Read mode role of each chip pin, starting at pin 1
+    pub pinout: &'static [PinRole],
+    /// VCC levels the chip may be run at
+    pub vcc: &'static [Vcc],
+    /// Programming voltage, if the chip takes one
+    pub vpp: Option<Vpp>,
+}
+
+impl Chip {
+    pub fn pin_count(&self) -> u8 {
+        self.pinout.len() as u8
+    }
+
+    /// Read mode pin configuration with the chip sitting at the bottom of
+    /// the socket.
+    pub fn pin_config(&self) -> Result<PinConfig> {
+        PinConfig::for_dip(self.pinout)
+    }
+
+    /// The signature the chip should report, if the database has one.
+    pub fn signature(&self) -> Option<Signature> {
+        match self.kind {
+            ChipKind::Flash(f) => Some(f.signature),
+            ChipKind::SpiFlash(f) => Some(f.signature),
+            ChipKind::Avr(a) => Some(Signature {
+                manufacturer: a.signature[0],
+                device: u16::from_be_bytes([a.signature[1], a.signature[2]]),
+            }),
+            ChipKind::Pic(p) => Some(Signature {
+                manufacturer: MICROCHIP,
+                device: p.device_id,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The supply range the chip is specified over, for margin verify:
+    /// +-5% for NMOS EPROMs and +-10% for everything else.
+    pub fn vcc_margins(&self) -> Option<(Vcc, Vcc)> {
+        let nmos =
+            self.kind == ChipKind::Eprom && !self.vcc.contains(&Vcc::V6_25);
+        if nmos {
+            Some((Vcc::V4_75, Vcc::V5_25))
+        } else if self.vcc.contains(&Vcc::V5_0) {
+            Some((Vcc::V4_5, Vcc::V5_5))
+        } else if self.vcc.contains(&Vcc::V3_3) {
+            Some((Vcc::V3_0, Vcc::V3_6))
+        } else {
+            None
+        }
+    }
+
+    /// Start address and size of every sector, or `None` for chips without
+    /// sector erase.
+    pub fn sectors(&self) -> Option<Vec<(u32, u32)>> {
+        match self.kind {
+            ChipKind::Flash(flash) if !flash.sectors.is_empty() => {
+                let mut start = 0;
+                Some(
+                    flash
+                        .sectors
+                        .iter()
+                        .map(|&size| {
+                            start += size;
+                            (start - size, size)
+                        })
+                        .collect(),
+                )
+            }
+            _ => None,
+        }
+    }
+
+    /// The ZIF pin the first chip pin with `role` lands on.
+    pub fn zif_pin(&self, role: PinRole) -> Result<u8> {
+        let pin = self
+            .pinout
+            .iter()
+            .position(|r| *r == role)
+            .ok_or_else(|| anyhow!("{} has no {} pin", self.name, role))?;
+        zif_pin(pin as u8 + 1, self.pin_count())
+    }
+}
+
+/// Manufacturer and device codes a chip reports about itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Signature {
+    pub manufacturer: u8,
+    /// One byte for parallel parts, two (type and capacity) for SPI flash
+    pub device: u16,
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "manufacturer {:02x}", self.manufacturer)?;
+        if let Some(name) = manufacturer(self.manufacturer) {
+            write!(f, " ({})", name)?;
+        }
+        write!(f, " device {:02x}", self.device)
+    }
+}
+
+// Manufacturer codes of the parts below and in the database. Atmel's
+// EPROMs and AVRs answer 1e rather than their JEDEC 1f.
+static MANUFACTURERS: &[(u8, &str)] = &[
+    (0x01, "AMD"),
+    (0x1e, "Atmel"),
+    (0x1f, "Atmel"),
+    (0x20, "ST"),
+    (0x29, "Microchip"),
+    (0x89, "Intel"),
+    (0xbf, "SST"),
+    (0xef, "Winbond"),
+];
+
+// EPROM signatures from the makers' datasheets, for `lookup`; the
+// database's EPROM entries are generic and don't carry one. Device codes
+// are only unique together with the manufacturer. Not exhaustive.
+static EPROM_SIGNATURES: &[(u8, u8, &str)] = &[
+    (0x89, 0x08, "2764"),
+    (0x89, 0x07, "27C64"),
+    (0x29, 0x07, "27C64"),
+    (0x01, 0x15, "27C64"),
+    (0x20, 0x08, "27C64"),
+];
+
+/// The maker behind a manufacturer code, if we know it.
+pub fn manufacturer(code: u8) -> Option<&'static str> {
+    MANUFACTURERS
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map(|(_, name)| *name)
+}
+
+/// Database parts that report `signature`.
+pub fn lookup(signature: Signature) -> Vec<&'static Chip> {
+    let eproms = EPROM_SIGNATURES
+        .iter()
+        .filter(|&&(m, d, _)| {
+            Signature {
+                manufacturer: m,
+                device: u16::from(d),
+            } == signature
+        })
+        .filter_map(|(_, _, name)| find(name).ok());
+    CHIPS
+        .iter()
+        .filter(|c| c.signature() == Some(signature))
+        .chain(eproms)
+        .collect()
+}
+
+/// Looks a chip up by name, ignoring case.
+pub fn find(name: &str) -> Result<&'static Chip> {
+    CHIPS
+        .iter()
+        .find(|c| c.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| anyhow!("Unknown chip: {}", name))
+}
+
+/// A database entry for an SPI flash the database doesn't have, from its
+/// JEDEC ID, what its SFDP table says and the supply it was read at.
+/// `erase_types` go smallest first. Entries are leaked like the static ones
+/// they stand in for, so keep the ones made rather than making one per use.
+pub fn discovered_spi_flash(
+    signature: Signature,
+    size: u32,
+    page_size: u32,
+    erase_types: Vec<(u32, u8)>,
+    level: Vcc,
+) -> &'static Chip {
+    let flash = Box::leak(Box::new(SpiFlash {
+        signature,
+        page_size,
+        erase_types: Box::leak(erase_types.into_boxed_slice()),
+    }));
+    Box::leak(Box::new(Chip {
+        name: Box::leak(
+            format!(
+                "SPI-{:02X}{:04X}",
+                signature.manufacturer, signature.device
+            )
+            .into_boxed_str(),
+        ),
+        kind: ChipKind::SpiFlash(flash),
+        size,
+        pinout: SPI_FLASH_8,
+        vcc: single_level(level),
+        vpp: None,
+    }))
+}
+
+/// An entry to read an unknown 8 pin SPI flash's JEDEC ID and SFDP with,
+/// at `level`. Nothing in the socket says what supply the part takes, so
+/// it has to come from its marking.
+pub fn spi_flash_probe(level: Vcc) -> Chip {
+    Chip {
+        name: "SPI flash",
+        // Only the pinout and supply are used
+        kind: ChipKind::SpiFlash(&W25Q80),
+        size: 0,
+        pinout: SPI_FLASH_8,
+        vcc: single_level(level),
+        vpp: None,
+    }
+}
+
+// `level` alone, borrowed from `Vcc::ALL` so it lives as long as the database
+fn single_level(level: Vcc) -> &'static [Vcc] {
+    let i = Vcc::ALL
+        .iter()
+        .position(|&l| l == level)
+        .unwrap_or_default();
+    &Vcc::ALL[i..=i]
+}
+
+const EPROM_2532: &[PinRole] = &[
+    Address(7),
+    Address(6),
+    Address(5),
+    Address(4),
+    Address(3),
+    Address(2),
+    Address(1),
+    Address(0),
+    Data(0),
+    Data(1),
+    Data(2),
+    Gnd,
+    Data(3),
+    Data(4),
+    Data(5),
+    Data(6),
+    Data(7),
+    ChipEnable,
+    Address(10),
+    Vpp,
+    Address(11),
+    Address(9),
+    Address(8),
+    Vcc,
+];
+
+const EPROM_2732: &[PinRole] = &[
+    Address(7),
+    Address(6),
+    Address(5),
+    Address(4),
+    Address(3),
+    Address(2),
+    Address(1),
+    Address(0),
+    Data(0),
+    Data(1),
+    Data(2),
+    Gnd,
+    Data(3),
+    Data(4),
+    Data(5),
+    Data(6),
+    Data(7),
+    ChipEnable,
+    Address(10),
+    // /OE, doubles as VPP when programming
+    OutputEnable,
+    Address(11),
+    Address(9),
+    Address(8),
+    Vcc,
+];
+
+// 28 pin JEDEC EPROMs differ only in pins 1, 22, 26 and 27
+macro_rules! jedec_28 {
+    ($pin1:expr, $pin22:expr, $pin26:expr, $pin27:expr) => {
+        &[
+            $pin1,
+            Address(12),
+            Address(7),
+            Address(6),
+            Address(5),
+            Address(4),
+            Address(3),
+            Address(2),
+            Address(1),
+            Address(0),
+            Data(0),
+            Data(1),
+            Data(2),
+            Gnd,
+            Data(3),
+            Data(4),
+            Data(5),
+            Data(6),
+            Data(7),
+            ChipEnable,
+            Address(10),
+            $pin22,
+            Address(11),
+            Address(9),
+            Address(8),
+            $pin26,
+            $pin27,
+            Vcc,
+        ]
+    };
+}
+
+const EPROM_2764: &[PinRole] = jedec_28!(Vpp, OutputEnable, HighZ, WriteEnable);
+
+// Pin 1 is RDY/BUSY on some 28C64s, left floating
+const EEPROM_28C64: &[PinRole] =
+    jedec_28!(HighZ, OutputEnable, HighZ, WriteEnable);
+
+static EEPROM_64_BYTE_PAGE: Eeprom = Eeprom {
+    page_size: 64,
+    sdp: true,
+};
+
+// SOIC-8 in a DIP adapter. /WP and /HOLD are held high.
+const SPI_FLASH_8: &[PinRole] = &[
+    ChipEnable, SerialOut, PullUp, Gnd, SerialIn, Clock, PullUp, Vcc,
+];
+
+macro_rules! winbond_25q {
+    ($capacity:expr) => {
+        SpiFlash {
+            signature: Signature {
+                manufacturer: 0xef,
+                device: 0x4000 | $capacity,
+            },
+            page_size: 256,
+            erase_types: WINBOND_ERASE,
+        }
+    };
+}
+
+const WINBOND_ERASE: &[(u32, u8)] =
+    &[(0x1000, 0x20), (0x8000, 0x52), (0x10000, 0xd8)];
+
+static W25Q80: SpiFlash = winbond_25q!(0x14);
+static W25Q16: SpiFlash = winbond_25q!(0x15);
+static W25Q32: SpiFlash = winbond_25q!(0x16);
+static W25Q64: SpiFlash = winbond_25q!(0x17);
+
+// A0-A2 and WP tied low, so the device address is 50 and writes are
+// allowed
+const I2C_EEPROM_8: &[PinRole] =
+    &[Gnd, Gnd, Gnd, Gnd, SerialData, Clock, Gnd, Vcc];
+
+static I2C_EEPROM_24C01: I2cEeprom = I2cEeprom {
+    address_bytes: 1,
+    page_size: 8,
+    block_bits: 0,
+};
+static I2C_EEPROM_24C04: I2cEeprom = I2cEeprom {
+    address_bytes: 1,
+    page_size: 16,
+    block_bits: 1,
+};
+static I2C_EEPROM_24C08: I2cEeprom = I2cEeprom {
+    address_bytes: 1,
+    page_size: 16,
+    block_bits: 2,
+};
+static I2C_EEPROM_24C16: I2cEeprom = I2cEeprom {
+    address_bytes: 1,
+    page_size: 16,
+    block_bits: 3,
+};
+static I2C_EEPROM_24C32: I2cEeprom = I2cEeprom {
+    address_bytes: 2,
+    page_size: 32,
+    block_bits: 0,
+};
+static I2C_EEPROM_24C256: I2cEeprom = I2cEeprom {
+    address_bytes: 2,
+    page_size: 64,
+    block_bits: 0,
+};
+
+// CS is active high on these. ORG is strapped low for the x8 ("A") parts
+// and high for the x16 ("B") parts.
+macro_rules! microwire_8 {
+    ($org:expr) => {
+        &[
+            ChipEnable, Clock, SerialIn, SerialOut, Gnd, $org, HighZ, Vcc,
+        ]
+    };
+}
+
+const MICROWIRE_X8: &[PinRole] = microwire_8!(Gnd);
+const MICROWIRE_X16: &[PinRole] = microwire_8!(PullUp);
+
+static MICROWIRE_93C46_X8: Microwire = Microwire {
+    word_bits: 8,
+    address_bits: 7,
+};
+static MICROWIRE_93C46_X16: Microwire = Microwire {
+    word_bits: 16,
+    address_bits: 6,
+};
+// The 56 has the 66's command length, with the top address bit ignored
+static MICROWIRE_93C66_X8: Microwire = Microwire {
+    word_bits: 8,
+    address_bits: 9,
+};
+static MICROWIRE_93C66_X16: Microwire = Microwire {
+    word_bits: 16,
+    address_bits: 8,
+};
+
+// JEDEC code, for reporting PIC device IDs as signatures
+pub const MICROCHIP: u8 = 0x29;
+
+// MCLR on VPP, PGC and PGD as a clock and bidirectional data. PGM (RB4) is
+// held low so the part doesn't go into low voltage programming.
+const PIC_18: &[PinRole] = &[
+    HighZ, HighZ, HighZ, Vpp, Gnd, HighZ, HighZ, HighZ, HighZ, Gnd, HighZ,
+    Clock, SerialData, Vcc, HighZ, HighZ, HighZ, HighZ,
+];
+const PIC_8: &[PinRole] =
+    &[Vcc, HighZ, HighZ, Vpp, HighZ, Clock, SerialData, Gnd];
+
+static PIC16F84A: Pic = Pic {
+    device_id: 0x0560,
+    eeprom_size: 64,
+    erase_begin: true,
+    calibration: false,
+};
+static PIC16F627A: Pic = Pic {
+    device_id: 0x1040,
+    eeprom_size: 128,
+    erase_begin: false,
+    calibration: false,
+};
+static PIC16F628A: Pic = Pic {
+    device_id: 0x1060,
+    eeprom_size: 128,
+    erase_begin: false,
+    calibration: false,
+};
+static PIC16F648A: Pic = Pic {
+    device_id: 0x1100,
+    eeprom_size: 256,
+    erase_begin: false,
+    calibration: false,
+};
+static PIC12F629: Pic = Pic {
+    device_id: 0x0f80,
+    eeprom_size: 128,
+    erase_begin: false,
+    calibration: true,
+};
+static PIC12F675: Pic = Pic {
+    device_id: 0x0fc0,
+    eeprom_size: 128,
+    erase_begin: false,
+    calibration: true,
+};
+
+// RESET is the select line, held low for the whole session
+const AVR_8: &[PinRole] = &[
+    ChipEnable, HighZ, HighZ, Gnd, SerialIn, SerialOut, Clock, Vcc,
+];
+const AVR_20: &[PinRole] = &[
+    ChipEnable, HighZ, HighZ, HighZ, HighZ, HighZ, HighZ, HighZ, HighZ, Gnd,
+    HighZ, HighZ, HighZ, HighZ, HighZ, HighZ, SerialIn, SerialOut, Clock, Vcc,
+];
+// AVCC is powered with VCC, AREF left alone
+const AVR_28: &[PinRole] = &[
+    ChipEnable, HighZ, HighZ, HighZ, HighZ, HighZ, Vcc, Gnd, HighZ, HighZ,
+    HighZ, HighZ, HighZ, HighZ, HighZ, HighZ, SerialIn, SerialOut, Clock, Vcc,
+    HighZ, Gnd, HighZ, HighZ, HighZ, HighZ, HighZ, HighZ,
+];
+
+macro_rules! fuse_fields {
+    ($(($fuse:ident, $name:expr, $shift:expr, $width:expr)),* $(,)?) => {
+        &[$(FuseField {
+            fuse: Fuse::$fuse,
+            name: $name,
+            shift: $shift,
+            width: $width,
+        }),*]
+    };
+}
+
+// The low fuse byte the newer parts share
+macro_rules! clock_fuses {
+    ($(($fuse:ident, $name:expr, $shift:expr, $width:expr)),* $(,)?) => {
+        fuse_fields![
+            (Low, "CKDIV8", 7, 1),
+            (Low, "CKOUT", 6, 1),
+            (Low, "SUT", 4, 2),
+            (Low, "CKSEL", 0, 4),
+            $(($fuse, $name, $shift, $width)),*
+        ]
+    };
+}
+
+static ATTINY85: Avr = Avr {
+    signature: [0x1e, 0x93, 0x0b],
+    page_size: 64,
+    eeprom_size: 512,
+    extended_fuse: true,
+    ready: AvrReady::Poll,
+    fuse_fields: clock_fuses![
+        (High, "RSTDISBL", 7, 1),
+        (High, "DWEN", 6, 1),
+        (High, "SPIEN", 5, 1),
+        (High, "WDTON", 4, 1),
+        (High, "EESAVE", 3, 1),
+        (High, "BODLEVEL", 0, 3),
+        (Extended, "SELFPRGEN", 0, 1),
+        (Lock, "LB", 0, 2),
+    ],
+};
+static ATTINY2313: Avr = Avr {
+    signature: [0x1e, 0x91, 0x0a],
+    page_size: 32,
+    eeprom_size: 128,
+    extended_fuse: true,
+    ready: AvrReady::Poll,
+    fuse_fields: clock_fuses![
+        (High, "DWEN", 7, 1),
+        (High, "EESAVE", 6, 1),
+        (High, "SPIEN", 5, 1),
+        (High, "WDTON", 4, 1),
+        (High, "BODLEVEL", 1, 3),
+        (High, "RSTDISBL", 0, 1),
+        (Extended, "SELFPRGEN", 0, 1),
+        (Lock, "LB", 0, 2),
+    ],
+};
+// ATmega8 predates CKDIV8 and the extended fuse byte
+static ATMEGA8: Avr = Avr {
+    signature: [0x1e, 0x93, 0x07],
+    page_size: 64,
+    eeprom_size: 512,
+    extended_fuse: false,
+    // Predates Poll RDY/BSY, these are its datasheet's tWD_* figures
+    ready: AvrReady::Delays(WriteDelays {
+        flash: Duration::from_micros(4500),
+        eeprom: Duration::from_millis(9),
+        fuse: Duration::from_micros(4500),
+        erase: Duration::from_millis(9),
+    }),
+    fuse_fields: fuse_fields![
+        (Low, "BODLEVEL", 7, 1),
+        (Low, "BODEN", 6, 1),
+        (Low, "SUT", 4, 2),
+        (Low, "CKSEL", 0, 4),
+        (High, "RSTDISBL", 7, 1),
+        (High, "WDTON", 6, 1),
+        (High, "SPIEN", 5, 1),
+        (High, "CKOPT", 4, 1),
+        (High, "EESAVE", 3, 1),
+        (High, "BOOTSZ", 1, 2),
+        (High, "BOOTRST", 0, 1),
+        (Lock, "BLB1", 4, 2),
+        (Lock, "BLB0", 2, 2),
+        (Lock, "LB", 0, 2),
+    ],
+};
+
+// The 168 keeps BODLEVEL in the high fuse and the boot loader fuses in the
+// extended one; the 328P has them the other way round
+static ATMEGA168: Avr = Avr {
+    signature: [0x1e, 0x94, 0x06],
+    page_size: 128,
+    eeprom_size: 512,
+    extended_fuse: true,
+    ready: AvrReady::Poll,
+    fuse_fields: clock_fuses![
+        (High, "RSTDISBL", 7, 1),
+        (High, "DWEN", 6, 1),
+        (High, "SPIEN", 5, 1),
+        (High, "WDTON", 4, 1),
+        (High, "EESAVE", 3, 1),
+        (High, "BODLEVEL", 0, 3),
+        (Extended, "BOOTSZ", 1, 2),
+        (Extended, "BOOTRST", 0, 1),
+        (Lock, "BLB1", 4, 2),
+        (Lock, "BLB0", 2, 2),
+        (Lock, "LB", 0, 2),
+    ],
+};
+static ATMEGA328P: Avr = Avr {
+    signature: [0x1e, 0x95, 0x0f],
+    page_size: 128,
+    eeprom_size: 1024,
+    extended_fuse: true,
+    ready: AvrReady::Poll,
+    fuse_fields: clock_fuses![
+        (High, "RSTDISBL", 7, 1),
+        (High, "DWEN", 6, 1),
+        (High, "SPIEN", 5, 1),
+        (High, "WDTON", 4, 1),
+        (High, "EESAVE", 3, 1),
+        (High, "BOOTSZ", 1, 2),
+        (High, "BOOTRST", 0, 1),
+        (Extended, "BODLEVEL", 0, 3),
+        (Lock, "BLB1", 4, 2),
+        (Lock, "BLB0", 2, 2),
+        (Lock, "LB", 0, 2),
+    ],
+};
+
+// Edit mode: EDIT (pin 2) at VPP, RA0-RA5 on pins 3-8 as address, SDIN and
+// SCLK as a serial input and clock, STB as the active low strobe, P/V as
+// write enable (high to program) and SDOUT sensed. The pin functions and
+// row numbers below follow the afterburner project's tables and haven't been
+// checked against parts in this socket.
+const GAL_20: &[PinRole] = &[
+    SerialIn,
+    Vpp,
+    Address(0),
+    Address(1),
+    Address(2),
+    Address(3),
+    Address(4),
+    Address(5),
+    Clock,
+    Gnd,
+    ChipEnable,
+    SerialOut,
+    HighZ,
+    HighZ,
+    HighZ,
+    HighZ,
+    HighZ,
+    HighZ,
+    WriteEnable,
+    Vcc,
+];
+const GAL_24: &[PinRole] = &[
+    SerialIn,
+    Vpp,
+    Address(0),
+    Address(1),
+    Address(2),
+    Address(3),
+    Address(4),
+    Address(5),
+    Clock,
+    HighZ,
+    ChipEnable,
+    Gnd,
+    HighZ,
+    SerialOut,
+    HighZ,
+    HighZ,
+    HighZ,
+    HighZ,
+    HighZ,
+    HighZ,
+    HighZ,
+    HighZ,
+    WriteEnable,
+    Vcc,
+];
+
+// XOR 2048-2055, AC1 2120-2127, PTD 2128-2191, SYN 2192, AC0 2193
+static GAL16V8: Gal = Gal {
+    fuses: 2194,
+    rows: 32,
+    row_bits: 64,
+    ues_row: 32,
+    ues_fuse: 2056,
+    config_row: 60,
+    config_fuses: &[(2128, 64), (2120, 8), (2048, 8), (2192, 2)],
+    pes_row: 58,
+    erase_row: 63,
+    security_row: 61,
+    vpp: &[15750, 15750, 16500, 14500, 14000],
+};
+// S0/S1 of the ten OLMCs, 5808-5827
+static GAL22V10: Gal = Gal {
+    fuses: 5892,
+    rows: 44,
+    row_bits: 132,
+    ues_row: 44,
+    ues_fuse: 5828,
+    config_row: 16,
+    config_fuses: &[(5808, 20)],
+    pes_row: 58,
+    erase_row: 61,
+    security_row: 62,
+    vpp: &[16500, 15750, 14500, 14000],
+};
+
+const NMOS: &[Vcc] = &[Vcc::V5_0];
+const CMOS: &[Vcc] = &[Vcc::V5_0, Vcc::V6_25];
+const FIVE_VOLT: &[Vcc] = &[Vcc::V5_0];
+const LOW_VOLTAGE: &[Vcc] = &[Vcc::V3_3];
+const WIDE_RANGE: &[Vcc] = &[Vcc::V5_0, Vcc::V3_3];
+
+pub static CHIPS: &[Chip] = &[
+    Chip {
+        name: "2532",
+        kind: ChipKind::Eprom,
+        size: 0x1000,
+        pinout: EPROM_2532,
+        vcc: NMOS,
+        vpp: Some(Vpp::V25_0),
+    },
+    Chip {
+        name: "2732",
+        kind: ChipKind::Eprom,
+        size: 0x1000,
+        pinout: EPROM_2732,
+        vcc: NMOS,
+        vpp: Some(Vpp::V25_0),
+    },
+    Chip {
+        name: "2732A",
+        kind: ChipKind::Eprom,
+        size: 0x1000,
+        pinout: EPROM_2732,
+        vcc: NMOS,
+        vpp: Some(Vpp::V21_0),
+    },
+    Chip {
+        name: "2764",
+        kind: ChipKind::Eprom,
+        size: 0x2000,
+        pinout: EPROM_2764,
+        vcc: NMOS,
+        vpp: Some(Vpp::V21_0),
+    },
+    Chip {
+        name: "27C64",
+        kind: ChipKind::Eprom,
+        size: 0x2000,
+        pinout: EPROM_2764,
+        vcc: CMOS,
+        vpp: Some(Vpp::V12_5),
+    },
+    Chip {
+        name: "28C64",
+        kind: ChipKind::Eeprom(&EEPROM_64_BYTE_PAGE),
+        size: 0x2000,
+        pinout: EEPROM_28C64,
+        vcc: FIVE_VOLT,
+        vpp: None,
+    },
+    Chip {
+        name: "W25Q80",
+        kind: ChipKind::SpiFlash(&W25Q80),
+        size: 0x100000,
+        pinout: SPI_FLASH_8,
+        vcc: LOW_VOLTAGE,
+        vpp: None,
+    },
+    Chip {
+        name: "W25Q16",
+        kind: ChipKind::SpiFlash(&W25Q16),
+        size: 0x200000,
+        pinout: SPI_FLASH_8,
+        vcc: LOW_VOLTAGE,
+        vpp: None,
+    },
+    Chip {
+        name: "W25Q32",
+        kind: ChipKind::SpiFlash(&W25Q32),
+        size: 0x400000,
+        pinout: SPI_FLASH_8,
+        vcc: LOW_VOLTAGE,
+        vpp: None,
+    },
+    Chip {
+        name: "W25Q64",
+        kind: ChipKind::SpiFlash(&W25Q64),
+        size: 0x800000,
+        pinout: SPI_FLASH_8,
+        vcc: LOW_VOLTAGE,
+        vpp: None,
+    },
+    Chip {
+        name: "24C01",
+        kind: ChipKind::I2cEeprom(&I2C_EEPROM_24C01),
+        size: 0x80,
+        pinout: I2C_EEPROM_8,
+        vcc: WIDE_RANGE,
+        vpp: None,
+    },
+    Chip {
+        name: "24C02",
+        kind: ChipKind::I2cEeprom(&I2C_EEPROM_24C01),
+        size: 0x100,
+        pinout: I2C_EEPROM_8,
+        vcc: WIDE_RANGE,
+        vpp: None,
+    },
+    Chip {
+        name: "24C04",
+        kind: ChipKind::I2cEeprom(&I2C_EEPROM_24C04),
+        size: 0x200,
+        pinout: I2C_EEPROM_8,
+        vcc: WIDE_RANGE,
+        vpp: None,
+    },
+    Chip {
+        name: "24C08",
+        kind: ChipKind::I2cEeprom(&I2C_EEPROM_24C08),
+        size: 0x400,
+        pinout: I2C_EEPROM_8,
+        vcc: WIDE_RANGE,
+        vpp: None,
+    },
+    Chip {
+        name: "24C16",
+        kind: ChipKind::I2cEeprom(&I2C_EEPROM_24C16),
+        size: 0x800,
+        pinout: I2C_EEPROM_8,
+        vcc: WIDE_RANGE,
+        vpp: None,
+    },
+    Chip {
+        name: "24C32",
+        kind: ChipKind::I2cEeprom(&I2C_EEPROM_24C32),
+        size: 0x1000,
+        pinout: I2C_EEPROM_8,
+        vcc: WIDE_RANGE,
+        vpp: None,
+    },
+    Chip {
+        name: "24C64",
+        kind: ChipKind::I2cEeprom(&I2C_EEPROM_24C32),
+        size: 0x2000,
+        pinout: I2C_EEPROM_8,
+        vcc: WIDE_RANGE,
+        vpp: None,
+    },
+    Chip {
+        name: "24C256",
+        kind: ChipKind::I2cEeprom(&I2C_EEPROM_24C256),
+        size: 0x8000,
+        pinout: I2C_EEPROM_8,
+        vcc: WIDE_RANGE,
+        vpp: None,
+    },
+    Chip {
+        name: "93C46A",
+        kind: ChipKind::Microwire(&MICROWIRE_93C46_X8),
+        size: 0x80,
+        pinout: MICROWIRE_X8,
+        vcc: WIDE_RANGE,
+        vpp: None,
+    },
+    Chip {
+        name: "93C46B",
+        kind: ChipKind::Microwire(&MICROWIRE_93C46_X16),
+        size: 0x80,
+        pinout: MICROWIRE_X16,
+        vcc: WIDE_RANGE,
+        vpp: None,
+    },
+    Chip {
+        name: "93C56A",
+        kind: ChipKind::Microwire(&MICROWIRE_93C66_X8),
+        size: 0x100,
+        pinout: MICROWIRE_X8,
+        vcc: WIDE_RANGE,
+        vpp: None,
+    },
+    Chip {
+        name: "93C56B",
+        kind: ChipKind::Microwire(&MICROWIRE_93C66_X16),
+        size: 0x100,
+        pinout: MICROWIRE_X16,
+        vcc: WIDE_RANGE,
+        vpp: None,
+    },
+    Chip {
+        name: "93C66A",
+        kind: ChipKind::Microwire(&MICROWIRE_93C66_X8),
+        size: 0x200,
+        pinout: MICROWIRE_X8,
+        vcc: WIDE_RANGE,
+        vpp: None,
+    },
+    Chip {
+        name: "93C66B",
+        kind: ChipKind::Microwire(&MICROWIRE_93C66_X16),
+        size: 0x200,
+        pinout: MICROWIRE_X16,
+        vcc: WIDE_RANGE,
+        vpp: None,
+    },
+    Chip {
+        name: "PIC16F84A",
+        kind: ChipKind::Pic(&PIC16F84A),
+        size: 0x800,
+        pinout: PIC_18,
+        vcc: FIVE_VOLT,
+        vpp: Some(Vpp::V12_5),
+    },
+    Chip {
+        name: "PIC16F627A",
+        kind: ChipKind::Pic(&PIC16F627A),
+        size: 0x800,
+        pinout: PIC_18,
+        vcc: FIVE_VOLT,
+        vpp: Some(Vpp::V12_5),
+    },
+    Chip {
+        name: "PIC16F628A",
+        kind: ChipKind::Pic(&PIC16F628A),
+        size: 0x1000,
+        pinout: PIC_18,
+        vcc: FIVE_VOLT,
+        vpp: Some(Vpp::V12_5),
+    },
+    Chip {
+        name: "PIC16F648A",
+        kind: ChipKind::Pic(&PIC16F648A),
+        size: 0x2000,
+        pinout: PIC_18,
+        vcc: FIVE_VOLT,
+        vpp: Some(Vpp::V12_5),
+    },
+    Chip {
+        name: "PIC12F629",
+        kind: ChipKind::Pic(&PIC12F629),
+        size: 0x800,
+        pinout: PIC_8,
+        vcc: FIVE_VOLT,
+        vpp: Some(Vpp::V12_5),
+    },
+    Chip {
+        name: "PIC12F675",
+        kind: ChipKind::Pic(&PIC12F675),
+        size: 0x800,
+        pinout: PIC_8,
+        vcc: FIVE_VOLT,
+        vpp: Some(Vpp::V12_5),
+    },
+    Chip {
+        name: "ATtiny85",
+        kind: ChipKind::Avr(&ATTINY85),
+        size: 0x2000,
+        pinout: AVR_8,
+        vcc: WIDE_RANGE,
+        vpp: None,
+    },
+    Chip {
+        name: "ATtiny2313",
+        kind: ChipKind::Avr(&ATTINY2313),
+        size: 0x800,
+        pinout: AVR_20,
+        vcc: WIDE_RANGE,
+        vpp: None,
+    },
+    Chip {
+        name: "ATmega8",
+        kind: ChipKind::Avr(&ATMEGA8),
+        size: 0x2000,
+        pinout: AVR_28,
+        vcc: FIVE_VOLT,
+        vpp: None,
+    },
+    Chip {
+        name: "ATmega168",
+        kind: ChipKind::Avr(&ATMEGA168),
+        size: 0x4000,
+        pinout: AVR_28,
+        vcc: WIDE_RANGE,
+        vpp: None,
+    },
+    Chip {
+        name: "ATmega328P",
+        kind: ChipKind::Avr(&ATMEGA328P),
+        size: 0x8000,
+        pinout: AVR_28,
+        vcc: WIDE_RANGE,
+        vpp: None,
+    },
+    Chip {
+        name: "GAL16V8",
+        kind: ChipKind::Gal(&GAL16V8),
+        size: 275,
+        pinout: GAL_20,
+        vcc: FIVE_VOLT,
+        // From the PES, see `gal::programming_vpp`
+        vpp: None,
+    },
+    Chip {
+        name: "GAL22V10",
+        kind: ChipKind::Gal(&GAL22V10),
+        size: 737,
+        pinout: GAL_24,
+        vcc: FIVE_VOLT,
+        // From the PES, see `gal::programming_vpp`
+        vpp: None,
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::READ_LIMIT;
+
+    #[test]
+    fn parallel_parts_fit_block_reads() {
+        for chip in CHIPS.iter().filter(|c| c.kind.is_parallel()) {
+            assert!(chip.size <= READ_LIMIT, "{} is too big", chip.name);
+        }
+    }
+
+    #[test]
+    fn eprom_signatures_name_database_parts() {
+        for (_, _, name) in EPROM_SIGNATURES {
+            assert!(find(name).is_ok(), "{} isn't in the database", name);
+        }
+    }
+}
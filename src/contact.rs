@@ -0,0 +1,309 @@
+use crate::chip::Chip;
+use crate::pins::{
+    configure_pins, sense_pins, zif_pin, PinConfig, PinLevels, PinRole,
+    ZIF_PINS,
+};
+use crate::voltage::{vcc_off, vpp_off};
+use anyhow::Result;
+use rusb::{DeviceHandle, UsbContext};
+use std::fmt;
+
+const ROWS: u8 = ZIF_PINS / 2;
+
+/// Result of `contact_check`. Pins are ZIF pin numbers.
+#[derive(Debug)]
+pub struct ContactReport {
+    pub chip: &'static Chip,
+    /// Footprint pins that didn't read as touching a chip
+    pub open: Vec<u8>,
+    /// Pins outside the footprint that read as touching a chip
+    pub stray: Vec<u8>,
+    /// Rows the chip appears to be shifted by. Chips belong at the bottom of
+    /// the socket so this is always negative, towards the lever.
+    pub offset: Option<i8>,
+    /// The chip's ground pin behaves like its supply pin
+    pub reversed: bool,
+    /// No pin conducted to ground at all
+    pub empty: bool,
+}
+
+impl ContactReport {
+    pub fn is_ok(&self) -> bool {
+        !self.empty
+            && self.open.is_empty()
+            && self.stray.is_empty()
+            && self.offset.is_none()
+            && !self.reversed
+    }
+}
+
+/// Checks `chip` is seated the right way round, at the bottom of the socket,
+/// with every pin making contact. Only weak pull-ups and ground are applied;
+/// VCC and VPP are switched off first.
+///
+/// An unpowered chip clamps a pulled up pin low through the protection
+/// diode to its supply rail, which its own circuitry holds near ground, so
+/// with the chip's GND pin grounded and everything else pulled up, seated
+/// pins read low and empty positions read high. If nothing reads low the
+/// socket is empty and that's all that's reported. Orientation is checked
+/// separately by grounding the VCC pin and pulling up GND: the GND-to-VCC
+/// body diode clamps it low only if the chip is the right way round. It's
+/// only called reversed if the pin at the VCC position also conducted to
+/// ground, i.e. the chip's own ground is sitting there.
+pub fn contact_check<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &'static Chip,
+) -> Result<ContactReport> {
+    vpp_off(handle)?;
+    vcc_off(handle)?;
+
+    let gnd = chip.zif_pin(PinRole::Gnd)?;
+    let vcc = chip.zif_pin(PinRole::Vcc)?;
+
+    let mut presence = PinConfig::new();
+    for pin in 1..=ZIF_PINS {
+        presence.set(pin, PinRole::PullUp)?;
+    }
+    presence.set(gnd, PinRole::Gnd)?;
+    configure_pins(handle, &presence)?;
+    let seated = sense_pins(handle)?;
+    if is_empty(seated, gnd)? {
+        configure_pins(handle, &PinConfig::new())?;
+        return Ok(ContactReport {
+            chip,
+            open: footprint(chip.pin_count(), 0)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|&p| p != gnd)
+                .collect(),
+            stray: vec![],
+            offset: None,
+            reversed: false,
+            empty: true,
+        });
+    }
+
+    let mut orientation = PinConfig::new();
+    orientation
+        .set(vcc, PinRole::Gnd)?
+        .set(gnd, PinRole::PullUp)?;
+    configure_pins(handle, &orientation)?;
+    let reversed = sense_pins(handle)?.is_high(gnd)? && !seated.is_high(vcc)?;
+
+    configure_pins(handle, &PinConfig::new())?;
+
+    analyse(chip, seated, gnd, reversed)
+}
+
+fn is_empty(levels: PinLevels, grounded: u8) -> Result<bool> {
+    for pin in 1..=ZIF_PINS {
+        if pin != grounded && !levels.is_high(pin)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn analyse(
+    chip: &'static Chip,
+    levels: PinLevels,
+    grounded: u8,
+    reversed: bool,
+) -> Result<ContactReport> {
+    let mut contacted = vec![];
+    for pin in 1..=ZIF_PINS {
+        if pin != grounded && !levels.is_high(pin)? {
+            contacted.push(pin);
+        }
+    }
+
+    let expected = footprint(chip.pin_count(), 0).unwrap_or_default();
+    let in_place = |pins: &[u8]| {
+        contacted.iter().filter(|p| pins.contains(p)).count() as i32
+            - contacted.iter().filter(|p| !pins.contains(p)).count() as i32
+    };
+
+    // If the pins we saw fit a shifted footprint better, the chip is offset
+    let max_shift = (ROWS - chip.pin_count() / 2) as i8;
+    let offset = (-max_shift..0)
+        .filter_map(|k| footprint(chip.pin_count(), k).map(|f| (k, f)))
+        .filter(|(_, f)| in_place(f) > in_place(&expected))
+        .max_by_key(|(_, f)| in_place(f))
+        .map(|(k, _)| k);
+
+    Ok(ContactReport {
+        chip,
+        open: expected
+            .iter()
+            .filter(|p| **p != grounded && !contacted.contains(p))
+            .copied()
+            .collect(),
+        stray: contacted
+            .iter()
+            .filter(|p| !expected.contains(p))
+            .copied()
+            .collect(),
+        offset,
+        reversed,
+        empty: false,
+    })
+}
+
+// ZIF pins covered by a DIP shifted `shift` rows up from the bottom
+fn footprint(pin_count: u8, shift: i8) -> Option<Vec<u8>> {
+    let side = i16::from(pin_count / 2);
+    let bottom = i16::from(ROWS) + i16::from(shift);
+    let top = bottom - side + 1;
+    if top < 1 || bottom > i16::from(ROWS) {
+        return None;
+    }
+
+    Some(
+        (top..=bottom)
+            .flat_map(|row| vec![row as u8, ZIF_PINS + 1 - row as u8])
+            .collect(),
+    )
+}
+
+impl fmt::Display for ContactReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.chip.pin_count();
+        let label = |pin: u8| -> String {
+            (1..=count)
+                .find(|&p| zif_pin(p, count).ok() == Some(pin))
+                .map(|p| self.chip.pinout[p as usize - 1].to_string())
+                .unwrap_or_default()
+        };
+        let mark = |pin: u8| {
+            if self.open.contains(&pin) {
+                "XX"
+            } else if self.stray.contains(&pin) {
+                "!!"
+            } else if label(pin).is_empty() {
+                " ."
+            } else {
+                "ok"
+            }
+        };
+
+        writeln!(f, "{:^36}", "lever")?;
+        for row in 1..=ROWS {
+            let right = ZIF_PINS + 1 - row;
+            writeln!(
+                f,
+                "{:>2} [{}] {:<5}        {:>5} [{}] {:<2}",
+                row,
+                mark(row),
+                label(row),
+                label(right),
+                mark(right),
+                right
+            )?;
+        }
+
+        if self.empty {
+            return write!(
+                f,
+                "Nothing in the socket: no pin conducts to ground"
+            );
+        }
+        if self.reversed {
+            writeln!(
+                f,
+                "{} looks reversed, pin 1 should face the lever",
+                self.chip.name
+            )?;
+        }
+        if let Some(offset) = self.offset {
+            writeln!(
+                f,
+                "{} looks {} row(s) too close to the lever, it should sit \
+                 at the bottom of the socket",
+                self.chip.name,
+                offset.abs()
+            )?;
+        }
+        if self.is_ok() {
+            write!(f, "All {} pins make contact", count)
+        } else {
+            write!(
+                f,
+                "{} open, {} stray (XX open, !! unexpected contact)",
+                self.open.len(),
+                self.stray.len()
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip;
+
+    // Pulled up pins read high except where a chip clamps them low
+    fn levels(low: &[u8]) -> PinLevels {
+        let mut levels = PinLevels(0);
+        for pin in 1..=ZIF_PINS {
+            levels.set(pin, !low.contains(&pin)).unwrap();
+        }
+        levels
+    }
+
+    #[test]
+    fn footprints() {
+        let seated = footprint(28, 0).unwrap();
+        assert_eq!(seated.len(), 28);
+        assert!(seated.contains(&7) && seated.contains(&20));
+        assert!(seated.contains(&21) && seated.contains(&34));
+        assert!(!seated.contains(&6) && !seated.contains(&35));
+
+        let shifted = footprint(28, -1).unwrap();
+        assert!(shifted.contains(&6) && shifted.contains(&35));
+        assert!(!shifted.contains(&20));
+
+        assert!(footprint(28, 1).is_none());
+        assert!(footprint(28, -7).is_none());
+        assert_eq!(footprint(40, 0).unwrap().len(), 40);
+    }
+
+    #[test]
+    fn seated_chip_is_ok() {
+        let chip = chip::find("27C64").unwrap();
+        let gnd = chip.zif_pin(PinRole::Gnd).unwrap();
+        let seated = footprint(28, 0).unwrap();
+        let report = analyse(chip, levels(&seated), gnd, false).unwrap();
+        assert!(report.is_ok(), "{}", report);
+    }
+
+    #[test]
+    fn reports_open_pins() {
+        let chip = chip::find("27C64").unwrap();
+        let gnd = chip.zif_pin(PinRole::Gnd).unwrap();
+        let mut seated = footprint(28, 0).unwrap();
+        seated.retain(|&p| p != 7);
+        let report = analyse(chip, levels(&seated), gnd, false).unwrap();
+        assert_eq!(report.open, [7]);
+        assert!(report.stray.is_empty());
+        assert_eq!(report.offset, None);
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn reports_offset() {
+        let chip = chip::find("27C64").unwrap();
+        let gnd = chip.zif_pin(PinRole::Gnd).unwrap();
+        let shifted = footprint(28, -1).unwrap();
+        let report = analyse(chip, levels(&shifted), gnd, false).unwrap();
+        assert_eq!(report.offset, Some(-1));
+        assert_eq!(report.stray, [6, 35]);
+    }
+
+    #[test]
+    fn empty_socket() {
+        assert!(is_empty(levels(&[]), 20).unwrap());
+        assert!(!is_empty(levels(&[3]), 20).unwrap());
+        // The grounded pin itself doesn't count
+        assert!(is_empty(levels(&[20]), 20).unwrap());
+    }
+}
@@ -0,0 +1,504 @@
+use anyhow::{anyhow, bail, Result};
+use rusb::{DeviceHandle, Speed, UsbContext};
+use std::fmt;
+
+// Descriptor types from chapter 9 of the USB 2.0 spec
+const DEVICE: u8 = 0x01;
+const CONFIGURATION: u8 = 0x02;
+const STRING: u8 = 0x03;
+const INTERFACE: u8 = 0x04;
+const ENDPOINT: u8 = 0x05;
+const DEVICE_QUALIFIER: u8 = 0x06;
+
+/// The descriptor table the firmware serves once it's running. The layout
+/// follows the Cypress FX2 frameworks: device, device qualifier, the high
+/// speed configuration, the full speed configuration and then the strings.
+#[derive(Debug)]
+pub struct FirmwareDescriptors {
+    pub device: DeviceDescriptor,
+    pub qualifier: DeviceQualifier,
+    pub high_speed: Configuration,
+    pub full_speed: Configuration,
+    pub strings: Vec<StringDescriptor>,
+}
+
+#[derive(Debug)]
+pub struct DeviceDescriptor {
+    pub usb_version: u16,
+    pub class: u8,
+    pub sub_class: u8,
+    pub protocol: u8,
+    pub max_packet_size: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_version: u16,
+    pub manufacturer_index: u8,
+    pub product_index: u8,
+    pub serial_number_index: u8,
+    pub num_configurations: u8,
+}
+
+#[derive(Debug)]
+pub struct DeviceQualifier {
+    pub usb_version: u16,
+    pub class: u8,
+    pub sub_class: u8,
+    pub protocol: u8,
+    pub max_packet_size: u8,
+    pub num_configurations: u8,
+}
+
+#[derive(Debug)]
+pub struct Configuration {
+    pub value: u8,
+    pub attributes: u8,
+    // In 2mA units, as on the wire
+    pub max_power: u8,
+    pub interfaces: Vec<Interface>,
+}
+
+#[derive(Debug)]
+pub struct Interface {
+    pub number: u8,
+    pub alternate_setting: u8,
+    pub class: u8,
+    pub sub_class: u8,
+    pub protocol: u8,
+    pub endpoints: Vec<Endpoint>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Endpoint {
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+#[derive(Debug)]
+pub enum StringDescriptor {
+    Languages(Vec<u16>),
+    Text(String),
+}
+
+/// Decodes the descriptor segment of the firmware (see
+/// `FIRMWARE_DESCRIPTORS`). Trailing zero padding is ignored.
+pub fn decode(bytes: &[u8]) -> Result<FirmwareDescriptors> {
+    let raw = split(bytes)?;
+    let mut raw = raw.iter().peekable();
+
+    let device = match raw.next() {
+        Some(d) if d[1] == DEVICE => device_descriptor(d)?,
+        _ => bail!("Expected a device descriptor first"),
+    };
+    let qualifier = match raw.next() {
+        Some(d) if d[1] == DEVICE_QUALIFIER => device_qualifier(d)?,
+        _ => bail!("Expected a device qualifier after the device"),
+    };
+
+    let mut configurations = vec![];
+    while let Some(d) = raw.next_if(|d| d[1] == CONFIGURATION) {
+        let mut config = configuration(d)?;
+        while let Some(d) = raw.next_if(|d| d[1] == INTERFACE) {
+            let mut iface = interface(d)?;
+            while let Some(d) = raw.next_if(|d| d[1] == ENDPOINT) {
+                iface.endpoints.push(endpoint(d)?);
+            }
+            config.interfaces.push(iface);
+        }
+        configurations.push(config);
+    }
+
+    let mut strings = vec![];
+    for d in raw {
+        if d[1] != STRING {
+            bail!("Unexpected descriptor type {:#04x} after strings", d[1]);
+        }
+        strings.push(string(d, strings.is_empty())?);
+    }
+
+    if configurations.len() != 2 {
+        bail!(
+            "Expected high and full speed configurations, found {}",
+            configurations.len()
+        );
+    }
+    let full_speed = configurations.pop().unwrap();
+    let high_speed = configurations.pop().unwrap();
+
+    Ok(FirmwareDescriptors {
+        device,
+        qualifier,
+        high_speed,
+        full_speed,
+        strings,
+    })
+}
+
+/// Compares the descriptors in the firmware with what the device reports
+/// after enumeration. Returns a description of every difference found, so
+/// an empty list means the device is running the firmware we uploaded.
+pub fn check<T: UsbContext>(
+    handle: &DeviceHandle<T>,
+    expected: &FirmwareDescriptors,
+) -> Result<Vec<String>> {
+    let device = handle.device();
+    let actual = device.device_descriptor()?;
+    let mut mismatches = vec![];
+
+    let mut compare = |field: &str, expected: u32, actual: u32| {
+        if expected != actual {
+            mismatches.push(format!(
+                "{}: firmware has {:#x}, device reports {:#x}",
+                field, expected, actual
+            ));
+        }
+    };
+
+    let d = &expected.device;
+    compare("idVendor", d.vendor_id.into(), actual.vendor_id().into());
+    compare("idProduct", d.product_id.into(), actual.product_id().into());
+    compare("bDeviceClass", d.class.into(), actual.class_code().into());
+    compare(
+        "bMaxPacketSize0",
+        d.max_packet_size.into(),
+        actual.max_packet_size().into(),
+    );
+    compare(
+        "bcdDevice",
+        d.device_version.into(),
+        bcd(actual.device_version()).into(),
+    );
+    compare(
+        "bNumConfigurations",
+        d.num_configurations.into(),
+        actual.num_configurations().into(),
+    );
+
+    let config = match device.speed() {
+        Speed::High => &expected.high_speed,
+        _ => &expected.full_speed,
+    };
+    let active = device.active_config_descriptor()?;
+    let reported: Vec<Endpoint> = active
+        .interfaces()
+        .flat_map(|i| i.descriptors())
+        .flat_map(|i| {
+            i.endpoint_descriptors()
+                .map(|e| Endpoint {
+                    address: e.address(),
+                    attributes: e.transfer_type() as u8,
+                    max_packet_size: e.max_packet_size(),
+                    interval: e.interval(),
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    for e in config.interfaces.iter().flat_map(|i| &i.endpoints) {
+        match reported.iter().find(|r| r.address == e.address) {
+            None => mismatches.push(format!("{} is missing", e)),
+            // Only the transfer type bits of bmAttributes survive libusb
+            Some(r)
+                if r.attributes != e.attributes & 0x3
+                    || r.max_packet_size != e.max_packet_size =>
+            {
+                mismatches.push(format!("expected {}, found {}", e, r))
+            }
+            Some(_) => {}
+        }
+    }
+    for r in &reported {
+        if !config
+            .interfaces
+            .iter()
+            .flat_map(|i| &i.endpoints)
+            .any(|e| e.address == r.address)
+        {
+            mismatches.push(format!("unexpected {}", r));
+        }
+    }
+
+    Ok(mismatches)
+}
+
+fn bcd(version: rusb::Version) -> u16 {
+    let rusb::Version(major, minor, sub_minor) = version;
+    (u16::from(major / 10) << 12)
+        | (u16::from(major % 10) << 8)
+        | (u16::from(minor) << 4)
+        | u16::from(sub_minor)
+}
+
+// Splits a run of descriptors on their bLength bytes
+fn split(mut bytes: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut descriptors = vec![];
+
+    while let Some(&len) = bytes.first() {
+        if len == 0 {
+            if bytes.iter().any(|&b| b != 0) {
+                bail!("Zero length descriptor before the end of the table");
+            }
+            break;
+        }
+        let len = len as usize;
+        if len < 2 || len > bytes.len() {
+            bail!("Descriptor length {} overruns the table", len);
+        }
+        descriptors.push(&bytes[..len]);
+        bytes = &bytes[len..];
+    }
+
+    Ok(descriptors)
+}
+
+fn expect_len(d: &[u8], len: usize) -> Result<()> {
+    if d.len() != len {
+        Err(anyhow!(
+            "Descriptor type {:#04x} should be {} bytes, got {}",
+            d[1],
+            len,
+            d.len()
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn word(d: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([d[offset], d[offset + 1]])
+}
+
+fn device_descriptor(d: &[u8]) -> Result<DeviceDescriptor> {
+    expect_len(d, 18)?;
+    Ok(DeviceDescriptor {
+        usb_version: word(d, 2),
+        class: d[4],
+        sub_class: d[5],
+        protocol: d[6],
+        max_packet_size: d[7],
+        vendor_id: word(d, 8),
+        product_id: word(d, 10),
+        device_version: word(d, 12),
+        manufacturer_index: d[14],
+        product_index: d[15],
+        serial_number_index: d[16],
+        num_configurations: d[17],
+    })
+}
+
+fn device_qualifier(d: &[u8]) -> Result<DeviceQualifier> {
+    expect_len(d, 10)?;
+    Ok(DeviceQualifier {
+        usb_version: word(d, 2),
+        class: d[4],
+        sub_class: d[5],
+        protocol: d[6],
+        max_packet_size: d[7],
+        num_configurations: d[8],
+    })
+}
+
+fn configuration(d: &[u8]) -> Result<Configuration> {
+    expect_len(d, 9)?;
+    Ok(Configuration {
+        value: d[5],
+        attributes: d[7],
+        max_power: d[8],
+        interfaces: vec![],
+    })
+}
+
+fn interface(d: &[u8]) -> Result<Interface> {
+    expect_len(d, 9)?;
+    Ok(Interface {
+        number: d[2],
+        alternate_setting: d[3],
+        class: d[5],
+        sub_class: d[6],
+        protocol: d[7],
+        endpoints: vec![],
+    })
+}
+
+fn endpoint(d: &[u8]) -> Result<Endpoint> {
+    expect_len(d, 7)?;
+    Ok(Endpoint {
+        address: d[2],
+        attributes: d[3],
+        max_packet_size: word(d, 4),
+        interval: d[6],
+    })
+}
+
+// String descriptor zero is the list of supported languages
+fn string(d: &[u8], first: bool) -> Result<StringDescriptor> {
+    if d.len() % 2 == 1 {
+        bail!("String descriptor has an odd length: {}", d.len());
+    }
+    let units: Vec<u16> = d[2..].chunks(2).map(|c| word(c, 0)).collect();
+
+    if first {
+        Ok(StringDescriptor::Languages(units))
+    } else {
+        Ok(StringDescriptor::Text(String::from_utf16(&units)?))
+    }
+}
+
+impl fmt::Display for FirmwareDescriptors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let d = &self.device;
+        writeln!(
+            f,
+            "Device {:04x}:{:04x} USB {:x}.{:02x} class {:#04x} \
+             ep0 {} bytes, {} configuration(s)",
+            d.vendor_id,
+            d.product_id,
+            d.usb_version >> 8,
+            d.usb_version & 0xff,
+            d.class,
+            d.max_packet_size,
+            d.num_configurations,
+        )?;
+        writeln!(
+            f,
+            "Qualifier USB {:x}.{:02x} ep0 {} bytes",
+            self.qualifier.usb_version >> 8,
+            self.qualifier.usb_version & 0xff,
+            self.qualifier.max_packet_size,
+        )?;
+        for (speed, config) in
+            &[("High", &self.high_speed), ("Full", &self.full_speed)]
+        {
+            writeln!(
+                f,
+                "{} speed configuration {} attributes {:#04x} {}mA",
+                speed,
+                config.value,
+                config.attributes,
+                u16::from(config.max_power) * 2,
+            )?;
+            for iface in &config.interfaces {
+                writeln!(
+                    f,
+                    "  Interface {} alt {} class {:#04x}",
+                    iface.number, iface.alternate_setting, iface.class
+                )?;
+                for e in &iface.endpoints {
+                    writeln!(f, "    {}", e)?;
+                }
+            }
+        }
+        for s in &self.strings {
+            match s {
+                StringDescriptor::Languages(l) => {
+                    writeln!(f, "Languages {:04x?}", l)?
+                }
+                StringDescriptor::Text(t) => writeln!(f, "String {:?}", t)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.attributes & 0x3 {
+            0 => "control",
+            1 => "isochronous",
+            2 => "bulk",
+            _ => "interrupt",
+        };
+        let direction = if self.address & 0x80 > 0 { "IN" } else { "OUT" };
+        write!(
+            f,
+            "endpoint {:#04x} {} {} {} bytes",
+            self.address, direction, kind, self.max_packet_size
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FIRMWARE_DESCRIPTORS;
+
+    fn endpoints(config: &Configuration) -> Vec<(u8, u16)> {
+        config
+            .interfaces
+            .iter()
+            .flat_map(|i| &i.endpoints)
+            .map(|e| (e.address, e.max_packet_size))
+            .collect()
+    }
+
+    #[test]
+    fn decodes_firmware_descriptors() {
+        let d = decode(FIRMWARE_DESCRIPTORS).unwrap();
+        assert_eq!(d.device.vendor_id, 0x04b4);
+        assert_eq!(d.device.product_id, 0x8613);
+        assert_eq!(d.device.num_configurations, 1);
+        assert_eq!(d.qualifier.max_packet_size, 64);
+
+        assert_eq!(
+            endpoints(&d.high_speed),
+            [
+                (0x01, 64),
+                (0x02, 512),
+                (0x04, 512),
+                (0x81, 64),
+                (0x86, 512),
+                (0x88, 512)
+            ]
+        );
+        assert_eq!(
+            endpoints(&d.full_speed),
+            [
+                (0x01, 64),
+                (0x02, 64),
+                (0x04, 64),
+                (0x81, 64),
+                (0x86, 64),
+                (0x88, 64)
+            ]
+        );
+        assert!(d
+            .high_speed
+            .interfaces
+            .iter()
+            .flat_map(|i| &i.endpoints)
+            .all(|e| e.attributes == 0x02));
+    }
+
+    #[test]
+    fn decodes_strings() {
+        let d = decode(FIRMWARE_DESCRIPTORS).unwrap();
+        assert!(matches!(
+            &d.strings[0],
+            StringDescriptor::Languages(l) if l == &[0x0409]
+        ));
+        let text: Vec<&str> = d
+            .strings
+            .iter()
+            .filter_map(|s| match s {
+                StringDescriptor::Text(t) => Some(t.trim_end()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(text, ["GQ USB", "EPROM Programmer"]);
+    }
+
+    #[test]
+    fn rejects_overrunning_length() {
+        let mut bytes = FIRMWARE_DESCRIPTORS[..18].to_vec();
+        bytes[0] = 19;
+        assert!(decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn rejects_data_after_padding() {
+        let mut bytes = FIRMWARE_DESCRIPTORS.to_vec();
+        bytes.extend_from_slice(&[0, 0, 1]);
+        assert!(decode(&bytes).is_err());
+    }
+}
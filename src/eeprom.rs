@@ -0,0 +1,185 @@
+use crate::bus::{poll_ready, powered, ParallelBus};
+use crate::chip::{Chip, Eeprom, ReadyCheck};
+use crate::progress::Tracker;
+use anyhow::{bail, Result};
+use rusb::{DeviceHandle, UsbContext};
+use std::time::Duration;
+
+// Software data protection, in 28C256 addresses. Smaller parts ignore the
+// high address bits, so they're masked to the chip's size.
+const UNLOCK: [(u32, u8); 2] = [(0x5555, 0xaa), (0x2aaa, 0x55)];
+const COMMAND_ADDR: u32 = 0x5555;
+// Enables protection, and prefixes every write while it's enabled
+const SDP_WRITE: u8 = 0xa0;
+const SDP_DISABLE: [u8; 2] = [0x80, 0x20];
+
+// tWC is 10 ms at worst, this allows for polling over USB
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How writes are sent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteMode {
+    /// Plain writes; the chip ignores them while data protection is on
+    Unprotected,
+    /// Every write cycle is prefixed with the AA/55/A0 sequence, which
+    /// works either way and leaves protection enabled
+    Protected,
+}
+
+/// Writes the bytes of `image` that differ from `current`, a page at a
+/// time where the chip has pages. VCC is off when this returns.
+///
+/// A page load only holds together if each byte arrives within the chip's
+/// byte load window (about 150 us), which bus cycles over USB often miss.
+/// Bytes of a page that don't read back are written again one at a time.
+/// A byte that still doesn't settle in plain mode means the chip ignored
+/// it, which is what data protection does.
+pub fn program<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    eeprom: &Eeprom,
+    image: &[u8],
+    current: &[u8],
+    mode: WriteMode,
+    tracker: &mut Tracker,
+) -> Result<()> {
+    if mode == WriteMode::Protected && !eeprom.sdp {
+        bail!("{} has no software data protection", chip.name);
+    }
+    let mask = chip.size - 1;
+    let page_size = eeprom.page_size.max(1) as usize;
+
+    powered(handle, chip, |bus| {
+        for (page, (wanted, had)) in image
+            .chunks(page_size)
+            .zip(current.chunks(page_size))
+            .enumerate()
+        {
+            let base = (page * page_size) as u32;
+            let changed: Vec<(u32, u8)> = wanted
+                .iter()
+                .zip(had)
+                .enumerate()
+                .filter(|(_, (w, h))| w != h)
+                .map(|(i, (&w, _))| (base + i as u32, w))
+                .collect();
+
+            if let Some(&(last, value)) = changed.last() {
+                write_cycle(bus, mask, mode, &changed)?;
+                // A page that broke up, or was ignored, never settles.
+                // Either way the bytes are checked one at a time below.
+                poll_ready(
+                    bus,
+                    ReadyCheck::DataPolling,
+                    last,
+                    value,
+                    WRITE_TIMEOUT,
+                )?;
+
+                for &(addr, value) in &changed {
+                    if bus.read(addr)? != value {
+                        write_cycle(bus, mask, mode, &[(addr, value)])?;
+                        wait(bus, mode, addr, value)?;
+                    }
+                }
+            }
+
+            let end = base + wanted.len() as u32;
+            tracker.update(u64::from(end), end - 1)?;
+        }
+        Ok(())
+    })
+}
+
+/// Turns software data protection on. Plain writes are ignored afterwards.
+pub fn enable_sdp<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    eeprom: &Eeprom,
+) -> Result<()> {
+    check_sdp(chip, eeprom)?;
+    let mask = chip.size - 1;
+    powered(handle, chip, |bus| {
+        command(bus, mask, SDP_WRITE)?;
+        // Protection takes hold after a write cycle's worth of time
+        std::thread::sleep(Duration::from_millis(10));
+        Ok(())
+    })
+}
+
+/// Turns software data protection off so plain writes are accepted.
+pub fn disable_sdp<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    eeprom: &Eeprom,
+) -> Result<()> {
+    check_sdp(chip, eeprom)?;
+    let mask = chip.size - 1;
+    powered(handle, chip, |bus| {
+        for &value in &SDP_DISABLE {
+            command(bus, mask, value)?;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+        Ok(())
+    })
+}
+
+fn check_sdp(chip: &Chip, eeprom: &Eeprom) -> Result<()> {
+    if !eeprom.sdp {
+        bail!("{} has no software data protection", chip.name);
+    }
+    Ok(())
+}
+
+// One write cycle: the unlock prefix if protected, then the bytes
+fn write_cycle<T: UsbContext>(
+    bus: &mut ParallelBus<T>,
+    mask: u32,
+    mode: WriteMode,
+    bytes: &[(u32, u8)],
+) -> Result<()> {
+    if mode == WriteMode::Protected {
+        command(bus, mask, SDP_WRITE)?;
+    }
+    for &(addr, value) in bytes {
+        bus.write(addr, value)?;
+    }
+    Ok(())
+}
+
+fn command<T: UsbContext>(
+    bus: &mut ParallelBus<T>,
+    mask: u32,
+    value: u8,
+) -> Result<()> {
+    for &(addr, value) in &UNLOCK {
+        bus.write(addr & mask, value)?;
+    }
+    bus.write(COMMAND_ADDR & mask, value)
+}
+
+fn wait<T: UsbContext>(
+    bus: &mut ParallelBus<T>,
+    mode: WriteMode,
+    addr: u32,
+    value: u8,
+) -> Result<()> {
+    // An ignored write can pass data polling if DQ7 already matched
+    let ready =
+        poll_ready(bus, ReadyCheck::DataPolling, addr, value, WRITE_TIMEOUT)?
+            && bus.read(addr)? == value;
+    match (ready, mode) {
+        (true, _) => Ok(()),
+        (false, WriteMode::Unprotected) => bail!(
+            "Write to {:#06x} ignored, is SDP enabled? use --disable-sdp",
+            addr
+        ),
+        (false, WriteMode::Protected) => {
+            bail!(
+                "Byte at {:#06x} didn't take within {:?}",
+                addr,
+                WRITE_TIMEOUT
+            )
+        }
+    }
+}
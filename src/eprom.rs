@@ -0,0 +1,160 @@
+use crate::bus::ParallelBus;
+use crate::chip::{Chip, Signature};
+use crate::pins::{PinConfig, PinRole};
+use crate::progress::Tracker;
+use crate::voltage::{self, Vcc, Vpp};
+use anyhow::{anyhow, bail, Result};
+use rusb::{DeviceHandle, UsbContext};
+use std::time::Duration;
+
+// Intel's interactive algorithm for CMOS parts: 1 ms pulses until the byte
+// reads back, then one overprogram pulse of three times the total
+const PULSE: Duration = Duration::from_millis(1);
+const MAX_PULSES: u32 = 25;
+const OVERPROGRAM: u32 = 3;
+// NMOS parts get the original single 50 ms pulse
+const NMOS_PULSE: Duration = Duration::from_millis(50);
+
+/// Programs every byte of `image` that isn't `ff`, starting at address 0.
+/// The chip must already be blank wherever `image` has a 1 bit. Both
+/// supplies are off when this returns, including when it's cancelled.
+pub fn program<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    image: &[u8],
+    tracker: &mut Tracker,
+) -> Result<()> {
+    let vpp = chip
+        .vpp
+        .ok_or_else(|| anyhow!("{} has no programming voltage", chip.name))?;
+    let cmos = chip.vcc.contains(&Vcc::V6_25);
+    let vcc = if cmos { Vcc::V6_25 } else { Vcc::V5_0 };
+
+    let mut bus = ParallelBus::new(handle, program_config(chip)?)?;
+    voltage::set_vcc(bus.handle(), chip, vcc)?;
+    voltage::set_vpp(bus.handle(), chip, vpp)?;
+    voltage::vcc_on(bus.handle())?;
+    voltage::vpp_on(bus.handle())?;
+
+    let result = (|| {
+        for (addr, &value) in image.iter().enumerate() {
+            if value != 0xff {
+                program_byte(&mut bus, chip, addr as u32, value, cmos)?;
+            }
+            tracker.update(addr as u64 + 1, addr as u32)?;
+        }
+        Ok(())
+    })();
+
+    voltage::vpp_off(bus.handle())?;
+    voltage::vcc_off(bus.handle())?;
+    result
+}
+
+fn program_byte<T: UsbContext>(
+    bus: &mut ParallelBus<T>,
+    chip: &Chip,
+    addr: u32,
+    value: u8,
+    cmos: bool,
+) -> Result<()> {
+    if !cmos {
+        bus.program_pulse(addr, value, NMOS_PULSE)?;
+        return check(bus, chip, addr, value);
+    }
+
+    for pulses in 1..=MAX_PULSES {
+        bus.program_pulse(addr, value, PULSE)?;
+        if read_back(bus, chip, addr)? == value {
+            return bus.program_pulse(
+                addr,
+                value,
+                PULSE * OVERPROGRAM * pulses,
+            );
+        }
+    }
+    check(bus, chip, addr, value)
+}
+
+fn check<T: UsbContext>(
+    bus: &mut ParallelBus<T>,
+    chip: &Chip,
+    addr: u32,
+    value: u8,
+) -> Result<()> {
+    let read = read_back(bus, chip, addr)?;
+    if read != value {
+        bail!(
+            "Byte at {:#06x} won't program: wrote {:02x}, reads {:02x}",
+            addr,
+            value,
+            read
+        );
+    }
+    Ok(())
+}
+
+// Reads a byte back between program pulses. Where VPP is on /OE, reading
+// with it still there is program mode with the data pins floating, so VPP
+// comes off and /OE goes back to being a logic pin for the read.
+fn read_back<T: UsbContext>(
+    bus: &mut ParallelBus<T>,
+    chip: &Chip,
+    addr: u32,
+) -> Result<u8> {
+    if !vpp_on_oe(chip) {
+        return bus.read(addr);
+    }
+    voltage::vpp_off(bus.handle())?;
+    bus.reconfigure(chip.pin_config()?)?;
+    let value = bus.read(addr)?;
+    bus.reconfigure(program_config(chip)?)?;
+    voltage::vpp_on(bus.handle())?;
+    Ok(value)
+}
+
+// Chips without a VPP pin take it on /OE while programming
+fn vpp_on_oe(chip: &Chip) -> bool {
+    !chip.pinout.contains(&PinRole::Vpp)
+}
+
+fn program_config(chip: &Chip) -> Result<PinConfig> {
+    let mut config = chip.pin_config()?;
+    if !vpp_on_oe(chip) {
+        return Ok(config);
+    }
+    let oe = chip.zif_pin(PinRole::OutputEnable)?;
+    config.set(oe, PinRole::Vpp)?;
+    Ok(config)
+}
+
+/// Reads the manufacturer and device codes, addresses 0 and 1 with A9 held
+/// at 12 V. The chip's own VPP pin, if it has one, is tied to VCC.
+/// Both supplies are off when this returns.
+pub fn signature<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+) -> Result<Signature> {
+    let mut config = chip.pin_config()?;
+    for pin in config.pins_with(PinRole::Vpp) {
+        config.set(pin, PinRole::Vcc)?;
+    }
+    config.set(chip.zif_pin(PinRole::Address(9))?, PinRole::Vpp)?;
+
+    let mut bus = ParallelBus::new(handle, config)?;
+    voltage::set_vcc(bus.handle(), chip, Vcc::V5_0)?;
+    voltage::set_vpp(bus.handle(), chip, Vpp::V12_5)?;
+    voltage::vcc_on(bus.handle())?;
+    voltage::vpp_on(bus.handle())?;
+
+    let result = (|| {
+        Ok(Signature {
+            manufacturer: bus.read(0)?,
+            device: u16::from(bus.read(1)?),
+        })
+    })();
+
+    voltage::vpp_off(bus.handle())?;
+    voltage::vcc_off(bus.handle())?;
+    result
+}
@@ -0,0 +1,110 @@
+use crate::bus::{powered, wait_ready, ParallelBus};
+use crate::chip::{Chip, Flash, Signature};
+use crate::progress::Tracker;
+use anyhow::{anyhow, Result};
+use rusb::{DeviceHandle, UsbContext};
+use std::time::Duration;
+
+// Every command is preceded by the AA/55 unlock cycles, then written to
+// 5555. Erases take a second unlock after the 80 setup command.
+const UNLOCK: [(u32, u8); 2] = [(0x5555, 0xaa), (0x2aaa, 0x55)];
+const COMMAND_ADDR: u32 = 0x5555;
+const PROGRAM: u8 = 0xa0;
+const ERASE_SETUP: u8 = 0x80;
+const CHIP_ERASE: u8 = 0x10;
+const SECTOR_ERASE: u8 = 0x30;
+const PRODUCT_ID: u8 = 0x90;
+const RESET: u8 = 0xf0;
+
+// Datasheet worst cases are far shorter, these allow for polling over USB
+const PROGRAM_TIMEOUT: Duration = Duration::from_millis(100);
+const ERASE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Programs every byte of `image` that isn't `ff`, starting at address 0.
+/// The chip must already be erased wherever `image` has a 1 bit. VCC is off
+/// when this returns, including when it's cancelled.
+pub fn program<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    flash: &Flash,
+    image: &[u8],
+    tracker: &mut Tracker,
+) -> Result<()> {
+    powered(handle, chip, |bus| {
+        for (addr, &value) in image.iter().enumerate() {
+            let addr = addr as u32;
+            if value != 0xff {
+                command(bus, PROGRAM)?;
+                bus.write(addr, value)?;
+                wait_ready(bus, flash.ready, addr, value, PROGRAM_TIMEOUT)?;
+            }
+            tracker.update(u64::from(addr) + 1, addr)?;
+        }
+        Ok(())
+    })
+}
+
+pub fn erase_chip<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    flash: &Flash,
+) -> Result<()> {
+    powered(handle, chip, |bus| {
+        command(bus, ERASE_SETUP)?;
+        command(bus, CHIP_ERASE)?;
+        wait_ready(bus, flash.ready, 0, 0xff, ERASE_TIMEOUT)
+    })
+}
+
+/// Erases the sector containing `address`. Returns the sector's start
+/// address and size.
+pub fn erase_sector<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    flash: &Flash,
+    address: u32,
+) -> Result<(u32, u32)> {
+    let sectors = chip
+        .sectors()
+        .ok_or_else(|| anyhow!("{} only has chip erase", chip.name))?;
+    let (start, size) = sectors
+        .into_iter()
+        .find(|&(start, size)| address >= start && address - start < size)
+        .ok_or_else(|| {
+            anyhow!("{:#x} is past the end of {}", address, chip.name)
+        })?;
+
+    powered(handle, chip, |bus| {
+        command(bus, ERASE_SETUP)?;
+        for &(addr, value) in &UNLOCK {
+            bus.write(addr, value)?;
+        }
+        bus.write(start, SECTOR_ERASE)?;
+        wait_ready(bus, flash.ready, start, 0xff, ERASE_TIMEOUT)
+    })?;
+    Ok((start, size))
+}
+
+/// Reads the manufacturer and device codes in product ID mode, then
+/// returns the chip to reading the array.
+pub fn signature<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+) -> Result<Signature> {
+    powered(handle, chip, |bus| {
+        command(bus, PRODUCT_ID)?;
+        let signature = Signature {
+            manufacturer: bus.read(0)?,
+            device: u16::from(bus.read(1)?),
+        };
+        bus.write(0, RESET)?;
+        Ok(signature)
+    })
+}
+
+fn command<T: UsbContext>(bus: &mut ParallelBus<T>, value: u8) -> Result<()> {
+    for &(addr, value) in &UNLOCK {
+        bus.write(addr, value)?;
+    }
+    bus.write(COMMAND_ADDR, value)
+}
@@ -0,0 +1,373 @@
+use crate::bitbang::BitBang;
+use crate::chip::{Chip, Gal};
+use crate::jedec::Jedec;
+use crate::pins::PinRole;
+use crate::progress::Tracker;
+use crate::voltage::{self, Vpp};
+use anyhow::{anyhow, bail, Result};
+use rusb::{DeviceHandle, UsbContext};
+use std::thread::sleep;
+use std::time::Duration;
+
+const UES_BITS: u32 = 64;
+const PES_BITS: usize = 64;
+
+// Conservative against the widths in the parts' PES bytes
+const PROGRAM_PULSE: Duration = Duration::from_millis(40);
+const ERASE_PULSE: Duration = Duration::from_millis(100);
+// Settling after EDIT goes to VPP
+const EDIT_DELAY: Duration = Duration::from_millis(10);
+// Edit mode for reading the PES and fuses. The PES only says what the part
+// programs at, and has to be read first, so reads use the lowest level.
+const READ_VPP: Vpp = Vpp::V12_5;
+// PES manufacturer byte of National parts, which program a little higher
+const NATIONAL: u8 = 0x8f;
+
+/// The pins of a GAL in edit mode. A row is selected on RA0-RA5 with P/V
+/// low to read or high to program, and STB low latches it: for a read,
+/// the row is then clocked out of SDOUT; for a write, the bits clocked into
+/// SDIN beforehand are programmed for as long as STB stays low.
+struct Edit<'a, T: UsbContext> {
+    bus: BitBang<'a, T>,
+    address: Vec<u8>,
+    sdin: u8,
+    sclk: u8,
+    sdout: u8,
+    strobe: u8,
+    program: u8,
+}
+
+impl<'a, T: UsbContext> Edit<'a, T> {
+    fn select_row(&mut self, row: u8, program: bool) -> Result<()> {
+        let mut levels: Vec<(u8, bool)> = self
+            .address
+            .iter()
+            .enumerate()
+            .map(|(bit, &pin)| (pin, row & (1 << bit) > 0))
+            .collect();
+        levels.push((self.program, program));
+        levels.push((self.sclk, false));
+        self.bus.set_all(&levels)
+    }
+
+    fn strobe(&mut self, width: Duration) -> Result<()> {
+        self.bus.set(self.strobe, false)?;
+        sleep(width);
+        self.bus.set(self.strobe, true)
+    }
+
+    fn shift_in(&mut self, bits: &[bool]) -> Result<()> {
+        for &bit in bits {
+            self.bus.set_all(&[(self.sdin, bit), (self.sclk, false)])?;
+            self.bus.set(self.sclk, true)?;
+        }
+        self.bus.set(self.sclk, false)
+    }
+
+    fn shift_out(&mut self, count: usize) -> Result<Vec<bool>> {
+        let mut bits = Vec::with_capacity(count);
+        for _ in 0..count {
+            bits.push(self.bus.get(self.sdout)?);
+            self.bus.set(self.sclk, true)?;
+            self.bus.set(self.sclk, false)?;
+        }
+        Ok(bits)
+    }
+
+    fn read_row(&mut self, row: u8, count: usize) -> Result<Vec<bool>> {
+        self.select_row(row, false)?;
+        self.strobe(Duration::from_micros(0))?;
+        self.shift_out(count)
+    }
+
+    fn write_row(&mut self, row: u8, bits: &[bool]) -> Result<()> {
+        self.select_row(row, true)?;
+        self.shift_in(bits)?;
+        self.strobe(PROGRAM_PULSE)?;
+        self.select_row(row, false)
+    }
+}
+
+/// Reads the array, UES and architecture fuses into a fuse map. A GAL with
+/// its security fuse set reads back as a uniform array.
+pub fn read<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    gal: &Gal,
+    tracker: &mut Tracker,
+) -> Result<Jedec> {
+    let mut jedec = Jedec::new(gal.fuses as usize);
+    jedec.pins = Some(u32::from(chip.pin_count()));
+    edit_mode(handle, chip, READ_VPP, |edit| {
+        for row in 0..gal.rows {
+            let fuses = array_fuses(gal, row);
+            let bits = edit.read_row(row, fuses.len())?;
+            for (fuse, bit) in fuses.into_iter().zip(bits) {
+                jedec.fuses[fuse as usize] = bit;
+            }
+            tracker.update(u64::from(row) + 1, u32::from(row))?;
+        }
+        for (row, fuses) in other_rows(gal) {
+            let bits = edit.read_row(row, fuses.len())?;
+            for (fuse, bit) in fuses.into_iter().zip(bits) {
+                jedec.fuses[fuse as usize] = bit;
+            }
+        }
+        Ok(())
+    })?;
+    Ok(jedec)
+}
+
+/// Erases the chip and programs the array, UES and architecture rows of
+/// `jedec`. The security fuse is left alone, so the result can still be
+/// verified; see `secure`.
+pub fn program<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    gal: &Gal,
+    jedec: &Jedec,
+    tracker: &mut Tracker,
+) -> Result<()> {
+    if jedec.fuses.len() != gal.fuses as usize {
+        bail!(
+            "Fuse map has {} fuses, {} has {}",
+            jedec.fuses.len(),
+            chip.name,
+            gal.fuses
+        );
+    }
+    let vpp = read_programming_vpp(handle, chip, gal)?;
+    erase_at(handle, chip, gal, vpp)?;
+    edit_mode(handle, chip, vpp, |edit| {
+        let bits = |fuses: Vec<u32>| -> Vec<bool> {
+            fuses.iter().map(|&f| jedec.fuses[f as usize]).collect()
+        };
+        for row in 0..gal.rows {
+            edit.write_row(row, &bits(array_fuses(gal, row)))?;
+            tracker.update(u64::from(row) + 1, u32::from(row))?;
+        }
+        for (row, fuses) in other_rows(gal) {
+            edit.write_row(row, &bits(fuses))?;
+        }
+        Ok(())
+    })
+}
+
+/// Clears every fuse, the security fuse included.
+pub fn erase<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    gal: &Gal,
+) -> Result<()> {
+    let vpp = read_programming_vpp(handle, chip, gal)?;
+    erase_at(handle, chip, gal, vpp)
+}
+
+fn erase_at<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    gal: &Gal,
+    vpp: Vpp,
+) -> Result<()> {
+    edit_mode(handle, chip, vpp, |edit| {
+        edit.select_row(gal.erase_row, true)?;
+        edit.bus.set(edit.sdin, true)?;
+        edit.strobe(ERASE_PULSE)?;
+        edit.select_row(gal.erase_row, false)
+    })
+}
+
+/// Sets the security fuse. Reads return a uniform array afterwards, until
+/// the next erase.
+pub fn secure<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    gal: &Gal,
+) -> Result<()> {
+    let vpp = read_programming_vpp(handle, chip, gal)?;
+    edit_mode(handle, chip, vpp, |edit| {
+        edit.select_row(gal.security_row, true)?;
+        edit.strobe(PROGRAM_PULSE)?;
+        edit.select_row(gal.security_row, false)
+    })
+}
+
+/// The eight programmer electronic signature bytes, LSB first in each.
+pub fn pes<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    gal: &Gal,
+) -> Result<[u8; 8]> {
+    let bits = edit_mode(handle, chip, READ_VPP, |edit| {
+        edit.read_row(gal.pes_row, PES_BITS)
+    })?;
+    let mut pes = [0; 8];
+    for (i, &bit) in bits.iter().enumerate() {
+        if bit {
+            pes[i / 8] |= 1 << (i % 8);
+        }
+    }
+    Ok(pes)
+}
+
+/// The programming voltage `pes` asks for, from afterburner's tables: the
+/// algorithm in the low nibble of byte 1 picks one of `gal.vpp`, except
+/// algorithm 5, whose parts give their level in byte 5. Fails for unknown
+/// algorithms and for levels the VPP regulator doesn't have.
+pub fn programming_vpp(gal: &Gal, pes: &[u8; 8]) -> Result<Vpp> {
+    let algorithm = pes[1] & 0x0f;
+    let millivolts = match algorithm {
+        5 => (2 * u32::from((pes[5] >> 2) & 0x1f) + 20) * 250,
+        _ => match gal.vpp.get(usize::from(algorithm)) {
+            Some(&14500) if pes[3] == NATIONAL => 15000,
+            Some(&millivolts) => millivolts,
+            None => bail!(
+                "PES {} has unknown programming algorithm {}",
+                hex::encode(pes),
+                algorithm
+            ),
+        },
+    };
+    Vpp::ALL
+        .iter()
+        .copied()
+        .find(|l| l.millivolts() == millivolts)
+        .ok_or_else(|| {
+            anyhow!(
+                "PES {} asks for {} mV VPP, which the regulator has no \
+                 level for",
+                hex::encode(pes),
+                millivolts
+            )
+        })
+}
+
+fn read_programming_vpp<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    gal: &Gal,
+) -> Result<Vpp> {
+    programming_vpp(gal, &pes(handle, chip, gal)?)
+}
+
+/// JEDEC fuse numbers that differ between two maps.
+pub fn differences(expected: &Jedec, actual: &Jedec) -> Vec<u32> {
+    expected
+        .fuses
+        .iter()
+        .zip(&actual.fuses)
+        .enumerate()
+        .filter(|(_, (e, a))| e != a)
+        .map(|(i, _)| i as u32)
+        .collect()
+}
+
+// JEDEC fuse numbers of an array row, in shift order
+fn array_fuses(gal: &Gal, row: u8) -> Vec<u32> {
+    let rows = u32::from(gal.rows);
+    (0..u32::from(gal.row_bits))
+        .map(|bit| bit * rows + u32::from(row))
+        .collect()
+}
+
+// The UES and architecture rows with their fuse numbers in shift order
+fn other_rows(gal: &Gal) -> Vec<(u8, Vec<u32>)> {
+    let ues = (gal.ues_fuse..gal.ues_fuse + UES_BITS).collect();
+    let config = gal
+        .config_fuses
+        .iter()
+        .flat_map(|&(first, count)| first..first + count)
+        .collect();
+    vec![(gal.ues_row, ues), (gal.config_row, config)]
+}
+
+// Loads the edit mode pins, powers the chip, raises EDIT to `vpp` and runs
+// `f`. Both supplies are off afterwards, whatever happens.
+fn edit_mode<T: UsbContext, R>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    vpp: Vpp,
+    f: impl FnOnce(&mut Edit<T>) -> Result<R>,
+) -> Result<R> {
+    let vcc = *chip
+        .vcc
+        .first()
+        .ok_or_else(|| anyhow!("{} has no supply voltage", chip.name))?;
+    let config = chip.pin_config()?;
+    let pin = |role| {
+        config
+            .pins_with(role)
+            .first()
+            .copied()
+            .ok_or_else(|| anyhow!("No {} pin in the config", role))
+    };
+    let address = (0..6)
+        .map(|bit| pin(PinRole::Address(bit)))
+        .collect::<Result<Vec<_>>>()?;
+    let (sdin, sclk) = (pin(PinRole::SerialIn)?, pin(PinRole::Clock)?);
+    let (sdout, strobe) = (pin(PinRole::SerialOut)?, pin(PinRole::ChipEnable)?);
+    let program = pin(PinRole::WriteEnable)?;
+
+    let mut edit = Edit {
+        bus: BitBang::new(handle, &config)?,
+        address,
+        sdin,
+        sclk,
+        sdout,
+        strobe,
+        program,
+    };
+    edit.bus.set_all(&[(sclk, false), (program, false)])?;
+    voltage::set_vcc(edit.bus.handle(), chip, vcc)?;
+    // Not checked against `chip.vpp`, GALs take theirs from the PES
+    voltage::write_vpp_level(edit.bus.handle(), vpp)?;
+    voltage::vcc_on(edit.bus.handle())?;
+    voltage::vpp_on(edit.bus.handle())?;
+    sleep(EDIT_DELAY);
+
+    let result = f(&mut edit);
+    voltage::vpp_off(edit.bus.handle())?;
+    voltage::vcc_off(edit.bus.handle())?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip::{self, ChipKind};
+
+    fn gal(name: &str) -> &'static Gal {
+        match chip::find(name).unwrap().kind {
+            ChipKind::Gal(g) => g,
+            _ => panic!("{} isn't a GAL", name),
+        }
+    }
+
+    #[test]
+    fn algorithm_5_gives_its_own_level() {
+        // 2 * 15 + 20 quarter volts
+        let pes = [0, 0x05, 0, 0xa1, 0, 15 << 2, 0, 0];
+        assert_eq!(programming_vpp(gal("GAL16V8"), &pes).unwrap(), Vpp::V12_5);
+    }
+
+    #[test]
+    fn table_levels_the_regulator_lacks_are_refused() {
+        let pes = [0, 0x04, 0, 0xa1, 0, 0, 0, 0];
+        let e = programming_vpp(gal("GAL16V8"), &pes).unwrap_err();
+        assert!(e.to_string().contains("14000 mV"), "{}", e);
+
+        // National parts take 15 V where the others take 14.5 V
+        let pes = [0, 0x02, 0, NATIONAL, 0, 0, 0, 0];
+        let e = programming_vpp(gal("GAL22V10"), &pes).unwrap_err();
+        assert!(e.to_string().contains("15000 mV"), "{}", e);
+    }
+
+    #[test]
+    fn unknown_algorithm_is_refused() {
+        let pes = [0, 0x04, 0, 0xa1, 0, 0, 0, 0];
+        assert!(programming_vpp(gal("GAL22V10"), &pes).is_err());
+        let pes = [0, 0x07, 0, 0xa1, 0, 0, 0, 0];
+        let e = programming_vpp(gal("GAL16V8"), &pes).unwrap_err();
+        assert!(e.to_string().contains("algorithm 7"), "{}", e);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::{peek, poke};
+use anyhow::{bail, Result};
+use hex_literal::hex;
+use rusb::{DeviceHandle, UsbContext};
+
+// Sent once before the exchanges. Both the first stage firmware's dispatch
+// table and initialization.pcapng (frame 394) have it ahead of the 0x7a
+// commands.
+const OPEN: [u8; 5] = hex!("7b0000c802");
+const EXCHANGE: u8 = 0x7a;
+const LEN: usize = 26;
+
+/// One 0x7a round trip as recorded from the vendor software.
+pub struct Exchange {
+    pub challenge: [u8; LEN],
+    pub response: [u8; LEN],
+}
+
+/// Every exchange we have a recording of, in the order the vendor software
+/// sent them.
+///
+/// Comparing the two: the challenges differ only in bytes 4 and 25, and 18
+/// of the 26 response bytes are identical (see `FIXED_RESPONSE`). The other
+/// eight (2, 5, 6, 9, 11, 13, 15 and 25) change with the challenge, and not
+/// as an XOR or offset of the changed challenge bytes. Two samples aren't
+/// enough to pin down how they're computed, so the transformation is still
+/// to be derived; until then we replay these and check the device answers
+/// exactly as it did for the vendor software.
+pub const CAPTURED: &[Exchange] = &[
+    Exchange {
+        challenge: hex!("326d037f6f3049795376326433764f4245744530557935496b6f"),
+        response: hex!("3363b546749a682156f4336430187ad2426f72367277485233c8"),
+    },
+    Exchange {
+        challenge: hex!("326d037f6e3049795376326433764f4245744530557935496b90"),
+        response: hex!("3363914674763021567c336630ec7aac426f7236727748523300"),
+    },
+];
+
+/// Response bytes that were the same for both recorded challenges. `None`
+/// marks the bytes that depend on the challenge.
+pub const FIXED_RESPONSE: [Option<u8>; LEN] = [
+    Some(0x33),
+    Some(0x63),
+    None,
+    Some(0x46),
+    Some(0x74),
+    None,
+    None,
+    Some(0x21),
+    Some(0x56),
+    None,
+    Some(0x33),
+    None,
+    Some(0x30),
+    None,
+    Some(0x7a),
+    None,
+    Some(0x42),
+    Some(0x6f),
+    Some(0x72),
+    Some(0x36),
+    Some(0x72),
+    Some(0x77),
+    Some(0x48),
+    Some(0x52),
+    Some(0x33),
+    None,
+];
+
+/// Replays the 0x7b/0x7a handshake the vendor software performs before it
+/// enables programming. This is a replay of `CAPTURED`, not a derivation:
+/// nothing here computes a response. Fails if the device answers any
+/// challenge differently from the recording.
+///
+/// In the capture the exchange follows the second stage firmware upload,
+/// which `init_with` doesn't do yet, so it's only run when asked for.
+pub fn authenticate<T: UsbContext>(handle: &mut DeviceHandle<T>) -> Result<()> {
+    poke(handle, &OPEN)?;
+
+    for (i, exchange) in CAPTURED.iter().enumerate() {
+        let mut command = vec![EXCHANGE];
+        command.extend_from_slice(&exchange.challenge);
+        poke(handle, &command)?;
+
+        let chunk = peek(handle)?;
+        let response = &chunk.bytes[..chunk.len];
+        if response == exchange.response {
+            continue;
+        }
+
+        if response.len() != LEN {
+            bail!(
+                "Device rejected handshake: challenge {} got {} bytes back, \
+                 expected {}",
+                i,
+                response.len(),
+                LEN
+            );
+        }
+        let framing_ok = FIXED_RESPONSE
+            .iter()
+            .zip(response)
+            .all(|(fixed, actual)| fixed.is_none() || *fixed == Some(*actual));
+        bail!(
+            "Device rejected handshake: challenge {} answered {}{}",
+            i,
+            hex::encode(response),
+            if framing_ok {
+                " (framing matches, challenge dependent bytes differ)"
+            } else {
+                ""
+            }
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,156 @@
+use crate::bitbang::I2c;
+use crate::chip::{Chip, I2cEeprom};
+use crate::progress::Tracker;
+use crate::voltage;
+use anyhow::{anyhow, bail, Result};
+use rusb::{DeviceHandle, UsbContext};
+use std::time::{Duration, Instant};
+
+// 1010 in the top bits, A2-A1-A0 strapped low in the pinout
+const DEVICE_ADDRESS: u8 = 0x50;
+const READ_BIT: u8 = 0x01;
+
+// Bytes per tracker update when reading
+const READ_CHUNK: usize = 64;
+
+// tWR is 5-10 ms depending on the part, this allows for polling over USB
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Reads the first `len` bytes with one sequential read. The address
+/// counter runs across block boundaries, so the block bits only matter for
+/// where the read starts.
+pub fn read<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    eeprom: &I2cEeprom,
+    len: usize,
+    tracker: &mut Tracker,
+) -> Result<Vec<u8>> {
+    powered(handle, chip, |i2c| {
+        let mut image = Vec::with_capacity(len);
+        if len == 0 {
+            return Ok(image);
+        }
+        address(i2c, eeprom, 0)?;
+        i2c.start()?;
+        send(i2c, device(eeprom, 0) | READ_BIT)?;
+        while image.len() < len {
+            let more = image.len() + 1 < len;
+            image.push(i2c.read(more)?);
+            if !more || image.len() % READ_CHUNK == 0 {
+                tracker.update(image.len() as u64, image.len() as u32 - 1)?;
+            }
+        }
+        i2c.stop()?;
+        Ok(image)
+    })
+}
+
+/// Writes the pages of `image` that differ from `current`, then waits for
+/// each write cycle by polling for an acknowledge. VCC is off when this
+/// returns.
+pub fn program<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    eeprom: &I2cEeprom,
+    image: &[u8],
+    current: &[u8],
+    tracker: &mut Tracker,
+) -> Result<()> {
+    let page_size = eeprom.page_size.max(1) as usize;
+    powered(handle, chip, |i2c| {
+        for (page, (wanted, had)) in image
+            .chunks(page_size)
+            .zip(current.chunks(page_size))
+            .enumerate()
+        {
+            let base = (page * page_size) as u32;
+            if wanted != had {
+                address(i2c, eeprom, base)?;
+                for &byte in wanted {
+                    send(i2c, byte)?;
+                }
+                i2c.stop()?;
+                wait(i2c, eeprom, base)?;
+            }
+
+            let end = base + wanted.len() as u32;
+            tracker.update(u64::from(end), end - 1)?;
+        }
+        Ok(())
+    })
+}
+
+// Start, device address for writing and the word address, leaving the
+// transfer open for data or a repeated start
+fn address<T: UsbContext>(
+    i2c: &mut I2c<T>,
+    eeprom: &I2cEeprom,
+    addr: u32,
+) -> Result<()> {
+    i2c.start()?;
+    send(i2c, device(eeprom, addr))?;
+    let bytes = addr.to_be_bytes();
+    for &byte in &bytes[4 - usize::from(eeprom.address_bytes)..] {
+        send(i2c, byte)?;
+    }
+    Ok(())
+}
+
+// The write device byte for `addr`, block bits included
+fn device(eeprom: &I2cEeprom, addr: u32) -> u8 {
+    let block = (addr >> (8 * u32::from(eeprom.address_bytes))) as u8
+        & ((1 << eeprom.block_bits) - 1);
+    (DEVICE_ADDRESS | block) << 1
+}
+
+fn send<T: UsbContext>(i2c: &mut I2c<T>, byte: u8) -> Result<()> {
+    if !i2c.write(byte)? {
+        i2c.stop()?;
+        bail!("No acknowledge for {:02x}, is the chip seated?", byte);
+    }
+    Ok(())
+}
+
+// The chip ignores its address until the write cycle is over
+fn wait<T: UsbContext>(
+    i2c: &mut I2c<T>,
+    eeprom: &I2cEeprom,
+    addr: u32,
+) -> Result<()> {
+    let start = Instant::now();
+    loop {
+        i2c.start()?;
+        let ack = i2c.write(device(eeprom, addr))?;
+        i2c.stop()?;
+        if ack {
+            return Ok(());
+        }
+        if start.elapsed() > WRITE_TIMEOUT {
+            bail!(
+                "Write at {:#06x} didn't finish in {:?}",
+                addr,
+                WRITE_TIMEOUT
+            );
+        }
+    }
+}
+
+// Loads the pins and runs `f` with VCC at the chip's first level, switching
+// it off afterwards whatever happens
+fn powered<T: UsbContext, R>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    f: impl FnOnce(&mut I2c<T>) -> Result<R>,
+) -> Result<R> {
+    let level = *chip
+        .vcc
+        .first()
+        .ok_or_else(|| anyhow!("{} has no supply voltage", chip.name))?;
+    let mut i2c = I2c::new(handle, &chip.pin_config()?)?;
+    voltage::set_vcc(i2c.handle(), chip, level)?;
+    voltage::vcc_on(i2c.handle())?;
+    let result = f(&mut i2c);
+    voltage::vcc_off(i2c.handle())?;
+    result
+}
@@ -0,0 +1,177 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT: u8 = 0x02;
+const START_SEGMENT: u8 = 0x03;
+const EXTENDED_LINEAR: u8 = 0x04;
+const START_LINEAR: u8 = 0x05;
+
+// Data bytes per record when writing, what MPLAB and avr-objcopy use
+const RECORD_LEN: usize = 16;
+
+/// Parses Intel HEX into a map from byte address to value. Extended
+/// segment and linear address records are applied, start address records
+/// ignored, and every record's checksum is checked.
+pub fn parse(text: &str) -> Result<BTreeMap<u32, u8>> {
+    let mut bytes = BTreeMap::new();
+    let mut base = 0u32;
+
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record =
+            parse_record(line).with_context(|| format!("Line {}", n + 1))?;
+        let (kind, offset, data) = record;
+        match kind {
+            DATA => {
+                for (i, &byte) in data.iter().enumerate() {
+                    let addr = base.wrapping_add(u32::from(offset) + i as u32);
+                    bytes.insert(addr, byte);
+                }
+            }
+            END_OF_FILE => return Ok(bytes),
+            EXTENDED_SEGMENT | EXTENDED_LINEAR if data.len() == 2 => {
+                let value = u32::from(u16::from_be_bytes([data[0], data[1]]));
+                base = if kind == EXTENDED_SEGMENT {
+                    value << 4
+                } else {
+                    value << 16
+                };
+            }
+            START_SEGMENT | START_LINEAR => {}
+            _ => bail!("Line {}: bad record type {:02x}", n + 1, kind),
+        }
+    }
+    bail!("No end of file record")
+}
+
+/// Writes `bytes` as Intel HEX, 16 bytes to a record with extended linear
+/// address records where the address passes a 64K boundary.
+pub fn write(bytes: &BTreeMap<u32, u8>) -> String {
+    let mut out = String::new();
+    let mut upper = 0u16;
+    let mut record: Vec<u8> = vec![];
+    let mut start = 0u32;
+
+    for (&addr, &byte) in bytes {
+        let next = start + record.len() as u32;
+        let contiguous = addr == next && (addr & 0xffff) != 0;
+        if !contiguous || record.len() == RECORD_LEN {
+            flush(&mut out, start, &mut record);
+            start = addr;
+        }
+        if (addr >> 16) as u16 != upper {
+            upper = (addr >> 16) as u16;
+            push_record(&mut out, EXTENDED_LINEAR, 0, &upper.to_be_bytes());
+        }
+        record.push(byte);
+    }
+    flush(&mut out, start, &mut record);
+    push_record(&mut out, END_OF_FILE, 0, &[]);
+    out
+}
+
+// (type, offset, data) of one `:llaaaatt...cc` line
+fn parse_record(line: &str) -> Result<(u8, u16, Vec<u8>)> {
+    let hex = line
+        .strip_prefix(':')
+        .ok_or_else(|| anyhow!("Record doesn't start with ':'"))?;
+    let raw = hex::decode(hex)?;
+    if raw.len() < 5 || raw.len() != usize::from(raw[0]) + 5 {
+        bail!("Record length doesn't match its byte count");
+    }
+    let sum = raw.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    if sum != 0 {
+        bail!("Bad checksum");
+    }
+    let offset = u16::from_be_bytes([raw[1], raw[2]]);
+    Ok((raw[3], offset, raw[4..raw.len() - 1].to_vec()))
+}
+
+fn flush(out: &mut String, start: u32, record: &mut Vec<u8>) {
+    if !record.is_empty() {
+        push_record(out, DATA, start as u16, record);
+        record.clear();
+    }
+}
+
+fn push_record(out: &mut String, kind: u8, offset: u16, data: &[u8]) {
+    let mut raw = vec![data.len() as u8];
+    raw.extend_from_slice(&offset.to_be_bytes());
+    raw.push(kind);
+    raw.extend_from_slice(data);
+    let sum = raw.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    raw.push(sum.wrapping_neg());
+    let _ = writeln!(out, ":{}", hex::encode_upper(raw));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_data() {
+        let bytes = parse(":0300300002337A1E\n:00000001FF\n").unwrap();
+        let expected: BTreeMap<u32, u8> =
+            [(0x30, 0x02), (0x31, 0x33), (0x32, 0x7a)]
+                .iter()
+                .copied()
+                .collect();
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn extended_linear_address() {
+        let bytes =
+            parse(":020000040001F9\n:0100000055AA\n:00000001FF\n").unwrap();
+        assert_eq!(bytes.into_iter().collect::<Vec<_>>(), [(0x10000, 0x55)]);
+    }
+
+    #[test]
+    fn extended_segment_address() {
+        let bytes =
+            parse(":020000021000EC\n:0100040055A6\n:00000001FF\n").unwrap();
+        assert_eq!(bytes.into_iter().collect::<Vec<_>>(), [(0x10004, 0x55)]);
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        assert!(parse(":0100000055AB\n:00000001FF\n").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_length() {
+        assert!(parse(":0200000055AA\n:00000001FF\n").is_err());
+    }
+
+    #[test]
+    fn needs_end_of_file() {
+        assert!(parse(":0100000055AA\n").is_err());
+    }
+
+    #[test]
+    fn writes_extended_linear_at_64k() {
+        let bytes: BTreeMap<u32, u8> =
+            [(0xffff, 0x01), (0x10000, 0x55)].iter().copied().collect();
+        assert_eq!(
+            write(&bytes),
+            ":01FFFF000100\n:020000040001F9\n:0100000055AA\n:00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut bytes = BTreeMap::new();
+        for addr in (0..40).chain(0xfff0..0x10020).chain(0x2_0005..0x2_0007) {
+            bytes.insert(addr, addr as u8 ^ 0xa5);
+        }
+        let text = write(&bytes);
+        assert!(text.lines().all(|l| l.len() <= 11 + 2 * RECORD_LEN));
+        assert_eq!(parse(&text).unwrap(), bytes);
+    }
+}
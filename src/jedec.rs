@@ -0,0 +1,277 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::fmt;
+
+const STX: char = '\x02';
+const ETX: char = '\x03';
+
+// Fuses per L line when writing
+const LINE_FUSES: usize = 32;
+// Largest QF accepted, far past any part we program, so a bad file can't
+// ask for an arbitrarily large map
+const MAX_FUSES: usize = 1 << 20;
+
+/// A JEDEC (JESD3) fuse map, as read from or written to a `.jed` file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Jedec {
+    /// `true` is a blown (1) fuse
+    pub fuses: Vec<bool>,
+    /// Package pin count (QP), if given
+    pub pins: Option<u32>,
+    /// Whether the security fuse is to be set (G1)
+    pub security: bool,
+    /// Free text for N fields when writing
+    pub notes: Vec<String>,
+}
+
+impl Jedec {
+    /// An all-zero map of `fuses` fuses.
+    pub fn new(fuses: usize) -> Self {
+        Jedec {
+            fuses: vec![false; fuses],
+            pins: None,
+            security: false,
+            notes: vec![],
+        }
+    }
+
+    /// The C field: the fuses packed LSB first into bytes, summed mod 2^16.
+    pub fn checksum(&self) -> u16 {
+        self.fuses
+            .chunks(8)
+            .map(|byte| {
+                byte.iter()
+                    .enumerate()
+                    .filter(|(_, &f)| f)
+                    .fold(0u16, |b, (i, _)| b | 1 << i)
+            })
+            .fold(0u16, |sum, b| sum.wrapping_add(b))
+    }
+}
+
+/// Parses a `.jed` file. The fuse count comes from QF; L fields fill in
+/// fuses from the F default. The C fuse checksum and the transmission
+/// checksum after ETX are checked when present (a transmission checksum of
+/// 0000 means "not computed").
+pub fn parse(text: &str) -> Result<Jedec> {
+    let start = text.find(STX).map(|i| i + 1).unwrap_or(0);
+    let end = text[start..]
+        .find(ETX)
+        .map(|i| start + i)
+        .unwrap_or(text.len());
+    if let (Some(stx), true) = (text.find(STX), end < text.len()) {
+        check_transmission(&text[stx..], &text[end + 1..])?;
+    }
+
+    let mut count = None;
+    let mut default = false;
+    let mut pins = None;
+    let mut security = false;
+    let mut checksum = None;
+    let mut lines: Vec<(usize, &str)> = vec![];
+
+    // The first field is the free form header, before the first '*'
+    for field in text[start..end].split('*').skip(1) {
+        let field = field.trim();
+        let key_len = field.chars().next().map_or(0, char::len_utf8);
+        let (key, value) = field.split_at(key_len);
+        match key {
+            "Q" if value.starts_with('F') => {
+                count = Some(value[1..].trim().parse::<usize>()?)
+            }
+            "Q" if value.starts_with('P') => {
+                pins = Some(value[1..].trim().parse::<u32>()?)
+            }
+            "F" => default = value.trim() == "1",
+            "G" => security = value.trim() == "1",
+            "C" => {
+                checksum = Some(
+                    u16::from_str_radix(value.trim(), 16)
+                        .context("Bad C field")?,
+                )
+            }
+            "L" => {
+                let value = value.trim_start();
+                let digits = value
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(value.len());
+                let address = value[..digits].parse::<usize>()?;
+                lines.push((address, &value[digits..]));
+            }
+            _ => {}
+        }
+    }
+
+    let count = count.ok_or_else(|| anyhow!("No QF field"))?;
+    if count > MAX_FUSES {
+        bail!("QF{} is more fuses than any supported part", count);
+    }
+    let mut jedec = Jedec::new(count);
+    jedec.fuses = vec![default; count];
+    jedec.pins = pins;
+    jedec.security = security;
+    for (address, bits) in lines {
+        for (i, c) in bits.chars().filter(|c| !c.is_whitespace()).enumerate() {
+            let fuse = jedec
+                .fuses
+                .get_mut(address + i)
+                .ok_or_else(|| anyhow!("Fuse {} is past QF", address + i))?;
+            *fuse = match c {
+                '0' => false,
+                '1' => true,
+                _ => bail!("Bad fuse value {:?} in L{}", c, address),
+            };
+        }
+    }
+
+    if let Some(expected) = checksum {
+        if jedec.checksum() != expected {
+            bail!(
+                "Fuse checksum is {:04X}, file says {:04X}",
+                jedec.checksum(),
+                expected
+            );
+        }
+    }
+    Ok(jedec)
+}
+
+// Sum of every byte from STX through ETX, against the four hex digits after
+fn check_transmission(body: &str, after: &str) -> Result<()> {
+    let digits: String = after.chars().take(4).collect();
+    let expected = match u16::from_str_radix(&digits, 16) {
+        Ok(0) | Err(_) => return Ok(()),
+        Ok(expected) => expected,
+    };
+    let end = body.find(ETX).map(|i| i + 1).unwrap_or(body.len());
+    let sum = body[..end]
+        .bytes()
+        .fold(0u16, |sum, b| sum.wrapping_add(u16::from(b)));
+    if sum != expected {
+        bail!(
+            "Transmission checksum is {:04X}, file says {:04X}",
+            sum,
+            expected
+        );
+    }
+    Ok(())
+}
+
+impl fmt::Display for Jedec {
+    /// The whole file, STX to the transmission checksum.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut body = String::new();
+        body.push(STX);
+        // The header runs to the first '*' and is ignored by readers
+        body.push_str("\r\nJEDEC fuse map*\r\n");
+        for note in &self.notes {
+            body.push_str(&format!("N {}*\r\n", note));
+        }
+        if let Some(pins) = self.pins {
+            body.push_str(&format!("QP{}*\r\n", pins));
+        }
+        body.push_str(&format!("QF{}*\r\n", self.fuses.len()));
+        body.push_str(&format!("G{}*\r\n", u8::from(self.security)));
+        body.push_str("F0*\r\n");
+        for (i, line) in self.fuses.chunks(LINE_FUSES).enumerate() {
+            // All zero lines are covered by F0
+            if line.iter().any(|&f| f) {
+                let bits: String =
+                    line.iter().map(|&f| if f { '1' } else { '0' }).collect();
+                body.push_str(&format!("L{:05} {}*\r\n", i * LINE_FUSES, bits));
+            }
+        }
+        body.push_str(&format!("C{:04X}*\r\n", self.checksum()));
+        body.push(ETX);
+
+        let sum = body
+            .bytes()
+            .fold(0u16, |sum, b| sum.wrapping_add(u16::from(b)));
+        write!(f, "{}{:04X}\r\n", body, sum)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fuses 0, 9 and 17 blown: bytes 01 02 02, so C is 0005. No
+    // transmission checksum (0000).
+    const SMALL: &str = "\x02Test part*\r\nQP20*\r\nQF24*\r\nF0*\r\n\
+                         L0000 10000000 01000000*\r\nL0017 1*\r\n\
+                         C0005*\r\n\x030000\r\n";
+
+    #[test]
+    fn parses_known_file() {
+        let jedec = parse(SMALL).unwrap();
+        assert_eq!(jedec.fuses.len(), 24);
+        assert_eq!(jedec.pins, Some(20));
+        assert!(!jedec.security);
+        let blown: Vec<usize> = (0..24).filter(|&i| jedec.fuses[i]).collect();
+        assert_eq!(blown, [0, 9, 17]);
+        assert_eq!(jedec.checksum(), 0x0005);
+    }
+
+    #[test]
+    fn checksum_wraps() {
+        // 300 bytes of ff sum to 0x12ad4
+        let mut jedec = Jedec::new(300 * 8);
+        jedec.fuses.iter_mut().for_each(|f| *f = true);
+        assert_eq!(jedec.checksum(), 0x2ad4);
+    }
+
+    #[test]
+    fn default_fills_unlisted_fuses() {
+        let jedec = parse("\x02*QF10*F1*L0003 0*\x03").unwrap();
+        assert_eq!(jedec.fuses.iter().filter(|&&f| f).count(), 9);
+        assert!(!jedec.fuses[3]);
+    }
+
+    #[test]
+    fn rejects_bad_fuse_checksum() {
+        let text = SMALL.replace("C0005", "C0006");
+        assert!(parse(&text).is_err());
+    }
+
+    #[test]
+    fn rejects_fuse_past_qf() {
+        assert!(parse("\x02*QF8*L0007 11*\x03").is_err());
+    }
+
+    #[test]
+    fn rejects_huge_qf() {
+        assert!(parse("\x02*QF4000000000*\x03").is_err());
+    }
+
+    #[test]
+    fn ignores_non_ascii_fields() {
+        let jedec = parse("\x02*\u{e9}t\u{e9}*QF8*L0000 1*\x03").unwrap();
+        assert!(jedec.fuses[0]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut jedec = Jedec::new(100);
+        for i in (0..100).step_by(7) {
+            jedec.fuses[i] = true;
+        }
+        jedec.pins = Some(24);
+        jedec.security = true;
+        jedec.notes = vec!["round trip".to_string()];
+
+        let text = jedec.to_string();
+        let read = parse(&text).unwrap();
+        assert_eq!(read.fuses, jedec.fuses);
+        assert_eq!(read.pins, jedec.pins);
+        assert_eq!(read.security, jedec.security);
+    }
+
+    #[test]
+    fn rejects_bad_transmission_checksum() {
+        let mut jedec = Jedec::new(16);
+        jedec.fuses[3] = true;
+        // Same fuse map, one header byte changed
+        let text = jedec.to_string().replace("fuse map", "fuse maq");
+        let error = parse(&text).unwrap_err().to_string();
+        assert!(error.starts_with("Transmission checksum"), "{}", error);
+    }
+}
@@ -1,22 +1,83 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use hex_literal::hex;
 use rusb::{DeviceHandle, UsbContext};
 use std::time::Duration;
 
+pub mod avr;
+pub mod bitbang;
+pub mod buffer;
+pub mod bus;
+pub mod chip;
+pub mod contact;
+pub mod descriptor;
+pub mod eeprom;
+pub mod eprom;
+pub mod flash;
+pub mod gal;
+pub mod handshake;
+pub mod i2ceeprom;
+pub mod ihex;
+pub mod jedec;
+pub mod logic;
+pub mod microwire;
+pub mod pic;
+pub mod pins;
+pub mod programmer;
+pub mod progress;
+pub mod reader;
+pub mod register;
+pub mod selftest;
+pub mod spiflash;
+pub mod stability;
+pub mod voltage;
+
 // device uid pid are picked directly form `lsusb` result
 const VID: u16 = 0x04b4;
 const PID: u16 = 0x8613;
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
 
+// FX2 vendor request for reading and writing internal RAM, and the CPU
+// control/status register used to hold the 8051 in reset while we do it.
+const FIRMWARE_LOAD: u8 = 0xa0;
+const CPUCS: u16 = 0xe600;
+// The FX2 handles at most 4K per 0xA0 transfer
+const MAX_RAM_TRANSFER: usize = 0x1000;
+
+#[derive(Debug, Default)]
+pub struct InitOptions {
+    /// Read every firmware segment back before releasing the CPU and fail if
+    /// it doesn't match what was written.
+    pub verify_firmware: bool,
+    /// Replay the 0x7b/0x7a handshake after the firmware starts. Off by
+    /// default: the vendor software only sends it after the second stage
+    /// firmware upload, which `initialize_device` doesn't do yet. See
+    /// `handshake::authenticate`.
+    pub handshake: bool,
+}
+
 pub fn init() -> Result<DeviceHandle<impl UsbContext>> {
+    init_with(&InitOptions::default())
+}
+
+pub fn init_with(
+    options: &InitOptions,
+) -> Result<DeviceHandle<impl UsbContext>> {
     let mut handle = rusb::open_device_with_vid_pid(VID, PID).unwrap();
-    initialize_device(&mut handle);
+    initialize_device(&mut handle, options)?;
+
+    if options.handshake {
+        handshake::authenticate(&mut handle)?;
+    }
 
     // We reopen because the old handle doesn't reflect reality. libusb bug? Usage issue?
     // Probably just a general misunderstanding about how long handles are valid.
     Ok(handle)
 }
 
+/// The USB descriptors served by the firmware, uploaded to 0x1100 during
+/// initialization. See `descriptor::decode`.
+pub const FIRMWARE_DESCRIPTORS: &[u8] = &hex!("1201000200000040b40413860100010200010a06000200000040010009023c00010100a0320904000006ff00000007050102400000070502020002000705040200020007058102400000070586020002000705880200020009023c0001010080320904000006ff000000070501024000000705020240000007050402400000070581024000000705860240000007058802400000040309040e0347005100200055005300420024034500500052004f004d002000500072006f006700720061006d006d006500720020000000");
+
 pub struct Chunk {
     pub bytes: [u8; 64],
     pub len: usize,
@@ -60,17 +121,58 @@ pub fn read<T: UsbContext>(handle: &mut DeviceHandle<T>) -> Result<Chunk> {
     // 16 turns on VCC
     poke(handle, &[0x16])?;
     poke(handle, &[0x08, 0x00])?;
-    poke(handle, &hex!("040000200000000040"))?;
+
+    let result = read_block(handle, 0);
+
+    poke(handle, &[0x15])?; // Turn off VCC
+
+    result
+}
+
+/// Size of a single block read, the size of the 0x81 endpoint.
+pub const BLOCK_SIZE: usize = 64;
+
+// Every read in read-2532.pcapng has bit 13 of the address set (0x2000-0x2fff
+// for the 4K part). We don't know why yet, so it's OR-ed in as part of the
+// command. Addresses at or above it would collide with it, and how the
+// upper address bits are sent hasn't been captured.
+const READ_BASE: u32 = 0x2000;
+
+/// The end of the address space `read_block` can reach, 8 KB.
+pub const READ_LIMIT: u32 = READ_BASE;
+
+/// Reads one block starting at `addr`. VCC must already be on and the read
+/// mode selected (`08 00`). Blocks past `READ_LIMIT` are refused.
+pub fn read_block<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    addr: u32,
+) -> Result<Chunk> {
+    if u64::from(addr) + BLOCK_SIZE as u64 > u64::from(READ_LIMIT) {
+        bail!(
+            "Can't read {:#x}: block reads only reach {:#x} until the upper \
+             address bits are worked out from a capture",
+            addr,
+            READ_LIMIT
+        );
+    }
+
+    // 04, then the address and length as big endian u32s
+    let mut command = vec![0x04];
+    command.extend_from_slice(&(READ_BASE | addr).to_be_bytes());
+    command.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+    poke(handle, &command)?;
 
     // Reading sends back a payload (64 bytes) and then 00 to say "done" afaict.
     let result = peek(handle);
 
     peek(handle)?; // Flush the extra 00
-    poke(handle, &[0x15])?; // Turn off VCC
 
     result
 }
 
+// Kept for replaying captures, see the commented out stages of
+// initialize_device
+#[allow(dead_code)]
 fn mimic_bulk_transaction<T: UsbContext>(
     handle: &mut DeviceHandle<T>,
     data: &[u8],
@@ -93,24 +195,107 @@ fn mimic_bulk_transaction<T: UsbContext>(
     }
 }
 
+/// Reads the FX2's internal RAM using the 0xA0 vendor request. This works
+/// whether or not the CPU is running, but RAM only holds still while it's
+/// in reset.
+pub fn ram_read<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    addr: u16,
+    len: usize,
+) -> Result<Vec<u8>> {
+    if len > 0x10000 - usize::from(addr) {
+        bail!(
+            "RAM read of {} bytes at {:#06x} runs past 0xffff",
+            len,
+            addr
+        );
+    }
+    let mut bytes = vec![0; len];
+
+    for (i, chunk) in bytes.chunks_mut(MAX_RAM_TRANSFER).enumerate() {
+        let chunk_addr = usize::from(addr) + i * MAX_RAM_TRANSFER;
+        let read = handle.read_control(
+            0xc0,
+            FIRMWARE_LOAD,
+            chunk_addr as u16,
+            0,
+            chunk,
+            DEFAULT_TIMEOUT,
+        )?;
+        if read != chunk.len() {
+            bail!(
+                "Short RAM read at {:#06x}: {} of {} bytes",
+                chunk_addr,
+                read,
+                chunk.len()
+            );
+        }
+    }
+
+    Ok(bytes)
+}
+
 fn write_init_control<T: UsbContext>(
-    mut handle: &mut DeviceHandle<T>,
+    handle: &mut DeviceHandle<T>,
     other_control_transfers: &[(u16, &[u8])],
-) {
+    verify: bool,
+) -> Result<()> {
     // Common commands
-    for (val, data) in [(0xe600, &hex!("01")[..])]
-        .iter()
-        .chain(other_control_transfers.iter())
-        .chain([(0xe600, &hex!("00")[..])].iter())
-    {
-        handle
-            .write_control(0x40, 160, *val, 0, *data, DEFAULT_TIMEOUT)
-            .unwrap();
+    handle.write_control(
+        0x40,
+        FIRMWARE_LOAD,
+        CPUCS,
+        0,
+        &hex!("01"),
+        DEFAULT_TIMEOUT,
+    )?;
+
+    for (val, data) in other_control_transfers {
+        handle.write_control(
+            0x40,
+            FIRMWARE_LOAD,
+            *val,
+            0,
+            data,
+            DEFAULT_TIMEOUT,
+        )?;
+    }
+
+    // Check while the CPU is still in reset. On a mismatch we leave it there
+    // rather than let it run corrupt firmware.
+    if verify {
+        for (val, data) in other_control_transfers {
+            let actual = ram_read(handle, *val, data.len())?;
+            if let Some(offset) =
+                actual.iter().zip(data.iter()).position(|(a, e)| a != e)
+            {
+                bail!(
+                    "Firmware verify failed at {:#06x}: wrote {:02x}, read {:02x}",
+                    usize::from(*val) + offset,
+                    data[offset],
+                    actual[offset]
+                );
+            }
+        }
     }
+
+    handle.write_control(
+        0x40,
+        FIRMWARE_LOAD,
+        CPUCS,
+        0,
+        &hex!("00"),
+        DEFAULT_TIMEOUT,
+    )?;
+
+    Ok(())
 }
 
 // This is all from recordings and a blackbox
-fn initialize_device<T: UsbContext>(mut handle: &mut DeviceHandle<T>) {
+fn initialize_device<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    options: &InitOptions,
+) -> Result<()> {
     handle
         .set_active_configuration(1)
         .expect("Failed to set active configuration");
@@ -121,10 +306,10 @@ fn initialize_device<T: UsbContext>(mut handle: &mut DeviceHandle<T>) {
         .set_alternate_setting(0, 0)
         .expect("Failed to set the interface");
 
-    write_init_control(&mut handle, &[
+    write_init_control(handle, &[
     // From initialization.pcapng
     // Frame 38
-    (0x1100, &hex!("1201000200000040b40413860100010200010a06000200000040010009023c00010100a0320904000006ff00000007050102400000070502020002000705040200020007058102400000070586020002000705880200020009023c0001010080320904000006ff000000070501024000000705020240000007050402400000070581024000000705860240000007058802400000040309040e0347005100200055005300420024034500500052004f004d002000500072006f006700720061006d006d006500720020000000")[..]),
+    (0x1100, FIRMWARE_DESCRIPTORS),
     (0x19bc, &hex!("00010202030304040505")[..]),
     (0x15c5, &hex!("ec4efeed4f24bcf58274193ef583e493ff3395e0feef24a1ffee34e68f82f5832290e6bce0547eff7e00e0d394807c0022f0e5242401f524e43523f523e43522f522e43521f52122af28ae27ad26ac25ab24aa23a922a821c3021258e52e2524f582e52d3523f58322")[..]),
     (0x0fa1, &hex!("e4f52cf52bf52af529c203c200c202c20112134812162e12199b7e117f008e0a8f0b75121175131275081175091c751011751158751411751594ee54e07003021093752d00752e808e2f8f30c374ca9fff74119ecf2402cf3400fee48f288e27f526f525f524f523f522f52112160d500a12162174cd1215f680f1e4f524f523f522f52112160d5017e5302524f582e52f3523f583e0ff121621ef1215f680e4852d0a852e0b74002480ff741134fffec3e5139ff513e5129ef512c3e50d9ff50de50c9ef50cc3e50f9ff50fe50e9ef50ec3e5099ff509e5089ef508c3e5119ff511e5109ef510c3e5159ff515e5149ef514d2e843d82090e668e04409f090e65ce0443df0d2af90e680e020e105d2041210d090e680e054f7f0538ef8c203300105120df7c20112191512162e80f0")[..]),
@@ -191,7 +376,7 @@ fn initialize_device<T: UsbContext>(mut handle: &mut DeviceHandle<T>) {
     (0x14c4, &hex!("020fa1e493a3f8e493a34003f68001f208dff48029e493a3f85407240cc8c333c4540f4420c8834004f456800146f6dfe4800b0102040810204080901846e47e019360bca3ff543f30e509541ffee493a360010ecf54c025e060a840b8e493a3fae493a3f8e493a3c8c582c8cac583caf0a3c8c582c8cac583cadfe9dee780be")[..]),
     (0x1867, &hex!("00")[..]),
     // Frame 172
-    ]);
+    ], options.verify_firmware)?;
 
     // Bulk reads/writes after control transfers
     // These are dumps from bytes 30 on.
@@ -482,4 +667,6 @@ fn initialize_device<T: UsbContext>(mut handle: &mut DeviceHandle<T>) {
     //{
     //mimic_bulk_transaction(&mut handle, &data);
     //}
+
+    Ok(())
 }
@@ -0,0 +1,612 @@
+use crate::pins::{
+    configure_pins, drive_pins, sense_pins, zif_pin, PinConfig, PinLevels,
+    PinRole,
+};
+use crate::voltage::{self, Vcc};
+use anyhow::{anyhow, bail, Context, Result};
+use rusb::{DeviceHandle, UsbContext};
+use std::fmt;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// The bundled 74xx/40xx vectors. The format is described at the top.
+pub const LIBRARY: &str = include_str!("../vectors/logic.vec");
+
+// Every part in the library runs from 5 V
+const SUPPLY: Vcc = Vcc::V5_0;
+// Supply settling before the first step
+const POWER_UP: Duration = Duration::from_millis(20);
+
+/// One pin's part in a test step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Low,
+    High,
+    /// Driven high then low again before the outputs are checked
+    Clock,
+    ExpectLow,
+    ExpectHigh,
+    DontCare,
+    Gnd,
+    Vcc,
+}
+
+impl Level {
+    fn parse(c: char) -> Option<Level> {
+        Some(match c.to_ascii_uppercase() {
+            '0' => Level::Low,
+            '1' => Level::High,
+            'C' => Level::Clock,
+            'L' => Level::ExpectLow,
+            'H' => Level::ExpectHigh,
+            'X' => Level::DontCare,
+            'G' => Level::Gnd,
+            'V' => Level::Vcc,
+            _ => return None,
+        })
+    }
+
+    fn is_driven(self) -> bool {
+        matches!(self, Level::Low | Level::High | Level::Clock)
+    }
+
+    fn is_checked(self) -> bool {
+        matches!(self, Level::ExpectLow | Level::ExpectHigh)
+    }
+}
+
+/// Pins whose failures are reported together, usually one gate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gate {
+    pub name: String,
+    /// Chip pins, 1 based
+    pub pins: Vec<u8>,
+}
+
+/// A logic part and its test vectors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogicPart {
+    /// The part number first, then the parts it stands in for
+    pub names: Vec<String>,
+    pub description: String,
+    pub pins: u8,
+    pub gates: Vec<Gate>,
+    /// One entry per pin per step, pin 1 first
+    pub steps: Vec<Vec<Level>>,
+}
+
+impl LogicPart {
+    pub fn name(&self) -> &str {
+        &self.names[0]
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.names.iter().any(|n| n.eq_ignore_ascii_case(name))
+    }
+
+    /// Chip pins with the given level in the first step.
+    fn pins_at(&self, level: Level) -> Vec<u8> {
+        (1..=self.pins)
+            .filter(|&p| self.steps[0][p as usize - 1] == level)
+            .collect()
+    }
+
+    /// Chip pins that are driven in any step.
+    pub fn inputs(&self) -> Vec<u8> {
+        self.pins_where(Level::is_driven)
+    }
+
+    /// Chip pins that are checked in any step.
+    pub fn outputs(&self) -> Vec<u8> {
+        self.pins_where(Level::is_checked)
+    }
+
+    pub fn gnd(&self) -> Vec<u8> {
+        self.pins_at(Level::Gnd)
+    }
+
+    pub fn vcc(&self) -> Vec<u8> {
+        self.pins_at(Level::Vcc)
+    }
+
+    fn pins_where(&self, f: fn(Level) -> bool) -> Vec<u8> {
+        (1..=self.pins)
+            .filter(|&p| self.steps.iter().any(|s| f(s[p as usize - 1])))
+            .collect()
+    }
+
+    /// Inputs driven, outputs pulled up, supplies connected and anything
+    /// else left floating.
+    pub fn pin_config(&self) -> Result<PinConfig> {
+        let mut config = PinConfig::new();
+        let roles = [
+            (self.inputs(), PinRole::LogicIn),
+            (self.outputs(), PinRole::PullUp),
+            (self.gnd(), PinRole::Gnd),
+            (self.vcc(), PinRole::Vcc),
+        ];
+        for (pins, role) in &roles {
+            for &pin in pins {
+                config.set(zif_pin(pin, self.pins)?, *role)?;
+            }
+        }
+        Ok(config)
+    }
+
+    // Each pin only ever drives or is checked, and the supplies don't move
+    fn check(&self) -> Result<()> {
+        if self.steps.is_empty() {
+            bail!("No test steps");
+        }
+        for (n, step) in self.steps.iter().enumerate() {
+            if step.len() != self.pins as usize {
+                bail!(
+                    "Step {} has {} pins, not {}",
+                    n + 1,
+                    step.len(),
+                    self.pins
+                );
+            }
+            for (a, b) in step.iter().zip(&self.steps[0]) {
+                let supply = |l: &Level| matches!(l, Level::Gnd | Level::Vcc);
+                if (supply(a) || supply(b)) && a != b {
+                    bail!("Step {} moves a supply pin", n + 1);
+                }
+            }
+        }
+        if let Some(&pin) =
+            self.inputs().iter().find(|p| self.outputs().contains(p))
+        {
+            bail!("Pin {} is both driven and checked", pin);
+        }
+        if self.gnd().is_empty() || self.vcc().is_empty() {
+            bail!("No G or V pin");
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for LogicPart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<8} {}", self.name(), self.description)
+    }
+}
+
+/// Parses a vector file. Lines starting with `#` are comments.
+pub fn parse(text: &str) -> Result<Vec<LogicPart>> {
+    let mut parts: Vec<LogicPart> = vec![];
+    for (n, line) in text.lines().enumerate() {
+        parse_line(&mut parts, line)
+            .with_context(|| format!("Line {}", n + 1))?;
+    }
+    for part in &parts {
+        part.check()
+            .with_context(|| format!("Part {}", part.name()))?;
+    }
+    Ok(parts)
+}
+
+fn parse_line(parts: &mut Vec<LogicPart>, line: &str) -> Result<()> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(());
+    }
+    let mut words = line.split_whitespace();
+    let keyword = words.next().unwrap_or_default();
+    if keyword == "part" {
+        let names: Vec<String> = words.map(String::from).collect();
+        if names.is_empty() {
+            bail!("`part` needs a name");
+        }
+        parts.push(LogicPart {
+            names,
+            description: String::new(),
+            pins: 0,
+            gates: vec![],
+            steps: vec![],
+        });
+        return Ok(());
+    }
+
+    let part = parts
+        .last_mut()
+        .ok_or_else(|| anyhow!("Expected `part` first"))?;
+    match keyword {
+        "desc" => part.description = line["desc".len()..].trim().to_string(),
+        "pins" => {
+            part.pins = words.next().unwrap_or_default().parse()?;
+            zif_pin(1, part.pins)?;
+        }
+        "gate" => {
+            let name =
+                words.next().ok_or_else(|| anyhow!("`gate` needs a name"))?;
+            let pins = words
+                .map(|w| w.parse::<u8>())
+                .collect::<Result<Vec<_>, _>>()?;
+            part.gates.push(Gate {
+                name: name.to_string(),
+                pins,
+            });
+        }
+        _ => {
+            let step = line
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .map(|c| {
+                    Level::parse(c)
+                        .ok_or_else(|| anyhow!("Bad pin level {:?}", c))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            part.steps.push(step);
+        }
+    }
+    Ok(())
+}
+
+/// The bundled parts.
+pub fn library() -> Result<Vec<LogicPart>> {
+    parse(LIBRARY)
+}
+
+/// Looks `name` up among the bundled parts and their aliases.
+pub fn find(name: &str) -> Result<LogicPart> {
+    library()?
+        .into_iter()
+        .find(|p| p.matches(name))
+        .ok_or_else(|| anyhow!("No test vectors for {}", name))
+}
+
+/// An output that read wrong.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Failure {
+    /// 0 based
+    pub step: usize,
+    /// Chip pin, 1 based
+    pub pin: u8,
+    /// What the vectors expected; the pin read the opposite
+    pub expected: bool,
+}
+
+/// Result of `test`.
+#[derive(Debug)]
+pub struct TestReport<'a> {
+    pub part: &'a LogicPart,
+    pub failures: Vec<Failure>,
+}
+
+impl TestReport<'_> {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// Output pins that failed at least once.
+    pub fn failing_pins(&self) -> Vec<u8> {
+        let mut pins: Vec<u8> = self.failures.iter().map(|f| f.pin).collect();
+        pins.sort_unstable();
+        pins.dedup();
+        pins
+    }
+
+    /// Gates with a failing output.
+    pub fn failing_gates(&self) -> Vec<&Gate> {
+        let pins = self.failing_pins();
+        self.part
+            .gates
+            .iter()
+            .filter(|g| g.pins.iter().any(|p| pins.contains(p)))
+            .collect()
+    }
+}
+
+impl fmt::Display for TestReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let steps = self.part.steps.len();
+        if self.passed() {
+            return write!(f, "{}: passed {} steps", self.part.name(), steps);
+        }
+        write!(f, "{}: FAILED", self.part.name())?;
+        for gate in self.failing_gates() {
+            write!(f, "\ngate {} (pins", gate.name)?;
+            for pin in &gate.pins {
+                write!(f, " {}", pin)?;
+            }
+            write!(f, ")")?;
+        }
+        for pin in self.failing_pins() {
+            let failures: Vec<&Failure> =
+                self.failures.iter().filter(|f| f.pin == pin).collect();
+            let stuck = failures.iter().all(|f| f.expected)
+                || failures.iter().all(|f| !f.expected);
+            write!(
+                f,
+                "\npin {}: wrong in {} of {} steps",
+                pin,
+                failures.len(),
+                steps
+            )?;
+            if stuck {
+                let level = if failures[0].expected { "low" } else { "high" };
+                write!(f, ", stuck {}", level)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How well a candidate fitted in `identify`.
+#[derive(Debug)]
+pub struct Match<'a> {
+    pub part: &'a LogicPart,
+    /// Output readings the vectors check
+    pub checks: usize,
+    pub failures: usize,
+    /// An input pin the chip was already pulling low. Nothing was driven
+    /// for this candidate.
+    pub conflict: Option<u8>,
+}
+
+impl Match<'_> {
+    /// Fraction of checks that passed, 0 for a conflict.
+    pub fn score(&self) -> f64 {
+        match self.conflict {
+            Some(_) => 0.0,
+            None => (self.checks - self.failures) as f64 / self.checks as f64,
+        }
+    }
+
+    pub fn is_exact(&self) -> bool {
+        self.conflict.is_none() && self.failures == 0
+    }
+}
+
+impl fmt::Display for Match<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<40} ", self.part.to_string())?;
+        match self.conflict {
+            Some(pin) => write!(f, "skipped, pin {} is an output", pin),
+            None => write!(
+                f,
+                "{:>3}% ({}/{} checks)",
+                (self.score() * 100.0).round(),
+                self.checks - self.failures,
+                self.checks
+            ),
+        }
+    }
+}
+
+// How inputs are driven
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Drive {
+    // The logic drivers both ways
+    Strong,
+    // Low through the drivers, high through the pull-ups, so a chip output
+    // taken for an input is never fought when it's high
+    Safe,
+}
+
+/// Powers `part` from 5 V and runs its steps: inputs are set, clock pins
+/// pulsed, then every checked output is read. VCC is off and the pins
+/// floating afterwards, whatever happens.
+pub fn test<'a, T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    part: &'a LogicPart,
+) -> Result<TestReport<'a>> {
+    let config = part.pin_config()?;
+    let failures = powered(handle, &config, |handle| {
+        run(handle, part, config.clone(), Drive::Strong)
+    })?;
+    Ok(TestReport { part, failures })
+}
+
+/// Runs every part in `parts` with `pins` pins against the chip in the
+/// socket and ranks them, best first.
+///
+/// Inputs are only ever pulled up or driven low, and before anything is
+/// driven the chip is powered with every candidate input pulled up: a
+/// candidate is skipped if the chip pulls one of them low, since that pin
+/// is really an output. Supplies still go where each candidate has them,
+/// which for 74xx/40xx parts is nearly always the corner pins.
+pub fn identify<'a, T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    parts: &'a [LogicPart],
+    pins: u8,
+) -> Result<Vec<Match<'a>>> {
+    let mut matches = vec![];
+    for part in parts.iter().filter(|p| p.pins == pins) {
+        let mut config = part.pin_config()?;
+        for &pin in &part.inputs() {
+            config.set(zif_pin(pin, part.pins)?, PinRole::PullUp)?;
+        }
+        let result = powered(handle, &config, |handle| {
+            let sensed = sense_pins(handle)?;
+            for pin in part.inputs() {
+                if !sensed.is_high(zif_pin(pin, part.pins)?)? {
+                    return Ok(Err(pin));
+                }
+            }
+            run(handle, part, config.clone(), Drive::Safe).map(Ok)
+        })?;
+        let checks = part
+            .steps
+            .iter()
+            .flatten()
+            .filter(|l| l.is_checked())
+            .count();
+        matches.push(Match {
+            part,
+            checks,
+            failures: result.as_ref().map(Vec::len).unwrap_or(checks),
+            conflict: result.err(),
+        });
+    }
+    if matches.is_empty() {
+        bail!("No {} pin parts in the vectors", pins);
+    }
+    matches.sort_by(|a, b| b.score().total_cmp(&a.score()));
+    Ok(matches)
+}
+
+// Loads `config` with every driver low, powers the chip from 5 V and runs
+// `f`. VCC is off and the pins floating afterwards, whatever happens.
+fn powered<T: UsbContext, R>(
+    handle: &mut DeviceHandle<T>,
+    config: &PinConfig,
+    f: impl FnOnce(&mut DeviceHandle<T>) -> Result<R>,
+) -> Result<R> {
+    voltage::vcc_off(handle)?;
+    voltage::vpp_off(handle)?;
+    configure_pins(handle, config)?;
+    drive_pins(handle, PinLevels(0))?;
+    voltage::write_vcc_level(handle, SUPPLY)?;
+    voltage::vcc_on(handle)?;
+    sleep(POWER_UP);
+
+    let result = f(handle);
+    voltage::vcc_off(handle)?;
+    configure_pins(handle, &PinConfig::new())?;
+    result
+}
+
+// Runs the steps with `config` loaded, returning the outputs that read
+// wrong
+fn run<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    part: &LogicPart,
+    mut config: PinConfig,
+    drive: Drive,
+) -> Result<Vec<Failure>> {
+    let zif = |pin| zif_pin(pin, part.pins);
+    let mut levels = PinLevels(0);
+    let mut set = |handle: &mut DeviceHandle<T>, pins: &[(u8, bool)]| {
+        for &(pin, high) in pins {
+            levels.set(pin, high)?;
+            if drive == Drive::Safe {
+                let role = if high {
+                    PinRole::PullUp
+                } else {
+                    PinRole::LogicIn
+                };
+                config.set(pin, role)?;
+            }
+        }
+        // Latch the levels before a pin is switched over to its driver
+        drive_pins(handle, levels)?;
+        if drive == Drive::Safe {
+            configure_pins(handle, &config)?;
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+
+    let mut failures = vec![];
+    for (n, step) in part.steps.iter().enumerate() {
+        let mut inputs = vec![];
+        let mut clocks = vec![];
+        for (pin, &level) in (1..=part.pins).zip(step) {
+            match level {
+                Level::Low | Level::Clock => inputs.push((zif(pin)?, false)),
+                Level::High => inputs.push((zif(pin)?, true)),
+                _ => {}
+            }
+            if level == Level::Clock {
+                clocks.push(zif(pin)?);
+            }
+        }
+        set(handle, &inputs)?;
+        if !clocks.is_empty() {
+            for &high in &[true, false] {
+                let pulse: Vec<_> = clocks.iter().map(|&p| (p, high)).collect();
+                set(handle, &pulse)?;
+            }
+        }
+
+        let sensed = sense_pins(handle)?;
+        for (pin, &level) in (1..=part.pins).zip(step) {
+            let expected = match level {
+                Level::ExpectLow => false,
+                Level::ExpectHigh => true,
+                _ => continue,
+            };
+            if sensed.is_high(zif(pin)?)? != expected {
+                failures.push(Failure {
+                    step: n,
+                    pin,
+                    expected,
+                });
+            }
+        }
+    }
+    Ok(failures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INVERTER: &str = "
+# comment
+part T1 ALIAS
+desc Test inverter
+pins 4
+gate 1 1 2
+0H G V
+1L GV
+";
+
+    #[test]
+    fn parses_a_part() {
+        let parts = parse(INVERTER).unwrap();
+        assert_eq!(parts.len(), 1);
+        let part = &parts[0];
+        assert_eq!(part.name(), "T1");
+        assert!(part.matches("alias"));
+        assert_eq!(part.description, "Test inverter");
+        assert_eq!(part.pins, 4);
+        assert_eq!(
+            part.gates,
+            vec![Gate {
+                name: "1".to_string(),
+                pins: vec![1, 2]
+            }]
+        );
+        assert_eq!(
+            part.steps[1],
+            vec![Level::High, Level::ExpectLow, Level::Gnd, Level::Vcc]
+        );
+        assert_eq!(part.inputs(), vec![1]);
+        assert_eq!(part.outputs(), vec![2]);
+        assert_eq!((part.gnd(), part.vcc()), (vec![3], vec![4]));
+    }
+
+    #[test]
+    fn library_parses() {
+        let parts = library().unwrap();
+        assert!(!parts.is_empty());
+        assert!(find("74LS00").is_ok());
+        for part in &parts {
+            assert!(part.pin_config().is_ok(), "{}", part.name());
+        }
+    }
+
+    fn error(text: &str) -> String {
+        format!("{:#}", parse(text).unwrap_err())
+    }
+
+    #[test]
+    fn reports_the_bad_line() {
+        assert!(error("pins 4").starts_with("Line 1: Expected `part` first"));
+        assert!(error("part T1\npins 4\n0Q GV").starts_with("Line 3"));
+        assert!(error("part").contains("needs a name"));
+        assert!(error("part T1\npins 99").starts_with("Line 2"));
+    }
+
+    #[test]
+    fn rejects_inconsistent_steps() {
+        let part = |steps: &str| format!("part T1\npins 4\n{}", steps);
+        assert!(error(&part("")).contains("No test steps"));
+        assert!(error(&part("0HG")).contains("Step 1 has 3 pins"));
+        assert!(error(&part("0HGV\n1LVG")).contains("moves a supply"));
+        assert!(error(&part("0HGV\nH0GV")).contains("both driven"));
+        assert!(error(&part("0HXX")).contains("No G or V"));
+    }
+}
@@ -1,17 +1,47 @@
 use anyhow::{anyhow, Result};
-use gq4x4;
-use hex;
+use gq4x4::buffer::{self, Buffer};
+use gq4x4::chip::{self, ChipKind};
+use gq4x4::jedec::{self, Jedec};
+use gq4x4::pic::PicImage;
+use gq4x4::programmer::{self, Difference, Programmer};
+use gq4x4::progress::{Cancelled, Observer, Progress};
+use gq4x4::register::{self, BitOp};
+use gq4x4::{avr, contact, ihex, logic, selftest, spiflash, voltage};
 use pretty_hex::*;
 use rusb::{DeviceHandle, UsbContext};
 use rustyline::{completion::Completer, Context};
 use rustyline_derive::{Helper, Highlighter, Hinter, Validator};
+use std::convert::TryFrom;
+use std::io::Write;
 use std::time::Duration;
 
 fn main() -> Result<()> {
-    let mut handle = gq4x4::init()?;
+    // Image tools run without a programmer attached
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    if let Some(name) = args.first() {
+        if OFFLINE_COMMANDS.contains(name) {
+            println!("{}", run_offline(&args)?);
+            return Ok(());
+        }
+    }
+
+    let options = gq4x4::InitOptions {
+        verify_firmware: std::env::args().any(|a| a == "--verify-firmware"),
+        handshake: std::env::args().any(|a| a == "--handshake"),
+    };
+    let mut programmer = programmer::open(&options)?;
+    println!("Firmware: {}", programmer.firmware());
+
+    // Ctrl-C while a command runs cancels it. At the prompt rustyline sees
+    // it first and exits.
+    let cancel = programmer.cancel_token();
+    ctrlc::set_handler(move || cancel.cancel())?;
+    programmer.set_observer(Some(progress_bar()));
 
     let mut rl = rustyline::Editor::<ReadlineHelper>::new();
     rl.set_helper(Some(ReadlineHelper {}));
+    let mut buffer = Buffer::new();
 
     loop {
         let line = rl.readline(">> ")?;
@@ -23,12 +53,17 @@ fn main() -> Result<()> {
                 Some((_, command)) => match command {
                     Command::Quit => return Ok(()),
                     _ => {
+                        programmer.cancel_token().reset();
                         match run_command(
-                            &mut handle,
+                            &mut programmer,
+                            &mut buffer,
                             command,
-                            &parts.collect(),
+                            &parts.collect::<Vec<_>>(),
                         ) {
                             Ok(s) => println!("{}", s),
+                            Err(e) if e.is::<Cancelled>() => {
+                                println!("\nCancelled, VCC and VPP are off")
+                            }
                             Err(e) => println!("Error: {}", e),
                         }
                     }
@@ -42,40 +77,687 @@ fn main() -> Result<()> {
 }
 
 fn run_command<T: UsbContext>(
-    mut handle: &mut DeviceHandle<T>,
+    programmer: &mut Programmer<T>,
+    buffer: &mut Buffer,
     command: &Command,
-    args: &Vec<&str>,
+    args: &[&str],
 ) -> Result<String> {
     use Command::*;
 
     match *command {
         PrintDetails => {
-            let details = device_details(&handle)?;
+            let details = device_details(programmer.handle())?;
             Ok(format!("{:#?}", details))
         }
-        Read => {
-            let chunk = gq4x4::read(&mut handle)?;
-            let chunk = &chunk.bytes[..chunk.len];
-            Ok(format!("{}", pretty_hex(&chunk)))
-        }
+        Read => match args {
+            ["--passes", passes, rest @ ..] => {
+                match rest {
+                    [] => {}
+                    [name] => programmer.select(chip::find(name)?)?,
+                    _ => {
+                        return Err(anyhow!("Usage: read [--passes N] [chip]"))
+                    }
+                }
+                let passes = parse_number(passes)? as usize;
+                let stability = programmer.read_passes(passes)?;
+                buffer.replace(stability.image.clone());
+                Ok(format!("{}\n{}", pretty_hex(&stability.image), stability))
+            }
+            [name] => {
+                programmer.select(chip::find(name)?)?;
+                buffer.replace(programmer.read()?);
+                Ok(pretty_hex(&buffer.data()))
+            }
+            [] if programmer.chip().is_ok() => {
+                buffer.replace(programmer.read()?);
+                Ok(pretty_hex(&buffer.data()))
+            }
+            // Nothing selected: the raw block read
+            [] => {
+                let chunk = gq4x4::read(programmer.handle())?;
+                let chunk = &chunk.bytes[..chunk.len];
+                Ok(pretty_hex(&chunk))
+            }
+            _ => Err(anyhow!("Usage: read [--passes N] [chip]")),
+        },
         FirmwareVersion => {
-            let chunk = gq4x4::firmware_version(&mut handle)?;
+            let chunk = gq4x4::firmware_version(programmer.handle())?;
             let chunk = &chunk.bytes[..chunk.len];
-            Ok(format!("{}", pretty_hex(&chunk)))
+            Ok(pretty_hex(&chunk))
         }
         SerialNumber => {
-            let chunk = gq4x4::serial_number(&mut handle)?;
+            let chunk = gq4x4::serial_number(programmer.handle())?;
             let chunk = &chunk.bytes[..chunk.len];
-            Ok(format!("{}", pretty_hex(&chunk)))
+            Ok(pretty_hex(&chunk))
         }
         Poke => {
-            gq4x4::poke(&mut handle, &hex::decode(args.join(""))?)?;
+            gq4x4::poke(programmer.handle(), &hex::decode(args.join(""))?)?;
             Ok("Ok".to_string())
         }
         Peek => {
-            let chunk = gq4x4::peek(&mut handle)?;
+            let chunk = gq4x4::peek(programmer.handle())?;
             let chunk = &chunk.bytes[..chunk.len];
-            Ok(format!("{}", pretty_hex(&chunk)))
+            Ok(pretty_hex(&chunk))
+        }
+        Ram => match args {
+            ["read", addr, len] => {
+                let bytes = gq4x4::ram_read(
+                    programmer.handle(),
+                    u16::try_from(parse_number(addr)?)?,
+                    parse_number(len)? as usize,
+                )?;
+                Ok(pretty_hex(&bytes))
+            }
+            _ => Err(anyhow!("Usage: ram read <addr> <len>")),
+        },
+        SelectChip => match args {
+            [] => Ok(chip::CHIPS
+                .iter()
+                .map(|c| c.name)
+                .collect::<Vec<_>>()
+                .join(" ")),
+            [name] => {
+                let chip = chip::find(name)?;
+                programmer.select(chip)?;
+                Ok(format!("Selected {}", chip.name))
+            }
+            _ => Err(anyhow!("Usage: chip [name]")),
+        },
+        SetVcc => match args {
+            ["on"] => {
+                programmer.vcc_on()?;
+                Ok("VCC on".to_string())
+            }
+            ["off"] => {
+                programmer.vcc_off()?;
+                Ok("VCC off".to_string())
+            }
+            [level] => {
+                let level = voltage::parse_vcc(level)?;
+                programmer.set_vcc(level)?;
+                Ok(format!("VCC set to {}", level))
+            }
+            _ => Err(anyhow!("Usage: vcc <3-6.25|on|off>")),
+        },
+        SetVpp => match args {
+            ["on"] => {
+                // Checked against the selected chip again, it may have
+                // changed since the level was set
+                programmer.vpp_on()?;
+                Ok("VPP on".to_string())
+            }
+            ["off"] => {
+                programmer.vpp_off()?;
+                Ok("VPP off".to_string())
+            }
+            [level] => {
+                let level = voltage::parse_vpp(level)?;
+                programmer.set_vpp(level)?;
+                Ok(format!("VPP set to {}", level))
+            }
+            _ => Err(anyhow!("Usage: vpp <12.5|12.75|13|21|25|on|off>")),
+        },
+        Contact => {
+            let chip = programmer.chip()?;
+            let report = contact::contact_check(programmer.handle(), chip)?;
+            Ok(report.to_string())
+        }
+        Test => {
+            let parts = match args {
+                [] => {
+                    return Ok(logic::library()?
+                        .iter()
+                        .map(|p| p.to_string())
+                        .collect::<Vec<_>>()
+                        .join("\n"))
+                }
+                [_] => logic::library()?,
+                [_, path] => logic::parse(&std::fs::read_to_string(path)?)?,
+                _ => return Err(anyhow!("Usage: test [part [vector file]]")),
+            };
+            let part = parts
+                .iter()
+                .find(|p| p.matches(args[0]))
+                .ok_or_else(|| anyhow!("No test vectors for {}", args[0]))?;
+            programmer.power_down()?;
+            let report = logic::test(programmer.handle(), part)?;
+            Ok(report.to_string())
+        }
+        IdentifyLogic => {
+            let pins = match args {
+                [pins] => u8::try_from(parse_number(pins)?)?,
+                _ => return Err(anyhow!("Usage: identify-logic <pins>")),
+            };
+            let parts = logic::library()?;
+            programmer.power_down()?;
+            let matches = logic::identify(programmer.handle(), &parts, pins)?;
+            let mut out = format!(
+                "{} of {} candidates passed every check, best first:",
+                matches.iter().filter(|m| m.is_exact()).count(),
+                matches.len()
+            );
+            for m in &matches {
+                out.push_str(&format!("\n{}", m));
+            }
+            Ok(out)
+        }
+        Buffer => match args {
+            [] => Ok(format!("{} bytes in the buffer", buffer.len())),
+            ["new", size] => {
+                buffer.replace(vec![0xff; parse_number(size)? as usize]);
+                Ok(format!("New buffer of {} bytes", buffer.len()))
+            }
+            ["load", path] => {
+                buffer.replace(std::fs::read(path)?);
+                Ok(format!("Loaded {} bytes from {}", buffer.len(), path))
+            }
+            ["save", path] => {
+                std::fs::write(path, buffer.data())?;
+                Ok(format!("Wrote {} bytes to {}", buffer.len(), path))
+            }
+            ["view", range @ ..] => {
+                let (start, len) = match range {
+                    [] => (0, None),
+                    [start] => (parse_number(start)?, None),
+                    [start, len] => {
+                        (parse_number(start)?, Some(parse_number(len)?))
+                    }
+                    _ => return Err(anyhow!(BUFFER_USAGE)),
+                };
+                // A screenful unless asked for more
+                let len = len.unwrap_or_else(|| {
+                    (buffer.len() as u32).saturating_sub(start).min(0x100)
+                });
+                buffer.view(start, len)
+            }
+            ["fill", start, end, value] => {
+                let value = u8::try_from(parse_number(value)?)?;
+                buffer.fill(parse_number(start)?, parse_number(end)?, value)?;
+                Ok("Filled".to_string())
+            }
+            ["copy", start, end, dest] => {
+                buffer.copy(
+                    parse_number(start)?,
+                    parse_number(end)?,
+                    parse_number(dest)?,
+                )?;
+                Ok("Copied".to_string())
+            }
+            ["move", start, end, dest] => {
+                buffer.move_range(
+                    parse_number(start)?,
+                    parse_number(end)?,
+                    parse_number(dest)?,
+                )?;
+                Ok("Moved".to_string())
+            }
+            ["set", address, bytes @ ..] => {
+                let bytes = buffer::parse_pattern(&bytes.join(" "))?;
+                buffer.set(parse_number(address)?, &bytes)?;
+                Ok(format!("Set {} bytes", bytes.len()))
+            }
+            ["search", pattern @ ..] => {
+                let pattern = buffer::parse_pattern(&pattern.join(" "))?;
+                let found = buffer.search(&pattern);
+                let mut out = format!("{} matches", found.len());
+                for address in found.iter().take(16) {
+                    out.push_str(&format!("\n{:#06x}", address));
+                }
+                if found.len() > 16 {
+                    out.push_str("\n...");
+                }
+                Ok(out)
+            }
+            ["invert"] if !buffer.is_empty() => {
+                buffer.invert(0, buffer.len() as u32 - 1)?;
+                Ok("Inverted".to_string())
+            }
+            ["invert", start, end] => {
+                buffer.invert(parse_number(start)?, parse_number(end)?)?;
+                Ok("Inverted".to_string())
+            }
+            ["split", ways, path] => {
+                write_lanes(path, buffer.data(), parse_number(ways)? as usize)
+            }
+            ["merge", paths @ ..] if !paths.is_empty() => {
+                buffer.replace(read_lanes(paths)?);
+                Ok(format!("Merged {} bytes", buffer.len()))
+            }
+            ["lane", ways, lane] => {
+                buffer.keep_lane(
+                    parse_number(ways)? as usize,
+                    parse_number(lane)? as usize,
+                )?;
+                Ok(format!("Kept {} bytes", buffer.len()))
+            }
+            ["swap"] => {
+                buffer.swap16()?;
+                Ok("Swapped".to_string())
+            }
+            ["undo"] => {
+                buffer.undo()?;
+                Ok(format!("Undone, {} bytes in the buffer", buffer.len()))
+            }
+            ["read"] => {
+                buffer.replace(programmer.read()?);
+                Ok(format!("Read {} bytes into the buffer", buffer.len()))
+            }
+            ["program"] => {
+                let differences = programmer.program(buffer.data())?;
+                if differences.is_empty() {
+                    Ok(format!(
+                        "Programmed and verified {} bytes",
+                        buffer.len()
+                    ))
+                } else {
+                    Ok(report("Programmed, but verify failed", &differences))
+                }
+            }
+            ["verify"] => {
+                let differences = programmer.verify(buffer.data())?;
+                if differences.is_empty() {
+                    Ok(format!("{} bytes match the buffer", buffer.len()))
+                } else {
+                    Ok(report("Verify failed", &differences))
+                }
+            }
+            _ => Err(anyhow!(BUFFER_USAGE)),
+        },
+        Reg => {
+            let (bus, addr) = match args.first() {
+                Some(spec) => parse_register(spec)?,
+                None => {
+                    return Ok(register::REGISTERS
+                        .iter()
+                        .map(|r| {
+                            format!(
+                                "{:<4} {:02x}:{:02x} {}",
+                                r.name, r.bus, r.addr, r.description
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n"))
+                }
+            };
+            // Register values are always hex, like the output
+            let mask = |s: &str| -> Result<u8> {
+                Ok(u8::from_str_radix(s.trim_start_matches("0x"), 16)?)
+            };
+            let op = |op: &str, m: &str| -> Result<BitOp> {
+                let m = mask(m)?;
+                match op {
+                    "set" => Ok(BitOp::Set(m)),
+                    "clear" => Ok(BitOp::Clear(m)),
+                    "toggle" => Ok(BitOp::Toggle(m)),
+                    _ => Err(anyhow!("Unknown bit operation: {}", op)),
+                }
+            };
+            let value = match args[1..] {
+                [] => register::read_register(programmer.handle(), bus, addr)?,
+                [value] => {
+                    let value = mask(value)?;
+                    register::write_register(
+                        programmer.handle(),
+                        bus,
+                        addr,
+                        value,
+                    )?;
+                    value
+                }
+                [o, m] => register::modify_register(
+                    programmer.handle(),
+                    bus,
+                    addr,
+                    op(o, m)?,
+                )?,
+                _ => {
+                    return Err(anyhow!(
+                        "Usage: reg [<name|bus:addr> [value|set|clear|toggle \
+                         <mask>]]"
+                    ))
+                }
+            };
+            Ok(format!(
+                "{:02x}:{:02x} = {:02x} ({:08b})",
+                bus, addr, value, value
+            ))
+        }
+        Dump => match args {
+            ["--passes", passes, path] => {
+                let passes = parse_number(passes)? as usize;
+                let stability = programmer.read_passes(passes)?;
+                std::fs::write(path, &stability.image)?;
+                Ok(format!(
+                    "Wrote {} voted bytes to {}\n{}",
+                    stability.image.len(),
+                    path,
+                    stability
+                ))
+            }
+            [path] => {
+                let chip = programmer.chip()?;
+                if let ChipKind::Pic(_) = chip.kind {
+                    let image = programmer.read_pic()?;
+                    std::fs::write(path, ihex::write(&image.to_hex()))?;
+                    return Ok(format!(
+                        "Wrote {} to {} as hex",
+                        chip.name, path
+                    ));
+                }
+                if let ChipKind::Gal(g) = chip.kind {
+                    let mut jedec = programmer.read_gal()?;
+                    jedec.notes.push(format!("{} read by gq4x4", chip.name));
+                    std::fs::write(path, jedec.to_string())?;
+                    let size = usize::from(g.rows) * usize::from(g.row_bits);
+                    let array = &jedec.fuses[..size];
+                    let uniform = array.iter().all(|&f| f == array[0]);
+                    return Ok(if uniform {
+                        format!(
+                            "Wrote {} to {}, but the array is uniform: \
+                             the part is blank or its security fuse is set",
+                            chip.name, path
+                        )
+                    } else {
+                        format!("Wrote {} to {} as JEDEC", chip.name, path)
+                    });
+                }
+                let image = programmer.read()?;
+                std::fs::write(path, &image)?;
+                Ok(format!(
+                    "Wrote {} bytes of {} to {}",
+                    image.len(),
+                    chip.name,
+                    path
+                ))
+            }
+            _ => Err(anyhow!("Usage: dump [--passes N] <file>")),
+        },
+        SelfTest => Ok(selftest::selftest(programmer.handle())?.to_string()),
+        Descriptors => {
+            let descriptors =
+                gq4x4::descriptor::decode(gq4x4::FIRMWARE_DESCRIPTORS)?;
+            let mismatches =
+                gq4x4::descriptor::check(programmer.handle(), &descriptors)?;
+            let mut out = descriptors.to_string();
+            if mismatches.is_empty() {
+                out.push_str("Device matches the firmware descriptors");
+            } else {
+                for m in mismatches {
+                    out.push_str(&format!("Mismatch: {}\n", m));
+                }
+            }
+            Ok(out)
+        }
+        Program => {
+            let (path, disable_sdp) = match args {
+                [path] => (path, false),
+                ["--disable-sdp", path] => (path, true),
+                _ => {
+                    return Err(anyhow!(
+                        "Usage: program [--disable-sdp] <file>"
+                    ))
+                }
+            };
+            if let Some(image) = pic_image(programmer, path)? {
+                let differences = programmer.program_pic(&image)?;
+                return if differences.is_empty() {
+                    Ok(format!("Programmed and verified {}", path))
+                } else {
+                    Ok(report("Programmed, but verify failed", &differences))
+                };
+            }
+            if let Some(jedec) = gal_jedec(programmer, path)? {
+                let fuses = programmer.program_gal(&jedec)?;
+                return if fuses.is_empty() {
+                    Ok(format!("Programmed and verified {}", path))
+                } else {
+                    Ok(fuse_report("Programmed, but verify failed", &fuses))
+                };
+            }
+            let image = std::fs::read(path)?;
+            let differences = if disable_sdp {
+                programmer.disable_sdp_and_program(&image)?
+            } else {
+                programmer.program(&image)?
+            };
+            if differences.is_empty() {
+                Ok(format!("Programmed and verified {} bytes", image.len()))
+            } else {
+                Ok(report("Programmed, but verify failed", &differences))
+            }
+        }
+        Sdp => match args {
+            ["on"] => {
+                programmer.enable_sdp()?;
+                Ok("Software data protection on".to_string())
+            }
+            ["off"] => {
+                programmer.disable_sdp()?;
+                Ok("Software data protection off".to_string())
+            }
+            _ => Err(anyhow!("Usage: sdp <on|off>")),
+        },
+        Verify => match args {
+            ["--margins", levels @ .., path] => {
+                let levels = match levels {
+                    [] => None,
+                    [low, high] => Some((
+                        voltage::parse_vcc(low)?,
+                        voltage::parse_vcc(high)?,
+                    )),
+                    _ => return Err(anyhow!(VERIFY_USAGE)),
+                };
+                let image = std::fs::read(path)?;
+                Ok(programmer.verify_margins(&image, levels)?.to_string())
+            }
+            [path] => {
+                if let Some(image) = pic_image(programmer, path)? {
+                    let differences = programmer.verify_pic(&image)?;
+                    return if differences.is_empty() {
+                        Ok(format!(
+                            "{} matches {}",
+                            programmer.chip()?.name,
+                            path
+                        ))
+                    } else {
+                        Ok(report("Verify failed", &differences))
+                    };
+                }
+                if let Some(jedec) = gal_jedec(programmer, path)? {
+                    let fuses = programmer.verify_gal(&jedec)?;
+                    return if fuses.is_empty() {
+                        Ok(format!(
+                            "{} matches {}",
+                            programmer.chip()?.name,
+                            path
+                        ))
+                    } else {
+                        Ok(fuse_report("Verify failed", &fuses))
+                    };
+                }
+                let image = std::fs::read(path)?;
+                let differences = programmer.verify(&image)?;
+                if differences.is_empty() {
+                    Ok(format!("{} bytes match {}", image.len(), path))
+                } else {
+                    Ok(report("Verify failed", &differences))
+                }
+            }
+            _ => Err(anyhow!(VERIFY_USAGE)),
+        },
+        Blank => {
+            let differences = programmer.blank_check()?;
+            if differences.is_empty() {
+                Ok(format!("{} is blank", programmer.chip()?.name))
+            } else {
+                Ok(report("Not blank", &differences))
+            }
+        }
+        Erase => match args {
+            [] => {
+                programmer.erase()?;
+                Ok(format!("Erased {}", programmer.chip()?.name))
+            }
+            [unit, addr] => {
+                let addr = parse_number(addr)?;
+                let (start, size) = match *unit {
+                    "sector" => programmer.erase_sector(addr)?,
+                    "block" => programmer.erase_block(addr)?,
+                    _ => return Err(anyhow!("Unknown erase unit: {}", unit)),
+                };
+                Ok(format!("Erased {:#x}-{:#x}", start, start + size - 1))
+            }
+            _ => Err(anyhow!("Usage: erase [sector|block <addr>]")),
+        },
+        Identify => match args {
+            [] => {
+                let signature = programmer.identify()?;
+                match programmer.chip()?.signature() {
+                    Some(expected) if expected != signature => Ok(format!(
+                        "{}, expected {}{}",
+                        signature,
+                        expected,
+                        suggest(&chip::lookup(signature))
+                    )),
+                    _ => Ok(signature.to_string()),
+                }
+            }
+            [pins] => {
+                let pins = u8::try_from(parse_number(pins)?)?;
+                let (signature, parts) = match programmer.detect(pins, false) {
+                    Ok(found) => found,
+                    Err(e) if programmer::needs_eprom_confirmation(pins) => {
+                        println!("{:#}", e);
+                        println!(
+                            "An EPROM's signature needs 12.5 V on pin 24 (A9), \
+                             which can damage anything else."
+                        );
+                        let prompt = format!(
+                            "Type `yes` if this is a {} pin EPROM: ",
+                            pins
+                        );
+                        if !confirm(&prompt)? {
+                            return Ok("Not identified".to_string());
+                        }
+                        programmer.detect(pins, true)?
+                    }
+                    Err(e) => return Err(e),
+                };
+                match parts[..] {
+                    [chip] if programmer.chip().is_err() => {
+                        programmer.select(chip)?;
+                        Ok(format!("{}\nSelected {}", signature, chip.name))
+                    }
+                    _ => Ok(format!("{}{}", signature, suggest(&parts))),
+                }
+            }
+            _ => Err(anyhow!("Usage: identify [pins]")),
+        },
+        Status => Ok(programmer.spi_status()?.to_string()),
+        Protect => match args {
+            [bits] => {
+                let status = programmer
+                    .set_block_protect(u8::try_from(parse_number(bits)?)?)?;
+                Ok(status.to_string())
+            }
+            _ => Err(anyhow!("Usage: protect <0-7>")),
+        },
+        Sfdp => match args {
+            [] => {
+                let sfdp = programmer.sfdp()?;
+                Ok(format!("{}\n{}", sfdp, read_time(sfdp.size)))
+            }
+            ["select", level] => {
+                let (chip, sfdp) =
+                    programmer.select_discovered(voltage::parse_vcc(level)?)?;
+                Ok(format!(
+                    "{}\nSelected {}\n{}",
+                    sfdp,
+                    chip.name,
+                    read_time(sfdp.size)
+                ))
+            }
+            _ => Err(anyhow!("Usage: sfdp [select <vcc>]")),
+        },
+        Pic => match args {
+            ["detect", pins] => {
+                let pins = u8::try_from(parse_number(pins)?)?;
+                let (chip, revision) = programmer.detect_pic(pins)?;
+                Ok(format!("Selected {} (revision {})", chip.name, revision))
+            }
+            ["config"] => {
+                let image = programmer.read_pic()?;
+                let ids = image
+                    .ids
+                    .iter()
+                    .map(|id| format!("{:04x}", id))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                Ok(format!("IDs: {}\nConfig: {:04x}", ids, image.config))
+            }
+            _ => Err(anyhow!("Usage: pic <detect <8|18>|config>")),
+        },
+        Avr => match args {
+            ["detect", pins] => {
+                let pins = u8::try_from(parse_number(pins)?)?;
+                let chip = programmer.detect_avr(pins)?;
+                Ok(format!("Selected {}", chip.name))
+            }
+            ["eeprom", "dump", path] => {
+                let image = programmer.read_eeprom()?;
+                std::fs::write(path, &image)?;
+                Ok(format!("Wrote {} bytes of EEPROM to {}", image.len(), path))
+            }
+            ["eeprom", "program", path] => {
+                let image = std::fs::read(path)?;
+                let differences = programmer.program_eeprom(&image)?;
+                if differences.is_empty() {
+                    Ok(format!("Programmed and verified {} bytes", image.len()))
+                } else {
+                    Ok(report("Programmed, but verify failed", &differences))
+                }
+            }
+            _ => Err(anyhow!(
+                "Usage: avr <detect <8|20|28>|eeprom <dump|program> <file>>"
+            )),
+        },
+        Fuses => {
+            let chip = programmer.chip()?;
+            let avr = match chip.kind {
+                ChipKind::Avr(a) => a,
+                _ => return Err(anyhow!("{} has no fuses", chip.name)),
+            };
+            match args {
+                [] => Ok(programmer.read_fuses()?.decode(avr)),
+                ["write", fuse, value] => {
+                    let fuse = avr::parse_fuse(fuse)?;
+                    let value = u8::try_from(parse_number(value)?)?;
+                    let current = programmer.read_fuses()?;
+                    if let Some(current) = current.get(fuse) {
+                        println!("Now: {}", avr::decode(avr, fuse, current));
+                    }
+                    println!("New: {}", avr::decode(avr, fuse, value));
+                    for warning in avr::warnings(avr, fuse, value) {
+                        println!("Warning: {}", warning);
+                    }
+                    if !confirm("Type `yes` to write the fuse byte: ")? {
+                        return Ok("Not written".to_string());
+                    }
+                    let read = programmer.write_fuse(fuse, value)?;
+                    if read == value {
+                        Ok(format!("Wrote {} fuse {:02x}", fuse, value))
+                    } else {
+                        Err(anyhow!(
+                            "Wrote {:02x}, read back {:02x}",
+                            value,
+                            read
+                        ))
+                    }
+                }
+                _ => Err(anyhow!(
+                    "Usage: fuses [write <low|high|ext|lock> <value>]"
+                )),
+            }
         }
         Quit => panic!("Quit command shouldn't be passed to run_command"),
     }
@@ -88,19 +770,255 @@ enum Command {
     FirmwareVersion,
     Read,
     PrintDetails,
+    Descriptors,
+    Ram,
+    SelectChip,
+    SetVcc,
+    SetVpp,
+    Contact,
+    SelfTest,
+    Reg,
+    Dump,
+    Program,
+    Sdp,
+    Verify,
+    Blank,
+    Erase,
+    Identify,
+    Status,
+    Protect,
+    Sfdp,
+    Pic,
+    Avr,
+    Fuses,
+    Test,
+    IdentifyLogic,
+    Buffer,
     Quit,
 }
 
-static NAME_TO_COMMAND: &[(&'static str, Command)] = &[
-    (&"details", Command::PrintDetails),
-    (&"read", Command::Read),
-    (&"quit", Command::Quit),
-    (&"firmware", Command::FirmwareVersion),
-    (&"serial", Command::SerialNumber),
-    (&"poke", Command::Poke),
-    (&"peek", Command::Peek),
+static NAME_TO_COMMAND: &[(&str, Command)] = &[
+    ("details", Command::PrintDetails),
+    ("descriptors", Command::Descriptors),
+    ("ram", Command::Ram),
+    ("chip", Command::SelectChip),
+    ("vcc", Command::SetVcc),
+    ("vpp", Command::SetVpp),
+    ("contact", Command::Contact),
+    ("selftest", Command::SelfTest),
+    ("reg", Command::Reg),
+    ("dump", Command::Dump),
+    ("program", Command::Program),
+    ("sdp", Command::Sdp),
+    ("verify", Command::Verify),
+    ("blank", Command::Blank),
+    ("erase", Command::Erase),
+    ("identify", Command::Identify),
+    ("status", Command::Status),
+    ("protect", Command::Protect),
+    ("sfdp", Command::Sfdp),
+    ("pic", Command::Pic),
+    ("avr", Command::Avr),
+    ("fuses", Command::Fuses),
+    ("test", Command::Test),
+    ("identify-logic", Command::IdentifyLogic),
+    ("buffer", Command::Buffer),
+    ("read", Command::Read),
+    ("quit", Command::Quit),
+    ("firmware", Command::FirmwareVersion),
+    ("serial", Command::SerialNumber),
+    ("poke", Command::Poke),
+    ("peek", Command::Peek),
 ];
 
+const BUFFER_USAGE: &str = "Usage: buffer [new <size> | load <file> | \
+     save <file> | view [start [len]] | fill <start> <end> <byte> | \
+     copy <start> <end> <dest> | move <start> <end> <dest> | \
+     set <addr> <bytes> | search <hex|\"text\"> | invert [start end] | \
+     split <2|4> <file> | merge <files> | lane <2|4> <lane> | swap | \
+     undo | read | program | verify]";
+const OFFLINE_COMMANDS: &[&str] = &["split", "merge", "swap"];
+const OFFLINE_USAGE: &str = "Usage: gq4x4 split --ways <2|4> <file> | \
+     merge <out> <lane files> | swap <file> <out>";
+const VERIFY_USAGE: &str = "Usage: verify [--margins [low high]] <file>";
+
+// A one line bar on stderr, redrawn when the percentage changes
+fn progress_bar() -> Observer {
+    let mut last = None;
+    Box::new(move |p: &Progress| {
+        let percent = p.done * 100 / p.total.max(1);
+        if last == Some((p.phase, percent)) {
+            return;
+        }
+        last = Some((p.phase, percent));
+
+        let eta = p
+            .eta()
+            .map(|d| {
+                format!(" ETA {}:{:02}", d.as_secs() / 60, d.as_secs() % 60)
+            })
+            .unwrap_or_default();
+        eprint!(
+            "\r{:<7} [{:<20}] {:>3}% {:#06x}{}   ",
+            p.phase,
+            "#".repeat(percent as usize / 5),
+            percent,
+            p.address,
+            eta
+        );
+        if p.done >= p.total {
+            eprintln!();
+        }
+    })
+}
+
+// A PIC's hex file as an image, or `None` if the selected chip isn't a PIC
+fn pic_image<T: UsbContext>(
+    programmer: &Programmer<T>,
+    path: &str,
+) -> Result<Option<PicImage>> {
+    let chip = programmer.chip()?;
+    match chip.kind {
+        ChipKind::Pic(p) => {
+            let bytes = ihex::parse(&std::fs::read_to_string(path)?)?;
+            Ok(Some(PicImage::from_hex(chip, p, &bytes)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+// The parts a signature points at, as a sentence to tack on
+fn suggest(parts: &[&chip::Chip]) -> String {
+    let names: Vec<&str> = parts.iter().map(|c| c.name).collect();
+    match names[..] {
+        [] => ", not in the database".to_string(),
+        [name] => format!(", looks like {}", name),
+        _ => format!(", could be {}", names.join(" or ")),
+    }
+}
+
+// Asks on stdin; only `yes` counts
+fn confirm(prompt: &str) -> Result<bool> {
+    print!("{}", prompt);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim() == "yes")
+}
+
+// A GAL's .jed file, or `None` if the selected chip isn't a GAL
+fn gal_jedec<T: UsbContext>(
+    programmer: &Programmer<T>,
+    path: &str,
+) -> Result<Option<Jedec>> {
+    match programmer.chip()?.kind {
+        ChipKind::Gal(_) => {
+            Ok(Some(jedec::parse(&std::fs::read_to_string(path)?)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+// The `OFFLINE_COMMANDS`, run from the command line without a programmer
+fn run_offline(args: &[&str]) -> Result<String> {
+    match args {
+        ["split", "--ways", ways, path] => write_lanes(
+            path,
+            &std::fs::read(path)?,
+            parse_number(ways)? as usize,
+        ),
+        ["merge", out, lanes @ ..] if !lanes.is_empty() => {
+            let image = read_lanes(lanes)?;
+            std::fs::write(out, &image)?;
+            Ok(format!("Wrote {} bytes to {}", image.len(), out))
+        }
+        ["swap", path, out] => {
+            let mut image = std::fs::read(path)?;
+            buffer::swap16(&mut image)?;
+            std::fs::write(out, &image)?;
+            Ok(format!("Wrote {} bytes to {}", image.len(), out))
+        }
+        _ => Err(anyhow!(OFFLINE_USAGE)),
+    }
+}
+
+// Writes each byte lane of `image` next to `path`
+fn write_lanes(path: &str, image: &[u8], ways: usize) -> Result<String> {
+    let lanes = buffer::split(image, ways)?;
+    let paths = buffer::lane_paths(std::path::Path::new(path), ways);
+    let mut out = format!("{} bytes split {} ways", image.len(), ways);
+    for (lane, path) in lanes.iter().zip(&paths) {
+        std::fs::write(path, lane)?;
+        out.push_str(&format!("\n{}", path.display()));
+    }
+    Ok(out)
+}
+
+// Interleaves lane files, lowest byte first
+fn read_lanes(paths: &[&str]) -> Result<Vec<u8>> {
+    let lanes = paths
+        .iter()
+        .map(std::fs::read)
+        .collect::<std::io::Result<Vec<_>>>()?;
+    buffer::merge(&lanes)
+}
+
+// Bit-banged SPI is slow enough to be worth warning about up front
+fn read_time(size: u64) -> String {
+    let time = spiflash::read_time(size).as_secs();
+    format!(
+        "SPI is bit-banged, about 7 USB transfers a bit: reading all {} \
+         bytes takes roughly {}h{:02}m",
+        size,
+        time / 3600,
+        time / 60 % 60
+    )
+}
+
+// Shows the first few differences, there can be thousands
+fn report(heading: &str, differences: &[Difference]) -> String {
+    let mut out = format!("{}: {} bytes differ", heading, differences.len());
+    for d in differences.iter().take(16) {
+        out.push_str(&format!("\n{}", d));
+    }
+    if differences.len() > 16 {
+        out.push_str("\n...");
+    }
+    out
+}
+
+fn fuse_report(heading: &str, fuses: &[u32]) -> String {
+    let mut out = format!("{}: {} fuses differ", heading, fuses.len());
+    for fuse in fuses.iter().take(16) {
+        out.push_str(&format!("\nfuse {}", fuse));
+    }
+    if fuses.len() > 16 {
+        out.push_str("\n...");
+    }
+    out
+}
+
+// Accepts decimal or 0x prefixed hex
+fn parse_number(s: &str) -> Result<u32> {
+    Ok(match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16)?,
+        None => s.parse()?,
+    })
+}
+
+// A register name from the table or bus:addr, both in hex
+fn parse_register(s: &str) -> Result<(u8, u8)> {
+    if let Ok(r) = register::find_register(s) {
+        return Ok((r.bus, r.addr));
+    }
+    match s.split(':').collect::<Vec<_>>()[..] {
+        [bus, addr] => {
+            Ok((u8::from_str_radix(bus, 16)?, u8::from_str_radix(addr, 16)?))
+        }
+        _ => Err(anyhow!("Expected a register name or bus:addr, got {}", s)),
+    }
+}
+
 #[derive(Helper, Hinter, Highlighter, Validator)]
 struct ReadlineHelper;
 
@@ -127,6 +1045,8 @@ impl Completer for ReadlineHelper {
     }
 }
 
+// Only read through the Debug output
+#[allow(dead_code)]
 #[derive(Debug)]
 struct DeviceDetails {
     manufacturer: Option<String>,
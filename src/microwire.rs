@@ -0,0 +1,222 @@
+use crate::bitbang::BitBang;
+use crate::chip::{Chip, Microwire};
+use crate::pins::PinRole;
+use crate::progress::Tracker;
+use crate::voltage;
+use anyhow::{anyhow, bail, Result};
+use rusb::{DeviceHandle, UsbContext};
+use std::time::{Duration, Instant};
+
+// Every command is a start bit, a two bit opcode and an address. The 00
+// opcode takes its operation from the top two address bits.
+const READ: u32 = 0b110;
+const WRITE: u32 = 0b101;
+const SPECIAL: u32 = 0b100;
+const EWEN: u32 = 0b11;
+const ERAL: u32 = 0b10;
+const EWDS: u32 = 0b00;
+
+// Bytes per tracker update when reading
+const READ_CHUNK: usize = 64;
+
+// tWC is 5-10 ms, ERAL up to 15 ms. This allows for polling over USB.
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The select, clock and data pins of a 93xx part. Select is active high,
+/// data is latched on the rising clock edge and DO changes after it.
+struct Bus<'a, T: UsbContext> {
+    bus: BitBang<'a, T>,
+    part: &'a Microwire,
+    select: u8,
+    clock: u8,
+    di: u8,
+    dout: u8,
+}
+
+impl<'a, T: UsbContext> Bus<'a, T> {
+    fn select(&mut self, active: bool) -> Result<()> {
+        self.bus
+            .set_all(&[(self.clock, false), (self.select, active)])
+    }
+
+    // Clocks out the low `count` bits of `bits`, MSB first
+    fn send(&mut self, bits: u32, count: u8) -> Result<()> {
+        for bit in (0..count).rev() {
+            self.bus.set_all(&[
+                (self.clock, false),
+                (self.di, bits & (1 << bit) > 0),
+            ])?;
+            self.bus.set(self.clock, true)?;
+        }
+        self.bus.set(self.clock, false)
+    }
+
+    fn receive(&mut self, count: u8) -> Result<u32> {
+        let mut bits = 0;
+        for _ in 0..count {
+            self.bus.set(self.clock, true)?;
+            bits = bits << 1 | u32::from(self.bus.get(self.dout)?);
+            self.bus.set(self.clock, false)?;
+        }
+        Ok(bits)
+    }
+
+    // A whole instruction with the chip selected: start bit, opcode and
+    // address, then `data` if there is any
+    fn command(
+        &mut self,
+        opcode: u32,
+        addr: u32,
+        data: Option<u32>,
+    ) -> Result<()> {
+        let address_bits = self.part.address_bits;
+        self.select(true)?;
+        self.send(opcode << address_bits | addr, 3 + address_bits)?;
+        if let Some(data) = data {
+            self.send(data, self.part.word_bits)?;
+        }
+        Ok(())
+    }
+
+    // EWEN, ERAL and EWDS only use the top two address bits
+    fn special(&mut self, operation: u32) -> Result<()> {
+        let addr = operation << (self.part.address_bits - 2);
+        self.command(SPECIAL, addr, None)?;
+        self.select(false)
+    }
+
+    // Deselecting starts the write cycle. Reselected, DO is low while it's
+    // busy and high once it's done.
+    fn wait(&mut self) -> Result<()> {
+        self.select(false)?;
+        self.select(true)?;
+        let start = Instant::now();
+        while !self.bus.get(self.dout)? {
+            if start.elapsed() > WRITE_TIMEOUT {
+                self.select(false)?;
+                bail!("Write cycle didn't finish in {:?}", WRITE_TIMEOUT);
+            }
+        }
+        self.select(false)
+    }
+}
+
+/// Reads the first `len` bytes with one sequential read. 16 bit words are
+/// stored high byte first, the order they're clocked out in.
+pub fn read<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    part: &Microwire,
+    len: usize,
+    tracker: &mut Tracker,
+) -> Result<Vec<u8>> {
+    powered(handle, chip, part, |bus| {
+        let word_bytes = usize::from(part.word_bits / 8);
+        let mut image = Vec::with_capacity(len);
+        bus.command(READ, 0, None)?;
+        while image.len() < len {
+            let word = bus.receive(part.word_bits)?.to_be_bytes();
+            image.extend_from_slice(&word[4 - word_bytes..]);
+            if image.len() % READ_CHUNK == 0 || image.len() >= len {
+                let done = image.len().min(len);
+                tracker.update(done as u64, done as u32 - 1)?;
+            }
+        }
+        bus.select(false)?;
+        image.truncate(len);
+        Ok(image)
+    })
+}
+
+/// Writes the words of `image` that differ from `current`, one write
+/// cycle each, between EWEN and EWDS. VCC is off when this returns.
+pub fn program<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    part: &Microwire,
+    image: &[u8],
+    current: &[u8],
+    tracker: &mut Tracker,
+) -> Result<()> {
+    let word_bytes = usize::from(part.word_bits / 8);
+    if !image.len().is_multiple_of(word_bytes) {
+        bail!("{} takes whole {} bit words", chip.name, part.word_bits);
+    }
+    powered(handle, chip, part, |bus| {
+        bus.special(EWEN)?;
+        let result = (|| {
+            for (addr, (wanted, had)) in image
+                .chunks(word_bytes)
+                .zip(current.chunks(word_bytes))
+                .enumerate()
+            {
+                if wanted != had {
+                    let word = wanted
+                        .iter()
+                        .fold(0, |word, &byte| word << 8 | u32::from(byte));
+                    bus.command(WRITE, addr as u32, Some(word))?;
+                    bus.wait()?;
+                }
+                let end = (addr + 1) * word_bytes;
+                tracker.update(end as u64, end as u32 - 1)?;
+            }
+            Ok(())
+        })();
+        bus.special(EWDS)?;
+        result
+    })
+}
+
+/// Sets every word to all ones with ERAL.
+pub fn erase_all<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    part: &Microwire,
+) -> Result<()> {
+    powered(handle, chip, part, |bus| {
+        bus.special(EWEN)?;
+        bus.command(SPECIAL, ERAL << (part.address_bits - 2), None)?;
+        let result = bus.wait();
+        bus.special(EWDS)?;
+        result
+    })
+}
+
+// Loads the pins and runs `f` with VCC at the chip's first level, switching
+// it off afterwards whatever happens
+fn powered<'a, T: UsbContext, R>(
+    handle: &'a mut DeviceHandle<T>,
+    chip: &Chip,
+    part: &'a Microwire,
+    f: impl FnOnce(&mut Bus<'a, T>) -> Result<R>,
+) -> Result<R> {
+    let level = *chip
+        .vcc
+        .first()
+        .ok_or_else(|| anyhow!("{} has no supply voltage", chip.name))?;
+    let config = chip.pin_config()?;
+    let pin = |role| {
+        config
+            .pins_with(role)
+            .first()
+            .copied()
+            .ok_or_else(|| anyhow!("No {} pin in the config", role))
+    };
+    let (select, clock) = (pin(PinRole::ChipEnable)?, pin(PinRole::Clock)?);
+    let (di, dout) = (pin(PinRole::SerialIn)?, pin(PinRole::SerialOut)?);
+
+    let mut bus = Bus {
+        bus: BitBang::new(handle, &config)?,
+        part,
+        select,
+        clock,
+        di,
+        dout,
+    };
+    bus.select(false)?;
+    voltage::set_vcc(bus.bus.handle(), chip, level)?;
+    voltage::vcc_on(bus.bus.handle())?;
+    let result = f(&mut bus);
+    voltage::vcc_off(bus.bus.handle())?;
+    result
+}
@@ -0,0 +1,482 @@
+use crate::bitbang::BitBang;
+use crate::chip::{Chip, Pic};
+use crate::pins::{PinConfig, PinRole};
+use crate::progress::Tracker;
+use crate::voltage;
+use anyhow::{anyhow, bail, Result};
+use rusb::{DeviceHandle, UsbContext};
+use std::collections::BTreeMap;
+use std::thread::sleep;
+use std::time::Duration;
+
+// Six bit ICSP commands, the set the 16F84A, 16F62xA and 12F6xx share. The
+// load and read commands are followed by a 16 bit data frame.
+const LOAD_CONFIG: u8 = 0x00;
+const LOAD_PROGRAM: u8 = 0x02;
+const LOAD_DATA: u8 = 0x03;
+const READ_PROGRAM: u8 = 0x04;
+const READ_DATA: u8 = 0x05;
+const INCREMENT: u8 = 0x06;
+const BEGIN_PROGRAMMING: u8 = 0x08;
+const BULK_ERASE_PROGRAM: u8 = 0x09;
+const BULK_ERASE_DATA: u8 = 0x0b;
+
+// Word addresses in configuration memory, which the PC jumps to on
+// LOAD_CONFIG
+const CONFIG_MEMORY: u32 = 0x2000;
+const ID_WORDS: usize = 4;
+const DEVICE_ID: u32 = 0x2006;
+const CONFIG_WORD: u32 = 0x2007;
+// Where MPLAB puts data EEPROM, one byte to a word
+const EEPROM: u32 = 0x2100;
+
+const BLANK_WORD: u16 = 0x3fff;
+const REVISION_BITS: u16 = 0x001f;
+// Band gap calibration in the 12F6xx config word
+const BAND_GAP: u16 = 0x3000;
+
+// Internally timed, the worst of the supported parts with some margin
+const PROGRAM_TIME: Duration = Duration::from_millis(10);
+const ERASE_TIME: Duration = Duration::from_millis(10);
+
+/// Everything a midrange PIC holds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PicImage {
+    pub program: Vec<u16>,
+    pub ids: [u16; ID_WORDS],
+    pub config: u16,
+    pub eeprom: Vec<u8>,
+}
+
+impl PicImage {
+    /// What an erased chip reads as.
+    pub fn blank(chip: &Chip, pic: &Pic) -> Self {
+        PicImage {
+            program: vec![BLANK_WORD; chip.size as usize / 2],
+            ids: [BLANK_WORD; ID_WORDS],
+            config: BLANK_WORD,
+            eeprom: vec![0xff; pic.eeprom_size as usize],
+        }
+    }
+
+    /// Builds an image from a parsed MPLAB hex file. Words are little
+    /// endian at twice their word address; anything the file leaves out
+    /// stays blank.
+    pub fn from_hex(
+        chip: &Chip,
+        pic: &Pic,
+        bytes: &BTreeMap<u32, u8>,
+    ) -> Result<Self> {
+        let mut image = Self::blank(chip, pic);
+        for (&addr, &byte) in bytes {
+            let word = addr / 2;
+            let high = addr % 2 == 1;
+            let set = |w: &mut u16| {
+                *w = if high {
+                    (*w & 0x00ff) | (u16::from(byte) << 8)
+                } else {
+                    (*w & 0xff00) | u16::from(byte)
+                } & BLANK_WORD
+            };
+            match word {
+                w if (w as usize) < image.program.len() => {
+                    set(&mut image.program[w as usize])
+                }
+                w if w >= CONFIG_MEMORY
+                    && w < CONFIG_MEMORY + ID_WORDS as u32 =>
+                {
+                    set(&mut image.ids[(w - CONFIG_MEMORY) as usize])
+                }
+                CONFIG_WORD => set(&mut image.config),
+                w if w >= EEPROM && w - EEPROM < pic.eeprom_size => {
+                    if !high {
+                        image.eeprom[(w - EEPROM) as usize] = byte;
+                    }
+                }
+                _ => bail!(
+                    "Hex file has data at {:#x}, outside {}",
+                    addr,
+                    chip.name
+                ),
+            }
+        }
+        Ok(image)
+    }
+
+    /// The image in MPLAB's hex layout, every location included.
+    pub fn to_hex(&self) -> BTreeMap<u32, u8> {
+        let mut bytes = BTreeMap::new();
+        let mut put = |word: u32, value: u16| {
+            let [low, high] = value.to_le_bytes();
+            bytes.insert(word * 2, low);
+            bytes.insert(word * 2 + 1, high);
+        };
+        for (addr, &word) in self.program.iter().enumerate() {
+            put(addr as u32, word);
+        }
+        for (i, &id) in self.ids.iter().enumerate() {
+            put(CONFIG_MEMORY + i as u32, id);
+        }
+        put(CONFIG_WORD, self.config);
+        for (i, &byte) in self.eeprom.iter().enumerate() {
+            put(EEPROM + i as u32, u16::from(byte));
+        }
+        bytes
+    }
+
+    /// Copies the factory calibration (OSCCAL and the band gap bits) from
+    /// `chip`, for parts that have it. The chip's own values always win
+    /// over whatever an image was built with.
+    pub fn keep_calibration(&mut self, pic: &Pic, chip: &PicImage) {
+        if !pic.calibration {
+            return;
+        }
+        if let (Some(word), Some(&osccal)) =
+            (self.program.last_mut(), chip.program.last())
+        {
+            *word = osccal;
+        }
+        self.config = (self.config & !BAND_GAP) | (chip.config & BAND_GAP);
+    }
+}
+
+/// ICSP clock and data. Commands and data go LSB first: we set PGD with the
+/// clock high and the chip latches it on the falling edge. For reads PGD is
+/// released to the pull-up and the chip drives it after each rising edge.
+struct Icsp<'a, T: UsbContext> {
+    bus: BitBang<'a, T>,
+    driving: PinConfig,
+    released: PinConfig,
+    clock: u8,
+    data: u8,
+}
+
+impl<'a, T: UsbContext> Icsp<'a, T> {
+    fn new(handle: &'a mut DeviceHandle<T>, config: PinConfig) -> Result<Self> {
+        let pin = |role| {
+            config
+                .pins_with(role)
+                .first()
+                .copied()
+                .ok_or_else(|| anyhow!("No {} pin in the config", role))
+        };
+        let (clock, data) = (pin(PinRole::Clock)?, pin(PinRole::SerialData)?);
+        let mut released = config.clone();
+        released.set(data, PinRole::PullUp)?;
+
+        let mut bus = BitBang::new(handle, &config)?;
+        bus.set_all(&[(clock, false), (data, false)])?;
+        Ok(Icsp {
+            bus,
+            driving: config,
+            released,
+            clock,
+            data,
+        })
+    }
+
+    fn send(&mut self, bits: u32, count: u8) -> Result<()> {
+        for bit in 0..count {
+            self.bus.set_all(&[
+                (self.clock, true),
+                (self.data, bits & (1 << bit) > 0),
+            ])?;
+            self.bus.set(self.clock, false)?;
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, command: u8) -> Result<()> {
+        self.send(u32::from(command), 6)
+    }
+
+    // The command, then a frame of a start bit, 14 data bits and a stop bit
+    fn load(&mut self, command: u8, data: u16) -> Result<()> {
+        self.command(command)?;
+        self.send(u32::from(data & BLANK_WORD) << 1, 16)
+    }
+
+    fn read(&mut self, command: u8) -> Result<u16> {
+        self.command(command)?;
+        self.bus.configure(&self.released)?;
+        let mut frame = 0u16;
+        for bit in 0..16 {
+            self.bus.set(self.clock, true)?;
+            if self.bus.get(self.data)? {
+                frame |= 1 << bit;
+            }
+            self.bus.set(self.clock, false)?;
+        }
+        self.bus.set(self.data, false)?;
+        self.bus.configure(&self.driving)?;
+        Ok((frame >> 1) & BLANK_WORD)
+    }
+
+    fn program(&mut self, command: u8, data: u16) -> Result<()> {
+        self.load(command, data)?;
+        self.command(BEGIN_PROGRAMMING)?;
+        sleep(PROGRAM_TIME);
+        Ok(())
+    }
+
+    fn skip(&mut self, words: u32) -> Result<()> {
+        for _ in 0..words {
+            self.command(INCREMENT)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads program memory, data EEPROM, the ID locations and the config
+/// word.
+pub fn read<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    pic: &Pic,
+    tracker: &mut Tracker,
+) -> Result<PicImage> {
+    let mut image = PicImage::blank(chip, pic);
+    let words = image.program.len();
+    image.program = read_words(handle, chip, words, Some(tracker))?;
+
+    image.eeprom = program_mode(handle, chip, |icsp| {
+        (0..pic.eeprom_size)
+            .map(|_| {
+                let byte = icsp.read(READ_DATA)? as u8;
+                icsp.command(INCREMENT)?;
+                Ok(byte)
+            })
+            .collect::<Result<Vec<u8>>>()
+    })?;
+
+    let config = read_config(handle, chip)?;
+    image.ids.copy_from_slice(&config[..ID_WORDS]);
+    image.config = config[(CONFIG_WORD - CONFIG_MEMORY) as usize];
+    Ok(image)
+}
+
+/// Reads the first `len` bytes of program memory, two to a word.
+pub fn read_program<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    len: usize,
+    tracker: &mut Tracker,
+) -> Result<Vec<u8>> {
+    let words = read_words(handle, chip, len.div_ceil(2), Some(tracker))?;
+    let mut bytes: Vec<u8> =
+        words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    bytes.truncate(len);
+    Ok(bytes)
+}
+
+/// The raw device ID word, revision bits included.
+pub fn device_id<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+) -> Result<u16> {
+    let config = read_config(handle, chip)?;
+    Ok(config[(DEVICE_ID - CONFIG_MEMORY) as usize])
+}
+
+/// The device ID without its revision bits, and the revision.
+pub fn split_device_id(id: u16) -> (u16, u8) {
+    (id & !REVISION_BITS, (id & REVISION_BITS) as u8)
+}
+
+/// Bulk erases program memory, the config word and IDs, and data EEPROM.
+/// Code protection is cleared along with them. Factory calibration is read
+/// first and written back.
+pub fn erase<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    pic: &Pic,
+) -> Result<()> {
+    let saved = calibration(handle, chip, pic)?;
+    bulk_erase(handle, chip, pic)?;
+    if let Some(saved) = saved {
+        let mut blank = PicImage::blank(chip, pic);
+        blank.keep_calibration(pic, &saved);
+        write_calibration(handle, chip, &blank)?;
+    }
+    Ok(())
+}
+
+/// Erases the chip and programs `image` into it. Blank words and bytes are
+/// skipped. Factory calibration is kept from the chip, not the image.
+pub fn program<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    pic: &Pic,
+    image: &PicImage,
+    tracker: &mut Tracker,
+) -> Result<()> {
+    let mut image = image.clone();
+    if let Some(saved) = calibration(handle, chip, pic)? {
+        image.keep_calibration(pic, &saved);
+    }
+    bulk_erase(handle, chip, pic)?;
+
+    let words = image.program.len();
+    program_mode(handle, chip, |icsp| {
+        for (addr, &word) in image.program.iter().enumerate() {
+            if word != BLANK_WORD {
+                icsp.program(LOAD_PROGRAM, word)?;
+            }
+            icsp.command(INCREMENT)?;
+            tracker.update(addr as u64 + 1, addr as u32)?;
+        }
+        Ok(())
+    })?;
+
+    program_mode(handle, chip, |icsp| {
+        for (i, &byte) in image.eeprom.iter().enumerate() {
+            if byte != 0xff {
+                icsp.program(LOAD_DATA, u16::from(byte))?;
+            }
+            icsp.command(INCREMENT)?;
+            let done = words + i + 1;
+            tracker.update(done as u64, (EEPROM as usize + i) as u32)?;
+        }
+        Ok(())
+    })?;
+
+    program_mode(handle, chip, |icsp| {
+        icsp.program(LOAD_CONFIG, image.ids[0])?;
+        for &id in &image.ids[1..] {
+            icsp.command(INCREMENT)?;
+            icsp.program(LOAD_PROGRAM, id)?;
+        }
+        icsp.skip(CONFIG_WORD - CONFIG_MEMORY - (ID_WORDS as u32 - 1))?;
+        icsp.program(LOAD_PROGRAM, image.config)
+    })
+}
+
+// The chip as read, for parts with calibration to keep
+fn calibration<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    pic: &Pic,
+) -> Result<Option<PicImage>> {
+    if !pic.calibration {
+        return Ok(None);
+    }
+    let mut saved = PicImage::blank(chip, pic);
+    let words = saved.program.len();
+    saved.program = read_words(handle, chip, words, None)?;
+    saved.config =
+        read_config(handle, chip)?[(CONFIG_WORD - CONFIG_MEMORY) as usize];
+    if saved.program[words - 1] == BLANK_WORD {
+        bail!("{} has lost its OSCCAL word, not erasing", chip.name);
+    }
+    Ok(Some(saved))
+}
+
+// OSCCAL and the config word of `image`, into an erased chip
+fn write_calibration<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    image: &PicImage,
+) -> Result<()> {
+    let last = image.program.len() as u32 - 1;
+    program_mode(handle, chip, |icsp| {
+        icsp.skip(last)?;
+        icsp.program(LOAD_PROGRAM, image.program[last as usize])
+    })?;
+    program_mode(handle, chip, |icsp| {
+        icsp.load(LOAD_CONFIG, BLANK_WORD)?;
+        icsp.skip(CONFIG_WORD - CONFIG_MEMORY)?;
+        icsp.program(LOAD_PROGRAM, image.config)
+    })
+}
+
+// With the PC in configuration memory the program memory erase takes the
+// config word and IDs with it, which is what clears code protection
+fn bulk_erase<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    pic: &Pic,
+) -> Result<()> {
+    program_mode(handle, chip, |icsp| {
+        icsp.load(LOAD_CONFIG, BLANK_WORD)?;
+        icsp.load(LOAD_PROGRAM, BLANK_WORD)?;
+        icsp.command(BULK_ERASE_PROGRAM)?;
+        if pic.erase_begin {
+            icsp.command(BEGIN_PROGRAMMING)?;
+        }
+        sleep(ERASE_TIME);
+
+        icsp.load(LOAD_DATA, 0xff)?;
+        icsp.command(BULK_ERASE_DATA)?;
+        if pic.erase_begin {
+            icsp.command(BEGIN_PROGRAMMING)?;
+        }
+        sleep(ERASE_TIME);
+        Ok(())
+    })
+}
+
+fn read_words<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    words: usize,
+    mut tracker: Option<&mut Tracker>,
+) -> Result<Vec<u16>> {
+    program_mode(handle, chip, |icsp| {
+        let mut program = Vec::with_capacity(words);
+        for addr in 0..words {
+            program.push(icsp.read(READ_PROGRAM)?);
+            icsp.command(INCREMENT)?;
+            if let Some(tracker) = tracker.as_mut() {
+                let done = (addr + 1) * 2;
+                tracker.update(done as u64, done as u32 - 1)?;
+            }
+        }
+        Ok(program)
+    })
+}
+
+// The eight words from 2000: IDs, two reserved, device ID, config word
+fn read_config<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+) -> Result<Vec<u16>> {
+    program_mode(handle, chip, |icsp| {
+        icsp.load(LOAD_CONFIG, BLANK_WORD)?;
+        (CONFIG_MEMORY..=CONFIG_WORD)
+            .map(|_| {
+                let word = icsp.read(READ_PROGRAM)?;
+                icsp.command(INCREMENT)?;
+                Ok(word)
+            })
+            .collect()
+    })
+}
+
+// Enters high voltage program mode, runs `f` and leaves it. VPP goes on
+// before VCC, so a part with MCLR disabled and the internal oscillator
+// never gets to run its code. Every entry starts the PC at 0.
+fn program_mode<T: UsbContext, R>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    f: impl FnOnce(&mut Icsp<T>) -> Result<R>,
+) -> Result<R> {
+    let vpp = chip
+        .vpp
+        .ok_or_else(|| anyhow!("{} has no programming voltage", chip.name))?;
+    let vcc = *chip
+        .vcc
+        .first()
+        .ok_or_else(|| anyhow!("{} has no supply voltage", chip.name))?;
+
+    let mut icsp = Icsp::new(handle, chip.pin_config()?)?;
+    voltage::set_vcc(icsp.bus.handle(), chip, vcc)?;
+    voltage::set_vpp(icsp.bus.handle(), chip, vpp)?;
+    voltage::vpp_on(icsp.bus.handle())?;
+    voltage::vcc_on(icsp.bus.handle())?;
+    sleep(Duration::from_millis(1));
+
+    let result = f(&mut icsp);
+    voltage::vpp_off(icsp.bus.handle())?;
+    voltage::vcc_off(icsp.bus.handle())?;
+    result
+}
@@ -0,0 +1,372 @@
+use crate::{peek, poke};
+use anyhow::{bail, Result};
+use hex_literal::hex;
+use rusb::{DeviceHandle, UsbContext};
+use std::fmt;
+
+/// Number of pins on the ZIF socket.
+pub const ZIF_PINS: u8 = 40;
+
+// The 0x56 family, as seen in initialization.pcapng (frames 394-520), looks
+// like a JTAG port on the board's CPLD:
+//
+//   56 00 01 00 00 / 56 00 00 00 00   clock TMS high / low
+//   56 03 00                          shift the instruction register
+//   56 01 00                          shift the data register
+//   56 02 00 <n> 01 <last>            shift n bits, the next write carries
+//                                     them LSB first and the device answers
+//                                     with the bits shifted out
+//
+// All of it is an IDCODE read (IR 06fc/07fc, IDCODE 14051600), bracketed by
+// `0f 01` / `0f 00`, and it's replayed as is by `replay_idcode_scan`. Nothing
+// has been captured of the vendor software setting up the socket for a chip,
+// so there's no pin setup encoding here yet, see `configure_pins`.
+const IDCODE_SCAN: &[(&[u8], &[u8])] = &[
+    (&hex!("0f01"), &[]),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600000000"), &[]),
+    (&hex!("5600000000"), &[]),
+    (&hex!("560300"), &[]),
+    (&hex!("5602000a0100"), &[]),
+    (&hex!("06fc"), &hex!("06fc")),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600000000"), &[]),
+    (&hex!("560300"), &[]),
+    (&hex!("5602000a0100"), &[]),
+    (&hex!("ffff"), &hex!("ffff")),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600000000"), &[]),
+    (&hex!("560300"), &[]),
+    (&hex!("5602000a0100"), &[]),
+    (&hex!("06fc"), &hex!("06fc")),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600000000"), &[]),
+    (&hex!("560300"), &[]),
+    (&hex!("5602000a0100"), &[]),
+    (&hex!("ffff"), &hex!("ffff")),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600000000"), &[]),
+    (&hex!("5600000000"), &[]),
+    (&hex!("560300"), &[]),
+    (&hex!("5602000a0100"), &[]),
+    (&hex!("07fc"), &hex!("07fc")),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600000000"), &[]),
+    (&hex!("560100"), &[]),
+    (&hex!("560200200101"), &[]),
+    (&hex!("ffffffff"), &hex!("14051600")),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600000000"), &[]),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600010000"), &[]),
+    (&hex!("5600000000"), &[]),
+    (&hex!("0f00"), &[]),
+];
+
+/// The CPLD's IDCODE as the vendor software read it.
+pub const IDCODE: [u8; 4] = hex!("14051600");
+
+// From the end of initialization.pcapng: `10 55555555` followed by five `14`
+// commands, each answered with a single byte. We read 0x14 as sensing one
+// bank of eight ZIF pins, five banks covering the socket.
+const DRIVE_PATTERN: u8 = 0x10;
+const SENSE_BANK: u8 = 0x14;
+const BANKS: u8 = ZIF_PINS / 8;
+
+/// What a ZIF pin is connected to. Address, data, the strobes and the
+/// serial clock and input are all driven by the logic level drivers; the
+/// roles only differ in meaning to the code sequencing a chip.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PinRole {
+    Address(u8),
+    Data(u8),
+    Vcc,
+    Vpp,
+    Gnd,
+    ChipEnable,
+    OutputEnable,
+    WriteEnable,
+    /// Serial clock, driven
+    Clock,
+    /// Serial data into the chip, driven
+    SerialIn,
+    /// Serial data out of the chip, sensed
+    SerialOut,
+    /// Bidirectional serial data. Driven; the protocol code releases it to
+    /// the pull-up when the chip answers.
+    SerialData,
+    /// An input of a logic part under test, driven
+    LogicIn,
+    PullUp,
+    HighZ,
+}
+
+impl fmt::Display for PinRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use PinRole::*;
+
+        match self {
+            Address(n) => write!(f, "A{}", n),
+            Data(n) => write!(f, "D{}", n),
+            Vcc => write!(f, "VCC"),
+            Vpp => write!(f, "VPP"),
+            Gnd => write!(f, "GND"),
+            ChipEnable => write!(f, "/CE"),
+            OutputEnable => write!(f, "/OE"),
+            WriteEnable => write!(f, "/WE"),
+            Clock => write!(f, "CLK"),
+            SerialIn => write!(f, "SI"),
+            SerialOut => write!(f, "SO"),
+            SerialData => write!(f, "SDA"),
+            LogicIn => write!(f, "IN"),
+            PullUp => write!(f, "PU"),
+            HighZ => write!(f, "-"),
+        }
+    }
+}
+
+/// Role of every pin on the 40 pin ZIF socket. Pins are numbered 1-40 the
+/// same way as a DIP package, counter-clockwise from the lever end.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PinConfig {
+    roles: [PinRole; ZIF_PINS as usize],
+}
+
+impl Default for PinConfig {
+    fn default() -> Self {
+        PinConfig {
+            roles: [PinRole::HighZ; ZIF_PINS as usize],
+        }
+    }
+}
+
+impl PinConfig {
+    /// Every pin high impedance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds a config for a DIP chip from its pinout, `pinout[0]` being
+    /// chip pin 1.
+    pub fn for_dip(pinout: &[PinRole]) -> Result<Self> {
+        let mut config = Self::new();
+        let pin_count = pinout.len() as u8;
+
+        for (i, role) in pinout.iter().enumerate() {
+            config.set(zif_pin(i as u8 + 1, pin_count)?, *role)?;
+        }
+
+        Ok(config)
+    }
+
+    pub fn set(&mut self, pin: u8, role: PinRole) -> Result<&mut Self> {
+        self.roles[index(pin)?] = role;
+        Ok(self)
+    }
+
+    pub fn role(&self, pin: u8) -> Result<PinRole> {
+        Ok(self.roles[index(pin)?])
+    }
+
+    /// ZIF pins (1 based) with the given role.
+    pub fn pins_with(&self, role: PinRole) -> Vec<u8> {
+        (1..=ZIF_PINS)
+            .filter(|&p| self.roles[p as usize - 1] == role)
+            .collect()
+    }
+}
+
+/// The ZIF pin a DIP chip's pin lands on. Chips sit at the bottom of the
+/// socket (away from the lever), so a 24 pin part has pin 1 in ZIF pin 9.
+pub fn zif_pin(chip_pin: u8, pin_count: u8) -> Result<u8> {
+    if pin_count % 2 == 1 || pin_count == 0 || pin_count > ZIF_PINS {
+        bail!("Can't place a {} pin DIP in the socket", pin_count);
+    }
+    if chip_pin == 0 || chip_pin > pin_count {
+        bail!("Pin {} doesn't exist on a {} pin DIP", chip_pin, pin_count);
+    }
+
+    let side = pin_count / 2;
+    Ok(if chip_pin <= side {
+        chip_pin + ZIF_PINS / 2 - side
+    } else {
+        chip_pin - side + ZIF_PINS / 2
+    })
+}
+
+/// Loads `config` into the pin drivers. Not possible yet: nothing has been
+/// captured of the vendor software setting up the socket, so there's no
+/// encoding to send. Always fails, without sending anything.
+pub fn configure_pins<T: UsbContext>(
+    _handle: &mut DeviceHandle<T>,
+    _config: &PinConfig,
+) -> Result<()> {
+    bail!(
+        "Can't set up the socket: the pin driver commands haven't been \
+         captured yet"
+    )
+}
+
+/// Replays the vendor software's IDCODE read of the pin driver CPLD byte for
+/// byte and checks every answer against the recording, `IDCODE` included.
+pub fn replay_idcode_scan<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+) -> Result<()> {
+    for (i, (command, answer)) in IDCODE_SCAN.iter().enumerate() {
+        poke(handle, command)?;
+        if answer.is_empty() {
+            continue;
+        }
+        let chunk = peek(handle)?;
+        let actual = &chunk.bytes[..chunk.len];
+        if actual != *answer {
+            bail!(
+                "Step {} ({}) answered {}, the recording has {}",
+                i,
+                hex::encode(command),
+                hex::encode(actual),
+                hex::encode(answer)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Logic level seen on each ZIF pin, bit 0 being pin 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PinLevels(pub u64);
+
+impl PinLevels {
+    pub fn is_high(self, pin: u8) -> Result<bool> {
+        Ok(self.0 & (1 << index(pin)?) > 0)
+    }
+
+    pub fn set(&mut self, pin: u8, high: bool) -> Result<()> {
+        let bit = 1 << index(pin)?;
+        if high {
+            self.0 |= bit;
+        } else {
+            self.0 &= !bit;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the level on every ZIF pin, one bank of eight at a time.
+pub fn sense_pins<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+) -> Result<PinLevels> {
+    let mut levels = 0u64;
+
+    for bank in 0..BANKS {
+        poke(handle, &[SENSE_BANK])?;
+        let chunk = peek(handle)?;
+        if chunk.len != 1 {
+            bail!("Expected one byte for bank {}, got {}", bank, chunk.len);
+        }
+        levels |= u64::from(chunk.bytes[0]) << (bank * 8);
+    }
+
+    Ok(PinLevels(levels))
+}
+
+/// Latches a test pattern onto the pin drivers. The vendor software only
+/// ever sends `55555555`.
+pub fn drive_pattern<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    pattern: [u8; 4],
+) -> Result<()> {
+    let mut command = vec![DRIVE_PATTERN];
+    command.extend_from_slice(&pattern);
+    poke(handle, &command)?;
+    Ok(())
+}
+
+/// Drives every pin configured as a logic output to the given level. Only
+/// pins 1-32: `10` carries four bytes in the capture and nothing shows how
+/// pins 33-40 would be reached, so their levels aren't sent.
+pub fn drive_pins<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    levels: PinLevels,
+) -> Result<()> {
+    drive_pattern(handle, (levels.0 as u32).to_le_bytes())
+}
+
+fn index(pin: u8) -> Result<usize> {
+    if pin == 0 || pin > ZIF_PINS {
+        bail!("ZIF pins are numbered 1-{}, got {}", ZIF_PINS, pin);
+    }
+    Ok(pin as usize - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dips_sit_at_the_bottom() {
+        // A 24 pin part has pin 1 in ZIF pin 9 and pin 24 in ZIF pin 32
+        assert_eq!(zif_pin(1, 24).unwrap(), 9);
+        assert_eq!(zif_pin(12, 24).unwrap(), 20);
+        assert_eq!(zif_pin(13, 24).unwrap(), 21);
+        assert_eq!(zif_pin(24, 24).unwrap(), 32);
+        // A 40 pin part fills the socket
+        assert_eq!(zif_pin(1, 40).unwrap(), 1);
+        assert_eq!(zif_pin(40, 40).unwrap(), 40);
+    }
+
+    #[test]
+    fn zif_pin_checks_the_package() {
+        assert!(zif_pin(1, 7).is_err());
+        assert!(zif_pin(1, 42).is_err());
+        assert!(zif_pin(0, 8).is_err());
+        assert!(zif_pin(9, 8).is_err());
+    }
+
+    #[test]
+    fn for_dip_places_pinout() {
+        let config =
+            PinConfig::for_dip(&[PinRole::Gnd, PinRole::Data(0)]).unwrap();
+        assert_eq!(config.pins_with(PinRole::Gnd), [20]);
+        assert_eq!(config.role(21).unwrap(), PinRole::Data(0));
+        assert_eq!(config.role(1).unwrap(), PinRole::HighZ);
+        assert!(config.role(41).is_err());
+    }
+
+    #[test]
+    fn levels_by_pin() {
+        let mut levels = PinLevels(0);
+        levels.set(1, true).unwrap();
+        levels.set(40, true).unwrap();
+        assert_eq!(levels.0, 1 | 1 << 39);
+        assert!(levels.is_high(40).unwrap());
+        levels.set(1, false).unwrap();
+        assert!(!levels.is_high(1).unwrap());
+        assert!(levels.set(0, true).is_err());
+    }
+
+    #[test]
+    fn idcode_scan_ends_with_the_idcode() {
+        let answers: Vec<&[u8]> = IDCODE_SCAN
+            .iter()
+            .map(|(_, answer)| *answer)
+            .filter(|a| !a.is_empty())
+            .collect();
+        assert_eq!(answers.last(), Some(&&IDCODE[..]));
+        assert_eq!(IDCODE_SCAN.first().map(|s| s.0), Some(&[0x0f, 0x01][..]));
+        assert_eq!(IDCODE_SCAN.last().map(|s| s.0), Some(&[0x0f, 0x00][..]));
+    }
+}
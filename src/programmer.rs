@@ -0,0 +1,1241 @@
+use crate::avr::{self, Fuses};
+use crate::chip::{self, Chip, ChipKind, Fuse, Signature, SpiFlash, MICROCHIP};
+use crate::eeprom::{self, WriteMode};
+use crate::gal;
+use crate::jedec::Jedec;
+use crate::pic::{self, PicImage};
+use crate::pins::{configure_pins, PinConfig};
+use crate::progress::{CancelToken, Observer, Phase, Tracker};
+use crate::reader::ChipReader;
+use crate::spiflash::{self, Erase, Sfdp, Status};
+use crate::stability::{self, Stability};
+use crate::voltage::{self, Vcc, Vpp};
+use crate::{eprom, flash, i2ceeprom, microwire};
+use crate::{firmware_version, init_with, InitOptions, BLOCK_SIZE, READ_LIMIT};
+use anyhow::{anyhow, bail, Result};
+use rusb::{DeviceHandle, UsbContext};
+use std::fmt;
+use std::io::Read;
+
+/// A supply's last programmed level and whether it's switched on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Supply<L> {
+    /// `None` until a level has been set through the `Programmer`
+    pub level: Option<L>,
+    /// `None` until the supply has been switched through the `Programmer`
+    pub on: Option<bool>,
+}
+
+impl<L> Default for Supply<L> {
+    fn default() -> Self {
+        Supply {
+            level: None,
+            on: None,
+        }
+    }
+}
+
+/// A byte that didn't read back as expected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Difference {
+    pub address: u32,
+    pub expected: u8,
+    pub actual: u8,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#06x}: expected {:02x}, read {:02x}",
+            self.address, self.expected, self.actual
+        )
+    }
+}
+
+/// Result of `verify_margins`: each VCC level with the bytes that didn't
+/// verify at it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MarginReport {
+    pub nominal: (Vcc, Vec<Difference>),
+    pub low: (Vcc, Vec<Difference>),
+    pub high: (Vcc, Vec<Difference>),
+}
+
+impl MarginReport {
+    pub fn passed(&self) -> bool {
+        self.nominal.1.is_empty()
+            && self.low.1.is_empty()
+            && self.high.1.is_empty()
+    }
+
+    /// Bytes that verify at nominal VCC but not at one of the margins,
+    /// with the level they failed at. A byte failing at both is listed at
+    /// the low margin.
+    pub fn marginal(&self) -> Vec<(Vcc, Difference)> {
+        let at_nominal = |a| self.nominal.1.iter().any(|d| d.address == a);
+        let mut marginal: Vec<(Vcc, Difference)> = self
+            .low
+            .1
+            .iter()
+            .map(|&d| (self.low.0, d))
+            .chain(self.high.1.iter().map(|&d| (self.high.0, d)))
+            .filter(|(_, d)| !at_nominal(d.address))
+            .collect();
+        marginal.sort_by_key(|(_, d)| d.address);
+        marginal.dedup_by_key(|(_, d)| d.address);
+        marginal
+    }
+}
+
+impl fmt::Display for MarginReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (level, differences) in &[&self.nominal, &self.low, &self.high] {
+            write!(f, "{:>5}: ", level.to_string())?;
+            if differences.is_empty() {
+                writeln!(f, "verified")?;
+            } else {
+                writeln!(f, "{} bytes differ", differences.len())?;
+            }
+        }
+        let marginal = self.marginal();
+        if marginal.is_empty() {
+            return write!(f, "No bytes only pass at nominal");
+        }
+        write!(f, "{} bytes only pass at nominal:", marginal.len())?;
+        for (level, d) in &marginal {
+            write!(f, "\n{} at {}", d, level)?;
+        }
+        Ok(())
+    }
+}
+
+/// An initialized programmer and everything we've told it since: the chip
+/// in the socket, the supplies and the pin drivers. Going through this
+/// rather than the free functions keeps that state in one place, and the
+/// chip operations put it back the way they found it.
+///
+/// Reads, programming and verifies report to the observer, if there is one,
+/// and stop with `progress::Cancelled` once the cancel token is cancelled.
+pub struct Programmer<T: UsbContext> {
+    handle: DeviceHandle<T>,
+    firmware: String,
+    chip: Option<&'static Chip>,
+    vcc: Supply<Vcc>,
+    vpp: Supply<Vpp>,
+    pins: Option<PinConfig>,
+    // Entries `select_discovered` has made, kept so each is leaked once
+    discovered: Vec<&'static Chip>,
+    observer: Option<Observer>,
+    cancel: CancelToken,
+}
+
+/// Finds the programmer, loads the firmware and wraps the handle.
+pub fn open(options: &InitOptions) -> Result<Programmer<impl UsbContext>> {
+    Programmer::new(init_with(options)?)
+}
+
+impl<T: UsbContext> Programmer<T> {
+    /// Wraps a handle that has already been through `init_with`. Nothing
+    /// is sent past the firmware version: the supplies and pins are whatever
+    /// they were left as, and stay unknown until they're set through here.
+    pub fn new(mut handle: DeviceHandle<T>) -> Result<Self> {
+        let version = firmware_version(&mut handle)?;
+        let firmware = String::from_utf8_lossy(&version.bytes[..version.len])
+            .trim_end_matches('\0')
+            .to_string();
+
+        Ok(Programmer {
+            handle,
+            firmware,
+            chip: None,
+            vcc: Supply::default(),
+            vpp: Supply::default(),
+            pins: None,
+            discovered: Vec::new(),
+            observer: None,
+            cancel: CancelToken::new(),
+        })
+    }
+
+    /// For commands the `Programmer` doesn't wrap. Anything changed through
+    /// the raw handle isn't tracked.
+    pub fn handle(&mut self) -> &mut DeviceHandle<T> {
+        &mut self.handle
+    }
+
+    /// The version string the firmware reported after loading.
+    pub fn firmware(&self) -> &str {
+        &self.firmware
+    }
+
+    pub fn chip(&self) -> Result<&'static Chip> {
+        self.chip
+            .ok_or_else(|| anyhow!("No chip selected, use `chip <name>`"))
+    }
+
+    pub fn vcc(&self) -> Supply<Vcc> {
+        self.vcc
+    }
+
+    pub fn vpp(&self) -> Supply<Vpp> {
+        self.vpp
+    }
+
+    /// `None` until pins have been loaded through `configure`, and again
+    /// after each chip operation.
+    pub fn pins(&self) -> Option<&PinConfig> {
+        self.pins.as_ref()
+    }
+
+    pub fn set_observer(&mut self, observer: Option<Observer>) {
+        self.observer = observer;
+    }
+
+    /// A clone of the token the chip operations check. Cancelling it stops
+    /// the current operation; `reset` it before starting the next.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Makes `chip` the one the operations work on. Nothing is sent: each
+    /// operation loads the pins it needs, and the supplies are left for the
+    /// caller to set. Any VPP level set for the previous chip is dropped.
+    pub fn select(&mut self, chip: &'static Chip) -> Result<()> {
+        self.chip = Some(chip);
+        self.vpp.level = None;
+        Ok(())
+    }
+
+    pub fn configure(&mut self, config: PinConfig) -> Result<()> {
+        self.pins = None;
+        configure_pins(&mut self.handle, &config)?;
+        self.pins = Some(config);
+        Ok(())
+    }
+
+    pub fn set_vcc(&mut self, level: Vcc) -> Result<()> {
+        let chip = self.chip()?;
+        voltage::set_vcc(&mut self.handle, chip, level)?;
+        self.vcc.level = Some(level);
+        Ok(())
+    }
+
+    pub fn set_vpp(&mut self, level: Vpp) -> Result<()> {
+        let chip = self.chip()?;
+        voltage::set_vpp(&mut self.handle, chip, level)?;
+        self.vpp.level = Some(level);
+        Ok(())
+    }
+
+    pub fn vcc_on(&mut self) -> Result<()> {
+        if self.vcc.level.is_none() {
+            bail!("Set a VCC level before switching it on");
+        }
+        voltage::vcc_on(&mut self.handle)?;
+        self.vcc.on = Some(true);
+        Ok(())
+    }
+
+    pub fn vcc_off(&mut self) -> Result<()> {
+        voltage::vcc_off(&mut self.handle)?;
+        self.vcc.on = Some(false);
+        Ok(())
+    }
+
+    /// Switches VPP on, checking its level against the chip selected now.
+    pub fn vpp_on(&mut self) -> Result<()> {
+        let level = match self.vpp.level {
+            Some(level) => level,
+            None => bail!("Set a VPP level before switching it on"),
+        };
+        voltage::check_vpp(self.chip()?, level)?;
+        voltage::vpp_on(&mut self.handle)?;
+        self.vpp.on = Some(true);
+        Ok(())
+    }
+
+    pub fn vpp_off(&mut self) -> Result<()> {
+        voltage::vpp_off(&mut self.handle)?;
+        self.vpp.on = Some(false);
+        Ok(())
+    }
+
+    /// VPP off, then VCC off.
+    pub fn power_down(&mut self) -> Result<()> {
+        self.vpp_off()?;
+        self.vcc_off()
+    }
+
+    /// A reader over the selected chip. VCC is switched on with the first
+    /// read and off again when the reader is dropped.
+    pub fn reader(&mut self) -> Result<ChipReader<'_, T>> {
+        let chip = self.chip()?;
+        if !chip.kind.is_parallel() {
+            bail!("{} is a serial part, use `read`", chip.name);
+        }
+        check_block_reads(chip)?;
+        match self.vcc.level {
+            Some(level) if chip.vcc.contains(&level) => {}
+            _ => {
+                bail!("Set VCC to one of {}'s levels before reading", chip.name)
+            }
+        }
+        self.power_down()?;
+        Ok(ChipReader::new(&mut self.handle, chip))
+    }
+
+    /// Reads the whole chip.
+    pub fn read(&mut self) -> Result<Vec<u8>> {
+        let size = self.chip()?.size as usize;
+        self.read_tracked(size, Phase::Read)
+    }
+
+    /// Reads the whole chip `passes` times and votes each bit, for old
+    /// parts whose weak bits read differently from pass to pass.
+    pub fn read_passes(&mut self, passes: usize) -> Result<Stability> {
+        if passes == 0 {
+            bail!("Reading takes at least one pass");
+        }
+        let mut reads = Vec::with_capacity(passes);
+        for _ in 0..passes {
+            reads.push(self.read()?);
+        }
+        stability::vote(&reads)
+    }
+
+    /// Compares the chip against `image`, from address 0.
+    pub fn verify(&mut self, image: &[u8]) -> Result<Vec<Difference>> {
+        self.check_fits(image)?;
+        let contents = self.read_tracked(image.len(), Phase::Verify)?;
+        Ok(differences(image, &contents))
+    }
+
+    /// Verifies at nominal VCC and again at `low` and `high`, or the chip's
+    /// own margins (see `Chip::vcc_margins`) when they're `None`. Only
+    /// parallel parts, whose reads take VCC from the regulator as it was
+    /// left; the serial drivers set their own. Refused before anything is
+    /// read unless all three levels are measured (see `Vcc::is_measured`),
+    /// since a margin is only worth checking at the voltage it claims.
+    pub fn verify_margins(
+        &mut self,
+        image: &[u8],
+        levels: Option<(Vcc, Vcc)>,
+    ) -> Result<MarginReport> {
+        let chip = self.chip()?;
+        if !chip.kind.is_parallel() {
+            bail!("Margin verify is only for parallel parts");
+        }
+        let (low, high) = match levels.or_else(|| chip.vcc_margins()) {
+            Some(levels) => levels,
+            None => bail!("{} has no VCC margins", chip.name),
+        };
+        let nominal = self
+            .vcc
+            .level
+            .ok_or_else(|| anyhow!("Set a VCC level first"))?;
+        if let Some(level) =
+            [nominal, low, high].iter().find(|l| !l.is_measured())
+        {
+            bail!(
+                "Can't verify margins: {} VCC hasn't been measured, so the \
+                 regulator can't be trusted to give it",
+                level
+            );
+        }
+
+        let mut results = vec![];
+        for &level in &[nominal, low, high] {
+            let result = self.set_vcc(level).and_then(|_| self.verify(image));
+            if level != nominal || result.is_err() {
+                self.set_vcc(nominal)?;
+            }
+            results.push(result?);
+        }
+        let at_high = results.pop().unwrap_or_default();
+        let at_low = results.pop().unwrap_or_default();
+        Ok(MarginReport {
+            nominal: (nominal, results.pop().unwrap_or_default()),
+            low: (low, at_low),
+            high: (high, at_high),
+        })
+    }
+
+    /// Every byte that isn't blank: `ff`, or `3fff` words on a PIC, whose
+    /// factory OSCCAL word is left out.
+    pub fn blank_check(&mut self) -> Result<Vec<Difference>> {
+        let chip = self.chip()?;
+        let size = chip.size as usize;
+        match chip.kind {
+            ChipKind::Pic(p) => {
+                let mut differences =
+                    self.verify(&[0xff, 0x3f].repeat(size / 2))?;
+                if p.calibration {
+                    differences.retain(|d| d.address < chip.size - 2);
+                }
+                Ok(differences)
+            }
+            _ => self.verify(&vec![0xff; size]),
+        }
+    }
+
+    /// Programs `image` from address 0 and verifies it. EPROM and flash bits
+    /// can only be programmed from 1 to 0, so for those this fails before
+    /// writing anything if a byte would need a 0 turned back into a 1.
+    /// EEPROMs only have the bytes (or pages, or words) that differ
+    /// written, through the data protection sequence if the chip has one.
+    pub fn program(&mut self, image: &[u8]) -> Result<Vec<Difference>> {
+        let mode = match self.chip()?.kind {
+            ChipKind::Eeprom(e) if e.sdp => WriteMode::Protected,
+            _ => WriteMode::Unprotected,
+        };
+        self.program_as(image, mode)
+    }
+
+    /// Turns an EEPROM's software data protection off, then programs it
+    /// with plain writes. Protection stays off.
+    pub fn disable_sdp_and_program(
+        &mut self,
+        image: &[u8],
+    ) -> Result<Vec<Difference>> {
+        self.disable_sdp()?;
+        self.program_as(image, WriteMode::Unprotected)
+    }
+
+    pub fn enable_sdp(&mut self) -> Result<()> {
+        let chip = self.chip()?;
+        let result = match chip.kind {
+            ChipKind::Eeprom(e) => {
+                eeprom::enable_sdp(&mut self.handle, chip, e)
+            }
+            _ => bail!("{} isn't an EEPROM", chip.name),
+        };
+        self.restore(chip)?;
+        result
+    }
+
+    pub fn disable_sdp(&mut self) -> Result<()> {
+        let chip = self.chip()?;
+        let result = match chip.kind {
+            ChipKind::Eeprom(e) => {
+                eeprom::disable_sdp(&mut self.handle, chip, e)
+            }
+            _ => bail!("{} isn't an EEPROM", chip.name),
+        };
+        self.restore(chip)?;
+        result
+    }
+
+    fn program_as(
+        &mut self,
+        image: &[u8],
+        mode: WriteMode,
+    ) -> Result<Vec<Difference>> {
+        let chip = self.chip()?;
+        self.check_fits(image)?;
+
+        let contents = self.read_tracked(image.len(), Phase::Read)?;
+        let rewritable = chip.kind.is_rewritable();
+        if let Some(d) = differences(image, &contents)
+            .into_iter()
+            .find(|d| !rewritable && d.actual & d.expected != d.expected)
+        {
+            bail!("{} needs erasing first ({})", chip.name, d);
+        }
+
+        let mut tracker = Tracker::new(
+            Phase::Program,
+            image.len() as u64,
+            self.observer.as_deref_mut(),
+            &self.cancel,
+        );
+        let result = match chip.kind {
+            ChipKind::Eprom => {
+                eprom::program(&mut self.handle, chip, image, &mut tracker)
+            }
+            ChipKind::Flash(f) => {
+                flash::program(&mut self.handle, chip, f, image, &mut tracker)
+            }
+            ChipKind::Eeprom(e) => eeprom::program(
+                &mut self.handle,
+                chip,
+                e,
+                image,
+                &contents,
+                mode,
+                &mut tracker,
+            ),
+            ChipKind::SpiFlash(f) => spiflash::program(
+                &mut self.handle,
+                chip,
+                f,
+                image,
+                &mut tracker,
+            ),
+            ChipKind::I2cEeprom(e) => i2ceeprom::program(
+                &mut self.handle,
+                chip,
+                e,
+                image,
+                &contents,
+                &mut tracker,
+            ),
+            ChipKind::Microwire(m) => microwire::program(
+                &mut self.handle,
+                chip,
+                m,
+                image,
+                &contents,
+                &mut tracker,
+            ),
+            ChipKind::Avr(a) => avr::program_flash(
+                &mut self.handle,
+                chip,
+                a,
+                image,
+                &mut tracker,
+            ),
+            ChipKind::Pic(_) => Err(anyhow!(
+                "{} is programmed from a hex file, see `program_pic`",
+                chip.name
+            )),
+            ChipKind::Gal(_) => Err(anyhow!(
+                "{} is programmed from a fuse map, see `program_gal`",
+                chip.name
+            )),
+        };
+        self.restore(chip)?;
+        result?;
+
+        self.verify(image)
+    }
+
+    /// Erases the whole chip.
+    pub fn erase(&mut self) -> Result<()> {
+        let chip = self.chip()?;
+        let result = match chip.kind {
+            ChipKind::Eprom => bail!(
+                "{} is UV erasable, it can't be erased electrically",
+                chip.name
+            ),
+            ChipKind::Flash(f) => flash::erase_chip(&mut self.handle, chip, f),
+            ChipKind::SpiFlash(f) => {
+                spiflash::erase(&mut self.handle, chip, f, Erase::Chip, 0)
+                    .map(|_| ())
+            }
+            ChipKind::Microwire(m) => {
+                microwire::erase_all(&mut self.handle, chip, m)
+            }
+            ChipKind::Pic(p) => pic::erase(&mut self.handle, chip, p),
+            ChipKind::Avr(a) => avr::chip_erase(&mut self.handle, chip, a),
+            ChipKind::Gal(g) => gal::erase(&mut self.handle, chip, g),
+            // No erase command, it's just a write of all ff
+            ChipKind::Eeprom(_) | ChipKind::I2cEeprom(_) => {
+                let differences =
+                    self.program(&vec![0xff; chip.size as usize])?;
+                if let Some(d) = differences.first() {
+                    bail!("Erase didn't take ({})", d);
+                }
+                return Ok(());
+            }
+        };
+        self.restore(chip)?;
+        result
+    }
+
+    /// Erases the sector containing `address`. Returns the sector's start
+    /// address and size.
+    pub fn erase_sector(&mut self, address: u32) -> Result<(u32, u32)> {
+        let chip = self.chip()?;
+        let result = match chip.kind {
+            ChipKind::Flash(f) => {
+                flash::erase_sector(&mut self.handle, chip, f, address)
+            }
+            ChipKind::SpiFlash(s) => spiflash::erase(
+                &mut self.handle,
+                chip,
+                s,
+                Erase::Sector,
+                address,
+            ),
+            _ => bail!("{} has no sectors", chip.name),
+        };
+        self.restore(chip)?;
+        result
+    }
+
+    /// Erases the block (the largest erase type) containing `address` on
+    /// SPI flash.
+    pub fn erase_block(&mut self, address: u32) -> Result<(u32, u32)> {
+        let chip = self.chip()?;
+        let flash = spi_only(chip)?;
+        let result = spiflash::erase(
+            &mut self.handle,
+            chip,
+            flash,
+            Erase::Block,
+            address,
+        );
+        self.restore(chip)?;
+        result
+    }
+
+    /// SPI flash status register 1.
+    pub fn spi_status(&mut self) -> Result<Status> {
+        let chip = self.chip()?;
+        spi_only(chip)?;
+        let result = spiflash::status(&mut self.handle, chip);
+        self.restore(chip)?;
+        result
+    }
+
+    /// Sets SPI flash block protect bits BP0-BP2.
+    pub fn set_block_protect(&mut self, bits: u8) -> Result<Status> {
+        let chip = self.chip()?;
+        spi_only(chip)?;
+        let result = spiflash::set_block_protect(&mut self.handle, chip, bits);
+        self.restore(chip)?;
+        result
+    }
+
+    /// Reads an SPI flash's discoverable parameters.
+    pub fn sfdp(&mut self) -> Result<Sfdp> {
+        let chip = self.chip()?;
+        spi_only(chip)?;
+        let result = spiflash::sfdp(&mut self.handle, chip);
+        self.restore(chip)?;
+        result
+    }
+
+    /// Reads the JEDEC ID and SFDP of the 8 pin SPI flash in the socket at
+    /// `level`, which has to come from the part's marking, and selects an
+    /// entry built from them with SFDP's size, page size and erase types.
+    /// For parts the database doesn't have; if it fails the previously
+    /// selected chip is kept. Entries are made once per part and reused.
+    pub fn select_discovered(
+        &mut self,
+        level: Vcc,
+    ) -> Result<(&'static Chip, Sfdp)> {
+        let probe = chip::spi_flash_probe(level);
+        self.pins = None;
+        let result = spiflash::discover(&mut self.handle, &probe);
+        self.power_down()?;
+        let (signature, sfdp) = result?;
+
+        let size = sfdp.size as u32;
+        let page_size = sfdp.page_size.unwrap_or(256);
+        let mut erase_types = sfdp.erase_types.clone();
+        erase_types.sort_unstable();
+        let known = self.discovered.iter().copied().find(|c| match c.kind {
+            ChipKind::SpiFlash(f) => {
+                f.signature == signature
+                    && f.page_size == page_size
+                    && f.erase_types == &erase_types[..]
+                    && c.size == size
+                    && c.vcc == [level]
+            }
+            _ => false,
+        });
+        let chip = match known {
+            Some(chip) => chip,
+            None => {
+                let chip = chip::discovered_spi_flash(
+                    signature,
+                    size,
+                    page_size,
+                    erase_types,
+                    level,
+                );
+                self.discovered.push(chip);
+                chip
+            }
+        };
+        self.select(chip)?;
+        Ok((chip, sfdp))
+    }
+
+    /// Reads the selected chip's electronic signature.
+    pub fn identify(&mut self) -> Result<Signature> {
+        let chip = self.chip()?;
+        let result = match chip.kind {
+            ChipKind::Eprom => eprom::signature(&mut self.handle, chip),
+            ChipKind::Flash(_) => flash::signature(&mut self.handle, chip),
+            ChipKind::Eeprom(_)
+            | ChipKind::I2cEeprom(_)
+            | ChipKind::Microwire(_) => {
+                bail!("{} has no electronic signature", chip.name)
+            }
+            ChipKind::SpiFlash(_) => spiflash::jedec_id(&mut self.handle, chip),
+            ChipKind::Avr(_) => {
+                avr::signature(&mut self.handle, chip).map(|s| Signature {
+                    manufacturer: s[0],
+                    device: u16::from_be_bytes([s[1], s[2]]),
+                })
+            }
+            ChipKind::Pic(_) => {
+                pic::device_id(&mut self.handle, chip).map(|id| Signature {
+                    manufacturer: MICROCHIP,
+                    device: pic::split_device_id(id).0,
+                })
+            }
+            ChipKind::Gal(_) => {
+                bail!("{} has no JEDEC signature, see `gal_pes`", chip.name)
+            }
+        };
+        self.restore(chip)?;
+        result
+    }
+
+    /// Reads the signature of an unknown part by its pin count: the JEDEC ID
+    /// for 8 pin SPI flash, and for 28 pin parts autoselect at 5 V, then the
+    /// A9 high voltage read. That puts 12.5 V on pin 24, so it's only tried
+    /// if `eprom` says the caller has confirmed the part is a 28 pin EPROM.
+    /// Each probe runs in a database part's pinout, in turn, until one
+    /// answers. Returns the signature and the database parts that report
+    /// it; the selected chip is kept. If none answers, the error lists what
+    /// each probe got.
+    pub fn detect(
+        &mut self,
+        pin_count: u8,
+        eprom: bool,
+    ) -> Result<(Signature, Vec<&'static Chip>)> {
+        let probes = SIGNATURE_PROBES
+            .iter()
+            .find(|(pins, _)| *pins == pin_count)
+            .map(|(_, probes)| *probes)
+            .ok_or_else(|| {
+                anyhow!(
+                    "No signature probe for {} pin parts (AVRs and PICs \
+                     have `avr detect` and `pic detect`)",
+                    pin_count
+                )
+            })?;
+        let selected = self.chip;
+        let mut found = None;
+        let mut failures = Vec::new();
+        for &(name, probe) in probes {
+            if probe == Probe::HighVoltage && !eprom {
+                failures.push(format!(
+                    "{}: skipped, A9 at 12.5 V needs the part confirmed as \
+                     a {} pin EPROM",
+                    name, pin_count
+                ));
+                continue;
+            }
+            let chip = chip::find(name)?;
+            self.select(chip)?;
+            let result = match probe {
+                Probe::Identify => self.identify(),
+                Probe::Autoselect => {
+                    let result = flash::signature(&mut self.handle, chip);
+                    self.restore(chip)?;
+                    result
+                }
+                Probe::HighVoltage => {
+                    let result = eprom::signature(&mut self.handle, chip);
+                    self.restore(chip)?;
+                    result
+                }
+            };
+            match result {
+                Ok(s) if s.manufacturer != 0x00 && s.manufacturer != 0xff => {
+                    found = Some(s);
+                    break;
+                }
+                Ok(s) => failures.push(format!("{}: read {}", name, s)),
+                Err(e) => failures.push(format!("{}: {}", name, e)),
+            }
+        }
+        self.chip = selected;
+        match found {
+            Some(signature) => Ok((signature, chip::lookup(signature))),
+            None => bail!(
+                "No {} pin part answered:\n{}",
+                pin_count,
+                failures.join("\n")
+            ),
+        }
+    }
+
+    /// Reads all of a PIC: program memory, data EEPROM, IDs and config.
+    pub fn read_pic(&mut self) -> Result<PicImage> {
+        let chip = self.chip()?;
+        let p = pic_only(chip)?;
+        self.power_down()?;
+        let mut tracker = Tracker::new(
+            Phase::Read,
+            u64::from(chip.size),
+            self.observer.as_deref_mut(),
+            &self.cancel,
+        );
+        let result = pic::read(&mut self.handle, chip, p, &mut tracker);
+        self.restore(chip)?;
+        result
+    }
+
+    /// Erases a PIC, programs `image` and verifies it. The chip's factory
+    /// calibration is kept whatever the image has.
+    pub fn program_pic(&mut self, image: &PicImage) -> Result<Vec<Difference>> {
+        let chip = self.chip()?;
+        let p = pic_only(chip)?;
+        self.power_down()?;
+        let mut tracker = Tracker::new(
+            Phase::Program,
+            (image.program.len() + image.eeprom.len()) as u64,
+            self.observer.as_deref_mut(),
+            &self.cancel,
+        );
+        let result =
+            pic::program(&mut self.handle, chip, p, image, &mut tracker);
+        self.restore(chip)?;
+        result?;
+        self.verify_pic(image)
+    }
+
+    /// Compares a PIC against `image`. Addresses are byte addresses in
+    /// MPLAB's hex layout.
+    pub fn verify_pic(&mut self, image: &PicImage) -> Result<Vec<Difference>> {
+        let p = pic_only(self.chip()?)?;
+        let actual = self.read_pic()?;
+        let mut expected = image.clone();
+        expected.keep_calibration(p, &actual);
+        let actual = actual.to_hex();
+        Ok(expected
+            .to_hex()
+            .into_iter()
+            .filter_map(|(address, expected)| {
+                let actual = actual.get(&address).copied().unwrap_or(0xff);
+                if actual == expected {
+                    None
+                } else {
+                    Some(Difference {
+                        address,
+                        expected,
+                        actual,
+                    })
+                }
+            })
+            .collect())
+    }
+
+    /// Reads the device ID through the first `pin_count` pin PIC in the
+    /// database's pinout and selects the part it belongs to. Returns the
+    /// part and its silicon revision.
+    pub fn detect_pic(&mut self, pin_count: u8) -> Result<(&'static Chip, u8)> {
+        let probe = chip::CHIPS
+            .iter()
+            .find(|c| {
+                matches!(c.kind, ChipKind::Pic(_)) && c.pin_count() == pin_count
+            })
+            .ok_or_else(|| {
+                anyhow!("No {} pin PICs in the database", pin_count)
+            })?;
+        self.select(probe)?;
+        let id = pic::device_id(&mut self.handle, probe);
+        self.restore(probe)?;
+        let id = id?;
+        if id == 0 || id == 0x3fff {
+            bail!("No PIC answered with a device ID");
+        }
+        let (device, revision) = pic::split_device_id(id);
+
+        let found = chip::CHIPS
+            .iter()
+            .find(|c| match c.kind {
+                ChipKind::Pic(p) => {
+                    p.device_id == device && c.pin_count() == pin_count
+                }
+                _ => false,
+            })
+            .ok_or_else(|| anyhow!("Unknown PIC device ID {:04x}", device))?;
+        self.select(found)?;
+        Ok((found, revision))
+    }
+
+    /// Reads an AVR's data EEPROM.
+    pub fn read_eeprom(&mut self) -> Result<Vec<u8>> {
+        let chip = self.chip()?;
+        let a = avr_only(chip)?;
+        self.power_down()?;
+        let mut tracker = Tracker::new(
+            Phase::Read,
+            u64::from(a.eeprom_size),
+            self.observer.as_deref_mut(),
+            &self.cancel,
+        );
+        let result = avr::read_eeprom(&mut self.handle, chip, a, &mut tracker);
+        self.restore(chip)?;
+        result
+    }
+
+    /// Writes the bytes of an AVR's data EEPROM that differ from `image`,
+    /// then verifies it.
+    pub fn program_eeprom(&mut self, image: &[u8]) -> Result<Vec<Difference>> {
+        let chip = self.chip()?;
+        let a = avr_only(chip)?;
+        if image.len() > a.eeprom_size as usize {
+            bail!(
+                "Image is {} bytes, {} has {} bytes of EEPROM",
+                image.len(),
+                chip.name,
+                a.eeprom_size
+            );
+        }
+        let current = self.read_eeprom()?;
+        let mut tracker = Tracker::new(
+            Phase::Program,
+            image.len() as u64,
+            self.observer.as_deref_mut(),
+            &self.cancel,
+        );
+        let result = avr::program_eeprom(
+            &mut self.handle,
+            chip,
+            a,
+            image,
+            &current,
+            &mut tracker,
+        );
+        self.restore(chip)?;
+        result?;
+        Ok(differences(image, &self.read_eeprom()?))
+    }
+
+    pub fn read_fuses(&mut self) -> Result<Fuses> {
+        let chip = self.chip()?;
+        let a = avr_only(chip)?;
+        let result = avr::read_fuses(&mut self.handle, chip, a);
+        self.restore(chip)?;
+        result
+    }
+
+    /// Writes an AVR fuse or lock byte and returns what reads back. There's
+    /// no undoing a fuse that disables programming; check
+    /// `avr::warnings` first.
+    pub fn write_fuse(&mut self, fuse: Fuse, value: u8) -> Result<u8> {
+        let chip = self.chip()?;
+        let a = avr_only(chip)?;
+        let result = avr::write_fuse(&mut self.handle, chip, a, fuse, value);
+        self.restore(chip)?;
+        result
+    }
+
+    /// Reads the signature through the first `pin_count` pin AVR in the
+    /// database's pinout and selects the part it belongs to.
+    pub fn detect_avr(&mut self, pin_count: u8) -> Result<&'static Chip> {
+        let probe = chip::CHIPS
+            .iter()
+            .find(|c| {
+                matches!(c.kind, ChipKind::Avr(_)) && c.pin_count() == pin_count
+            })
+            .ok_or_else(|| {
+                anyhow!("No {} pin AVRs in the database", pin_count)
+            })?;
+        self.select(probe)?;
+        let signature = avr::signature(&mut self.handle, probe);
+        self.restore(probe)?;
+        let signature = signature?;
+
+        let found = chip::CHIPS
+            .iter()
+            .find(|c| match c.kind {
+                ChipKind::Avr(a) => {
+                    a.signature == signature && c.pin_count() == pin_count
+                }
+                _ => false,
+            })
+            .ok_or_else(|| {
+                anyhow!("Unknown AVR signature {}", hex::encode(signature))
+            })?;
+        self.select(found)?;
+        Ok(found)
+    }
+
+    /// Reads a GAL's fuses. A protected part reads back as a uniform array
+    /// rather than failing, see `gal::read`.
+    pub fn read_gal(&mut self) -> Result<Jedec> {
+        let chip = self.chip()?;
+        let g = gal_only(chip)?;
+        self.power_down()?;
+        let mut tracker = Tracker::new(
+            Phase::Read,
+            u64::from(g.rows),
+            self.observer.as_deref_mut(),
+            &self.cancel,
+        );
+        let result = gal::read(&mut self.handle, chip, g, &mut tracker);
+        self.restore(chip)?;
+        result
+    }
+
+    /// Erases a GAL, programs `jedec` and verifies it. The security fuse is
+    /// only set, if `jedec` asks for it, once the verify has passed. Returns
+    /// the JEDEC numbers of the fuses that didn't verify.
+    pub fn program_gal(&mut self, jedec: &Jedec) -> Result<Vec<u32>> {
+        let chip = self.chip()?;
+        let g = gal_only(chip)?;
+        self.power_down()?;
+        let mut tracker = Tracker::new(
+            Phase::Program,
+            u64::from(g.rows),
+            self.observer.as_deref_mut(),
+            &self.cancel,
+        );
+        let result =
+            gal::program(&mut self.handle, chip, g, jedec, &mut tracker);
+        self.restore(chip)?;
+        result?;
+
+        let differences = self.verify_gal(jedec)?;
+        if differences.is_empty() && jedec.security {
+            let result = gal::secure(&mut self.handle, chip, g);
+            self.restore(chip)?;
+            result?;
+        }
+        Ok(differences)
+    }
+
+    /// JEDEC numbers of the fuses that differ from `jedec`.
+    pub fn verify_gal(&mut self, jedec: &Jedec) -> Result<Vec<u32>> {
+        let actual = self.read_gal()?;
+        Ok(gal::differences(jedec, &actual))
+    }
+
+    /// Reads a GAL's programmer electronic signature.
+    pub fn gal_pes(&mut self) -> Result<[u8; 8]> {
+        let chip = self.chip()?;
+        let g = gal_only(chip)?;
+        self.power_down()?;
+        let result = gal::pes(&mut self.handle, chip, g);
+        self.restore(chip)?;
+        result
+    }
+
+    // The first `len` bytes, a block at a time. VCC is off afterwards, even
+    // after an error or cancellation.
+    fn read_tracked(&mut self, len: usize, phase: Phase) -> Result<Vec<u8>> {
+        let chip = self.chip()?;
+        self.power_down()?;
+
+        let mut tracker = Tracker::new(
+            phase,
+            len as u64,
+            self.observer.as_deref_mut(),
+            &self.cancel,
+        );
+        match chip.kind {
+            ChipKind::SpiFlash(_) => {
+                return spiflash::read(
+                    &mut self.handle,
+                    chip,
+                    len,
+                    &mut tracker,
+                )
+            }
+            ChipKind::I2cEeprom(e) => {
+                return i2ceeprom::read(
+                    &mut self.handle,
+                    chip,
+                    e,
+                    len,
+                    &mut tracker,
+                )
+            }
+            ChipKind::Avr(_) => {
+                return avr::read_flash(
+                    &mut self.handle,
+                    chip,
+                    len,
+                    &mut tracker,
+                )
+            }
+            ChipKind::Pic(_) => {
+                return pic::read_program(
+                    &mut self.handle,
+                    chip,
+                    len,
+                    &mut tracker,
+                )
+            }
+            ChipKind::Microwire(m) => {
+                return microwire::read(
+                    &mut self.handle,
+                    chip,
+                    m,
+                    len,
+                    &mut tracker,
+                )
+            }
+            ChipKind::Gal(_) => {
+                bail!("{} is read as a fuse map, see `read_gal`", chip.name)
+            }
+            _ => {}
+        }
+
+        check_block_reads(chip)?;
+        let mut reader = ChipReader::new(&mut self.handle, chip);
+        let mut image = vec![0; len];
+        let mut done = 0;
+        for block in image.chunks_mut(BLOCK_SIZE) {
+            reader.read_exact(block)?;
+            done += block.len();
+            tracker.update(done as u64, done as u32 - 1)?;
+        }
+        Ok(image)
+    }
+
+    fn check_fits(&self, image: &[u8]) -> Result<()> {
+        let chip = self.chip()?;
+        if image.len() > chip.size as usize {
+            bail!(
+                "Image is {} bytes, {} only holds {}",
+                image.len(),
+                chip.name,
+                chip.size
+            );
+        }
+        Ok(())
+    }
+
+    // The chip operations switch the supplies and pins as they need to and
+    // leave the pins however they finish; this switches the supplies off
+    // and forgets the pins
+    fn restore(&mut self, chip: &'static Chip) -> Result<()> {
+        self.chip = Some(chip);
+        self.pins = None;
+        self.power_down()
+    }
+}
+
+// How `detect` reads a signature in a database part's pinout
+#[derive(Clone, Copy, PartialEq)]
+enum Probe {
+    /// The part's own `identify`
+    Identify,
+    /// JEDEC autoselect (AA/55/90) at 5 V, which an EPROM ignores
+    Autoselect,
+    /// A9 at 12.5 V, EPROMs only
+    HighVoltage,
+}
+
+// Database parts whose pinouts the signature reads are tried in, by pin
+// count, lowest voltage first. Parts sharing a pin count here share supply
+// pins, so a wrong guess doesn't power the chip backwards.
+static SIGNATURE_PROBES: &[(u8, &[(&str, Probe)])] = &[
+    (8, &[("W25Q80", Probe::Identify)]),
+    (
+        28,
+        &[("28C64", Probe::Autoselect), ("27C64", Probe::HighVoltage)],
+    ),
+];
+
+/// Whether `Programmer::detect` has a high voltage probe for `pin_count`
+/// pin parts, which it only runs once the part is confirmed as an EPROM.
+pub fn needs_eprom_confirmation(pin_count: u8) -> bool {
+    SIGNATURE_PROBES
+        .iter()
+        .filter(|(pins, _)| *pins == pin_count)
+        .flat_map(|(_, probes)| probes.iter())
+        .any(|&(_, probe)| probe == Probe::HighVoltage)
+}
+
+fn spi_only(chip: &Chip) -> Result<&'static SpiFlash> {
+    match chip.kind {
+        ChipKind::SpiFlash(f) => Ok(f),
+        _ => bail!("{} isn't SPI flash", chip.name),
+    }
+}
+
+fn avr_only(chip: &Chip) -> Result<&'static chip::Avr> {
+    match chip.kind {
+        ChipKind::Avr(a) => Ok(a),
+        _ => bail!("{} isn't an AVR", chip.name),
+    }
+}
+
+fn pic_only(chip: &Chip) -> Result<&'static chip::Pic> {
+    match chip.kind {
+        ChipKind::Pic(p) => Ok(p),
+        _ => bail!("{} isn't a PIC", chip.name),
+    }
+}
+
+fn gal_only(chip: &Chip) -> Result<&'static chip::Gal> {
+    match chip.kind {
+        ChipKind::Gal(g) => Ok(g),
+        _ => bail!("{} isn't a GAL", chip.name),
+    }
+}
+
+fn differences(expected: &[u8], actual: &[u8]) -> Vec<Difference> {
+    expected
+        .iter()
+        .zip(actual)
+        .enumerate()
+        .filter(|(_, (e, a))| e != a)
+        .map(|(i, (&expected, &actual))| Difference {
+            address: i as u32,
+            expected,
+            actual,
+        })
+        .collect()
+}
+
+// Parallel parts are read with the block read, which only reaches 8 KB
+fn check_block_reads(chip: &Chip) -> Result<()> {
+    if chip.size > READ_LIMIT {
+        bail!(
+            "{} is {} bytes, block reads only reach the first {} so far",
+            chip.name,
+            chip.size,
+            READ_LIMIT
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(address: u32) -> Difference {
+        Difference {
+            address,
+            expected: 0x00,
+            actual: 0xff,
+        }
+    }
+
+    fn report(
+        nominal: Vec<Difference>,
+        low: Vec<Difference>,
+        high: Vec<Difference>,
+    ) -> MarginReport {
+        MarginReport {
+            nominal: (Vcc::V5_0, nominal),
+            low: (Vcc::V4_5, low),
+            high: (Vcc::V5_5, high),
+        }
+    }
+
+    #[test]
+    fn marginal_leaves_out_nominal_failures() {
+        let r = report(vec![at(3)], vec![at(3), at(7)], vec![at(1), at(3)]);
+        assert!(!r.passed());
+        assert_eq!(r.marginal(), vec![(Vcc::V5_5, at(1)), (Vcc::V4_5, at(7))]);
+    }
+
+    #[test]
+    fn marginal_lists_a_byte_once_at_the_low_margin() {
+        let r = report(vec![], vec![at(2)], vec![at(2)]);
+        assert_eq!(r.marginal(), vec![(Vcc::V4_5, at(2))]);
+    }
+
+    #[test]
+    fn clean_report_passes() {
+        let r = report(vec![], vec![], vec![]);
+        assert!(r.passed());
+        assert!(r.marginal().is_empty());
+        assert!(r.to_string().ends_with("No bytes only pass at nominal"));
+    }
+}
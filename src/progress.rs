@@ -0,0 +1,134 @@
+use anyhow::Result;
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Read,
+    Program,
+    Verify,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Phase::Read => "read",
+            Phase::Program => "program",
+            Phase::Verify => "verify",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How far a long running operation has got.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub phase: Phase,
+    pub done: u64,
+    pub total: u64,
+    /// The chip address being worked on
+    pub address: u32,
+    /// Time since the phase started
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Time left at the rate so far, once there's a rate to go on.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.done == 0 || self.done > self.total {
+            return None;
+        }
+        let per_byte = self.elapsed.as_secs_f64() / self.done as f64;
+        Some(Duration::from_secs_f64(
+            per_byte * (self.total - self.done) as f64,
+        ))
+    }
+}
+
+/// Called as an operation makes progress.
+pub type Observer = Box<dyn FnMut(&Progress)>;
+
+/// Shared flag to stop a running operation. Clones share the flag, so one
+/// can be handed to a signal handler while the programmer holds another.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Rearms the token for the next operation.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// The error an operation stops with when its token is cancelled. The
+/// supplies are off by the time it's returned.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+/// Reports one phase to an observer and checks for cancellation between
+/// steps.
+pub struct Tracker<'a> {
+    phase: Phase,
+    total: u64,
+    start: Instant,
+    observer: Option<&'a mut (dyn FnMut(&Progress) + 'static)>,
+    cancel: &'a CancelToken,
+}
+
+impl<'a> Tracker<'a> {
+    pub fn new(
+        phase: Phase,
+        total: u64,
+        observer: Option<&'a mut (dyn FnMut(&Progress) + 'static)>,
+        cancel: &'a CancelToken,
+    ) -> Self {
+        Tracker {
+            phase,
+            total,
+            start: Instant::now(),
+            observer,
+            cancel,
+        }
+    }
+
+    /// Records `done` bytes finished, the last at `address`. Fails with
+    /// `Cancelled` if the token has been cancelled.
+    pub fn update(&mut self, done: u64, address: u32) -> Result<()> {
+        if let Some(observer) = self.observer.as_mut() {
+            observer(&Progress {
+                phase: self.phase,
+                done,
+                total: self.total,
+                address,
+                elapsed: self.start.elapsed(),
+            });
+        }
+        if self.cancel.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        Ok(())
+    }
+}
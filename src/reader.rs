@@ -0,0 +1,126 @@
+use crate::chip::Chip;
+use crate::voltage::{vcc_off, vcc_on};
+use crate::{poke, read_block, BLOCK_SIZE};
+use anyhow::bail;
+use rusb::{DeviceHandle, UsbContext};
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// `std::io::Read` and `Seek` over a chip's address space. Blocks are read
+/// from the device the first time they're needed and cached after that, so
+/// seeking around a header is cheap.
+///
+/// VCC is switched on with the first read and off again when the reader is
+/// dropped.
+pub struct ChipReader<'a, T: UsbContext> {
+    handle: &'a mut DeviceHandle<T>,
+    size: u64,
+    position: u64,
+    powered: bool,
+    cache: HashMap<u64, Vec<u8>>,
+}
+
+impl<'a, T: UsbContext> ChipReader<'a, T> {
+    pub fn new(handle: &'a mut DeviceHandle<T>, chip: &Chip) -> Self {
+        Self::with_size(handle, u64::from(chip.size))
+    }
+
+    /// A reader over the first `size` bytes, for when there's no chip
+    /// database entry.
+    pub fn with_size(handle: &'a mut DeviceHandle<T>, size: u64) -> Self {
+        ChipReader {
+            handle,
+            size,
+            position: 0,
+            powered: false,
+            cache: HashMap::new(),
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn block(&mut self, index: u64) -> anyhow::Result<&[u8]> {
+        if !self.cache.contains_key(&index) {
+            if !self.powered {
+                vcc_on(self.handle)?;
+                poke(self.handle, &[0x08, 0x00])?;
+                self.powered = true;
+            }
+
+            let chunk =
+                read_block(self.handle, (index * BLOCK_SIZE as u64) as u32)?;
+            if chunk.len != BLOCK_SIZE {
+                bail!(
+                    "Short read at {:#x}: {} bytes",
+                    index * BLOCK_SIZE as u64,
+                    chunk.len
+                );
+            }
+            self.cache.insert(index, chunk.bytes[..chunk.len].to_vec());
+        }
+
+        Ok(&self.cache[&index])
+    }
+}
+
+impl<'a, T: UsbContext> Read for ChipReader<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let block_size = BLOCK_SIZE as u64;
+        let index = self.position / block_size;
+        let offset = (self.position % block_size) as usize;
+        let remaining = (self.size - self.position) as usize;
+
+        let block = self
+            .block(index)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        let len = buf.len().min(block.len() - offset).min(remaining);
+        buf[..len].copy_from_slice(&block[offset..offset + len]);
+
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<'a, T: UsbContext> Seek for ChipReader<'a, T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => offset(self.size, d),
+            SeekFrom::Current(d) => offset(self.position, d),
+        };
+
+        match position {
+            Some(p) => {
+                self.position = p;
+                Ok(p)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek before the start of the chip",
+            )),
+        }
+    }
+}
+
+fn offset(base: u64, delta: i64) -> Option<u64> {
+    if delta < 0 {
+        base.checked_sub(delta.unsigned_abs())
+    } else {
+        base.checked_add(delta as u64)
+    }
+}
+
+impl<'a, T: UsbContext> Drop for ChipReader<'a, T> {
+    fn drop(&mut self) {
+        if self.powered {
+            // Nowhere to report a failure from here
+            let _ = vcc_off(self.handle);
+        }
+    }
+}
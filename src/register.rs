@@ -0,0 +1,101 @@
+use crate::{peek, poke};
+use anyhow::{anyhow, bail, Result};
+use rusb::{DeviceHandle, UsbContext};
+
+// `1c <bus> <addr>` answers with one byte, `1d <bus> <addr> <value>` writes
+// one. The vendor software reads, writes and reads back 0xc0 at startup, so
+// these look like a board level register file (CPLD or an I2C part).
+const READ: u8 = 0x1c;
+const WRITE: u8 = 0x1d;
+
+/// A register we have a name for.
+#[derive(Debug)]
+pub struct Register {
+    pub name: &'static str,
+    pub bus: u8,
+    pub addr: u8,
+    pub description: &'static str,
+}
+
+/// Registers seen in captures. Add to this as they get understood.
+pub static REGISTERS: &[Register] = &[
+    Register {
+        name: "c0",
+        bus: 0x00,
+        addr: 0xc0,
+        description: "Reads e2 at power up, the vendor software writes c2 \
+                      and then reads d2; bit 4 looks like status",
+    },
+    Register {
+        name: "b3",
+        bus: 0x00,
+        addr: 0xb3,
+        description: "Reads 00 at power up, the vendor software writes 10",
+    },
+];
+
+pub fn find_register(name: &str) -> Result<&'static Register> {
+    REGISTERS
+        .iter()
+        .find(|r| r.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| anyhow!("Unknown register: {}", name))
+}
+
+pub fn read_register<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    bus: u8,
+    addr: u8,
+) -> Result<u8> {
+    poke(handle, &[READ, bus, addr])?;
+    let chunk = peek(handle)?;
+    if chunk.len != 1 {
+        bail!(
+            "Register {:02x}:{:02x} answered {} bytes",
+            bus,
+            addr,
+            chunk.len
+        );
+    }
+    Ok(chunk.bytes[0])
+}
+
+pub fn write_register<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    bus: u8,
+    addr: u8,
+    value: u8,
+) -> Result<()> {
+    poke(handle, &[WRITE, bus, addr, value])?;
+    Ok(())
+}
+
+/// Bit operation for `modify_register`.
+#[derive(Debug, Clone, Copy)]
+pub enum BitOp {
+    Set(u8),
+    Clear(u8),
+    Toggle(u8),
+}
+
+impl BitOp {
+    pub fn apply(self, value: u8) -> u8 {
+        match self {
+            BitOp::Set(mask) => value | mask,
+            BitOp::Clear(mask) => value & !mask,
+            BitOp::Toggle(mask) => value ^ mask,
+        }
+    }
+}
+
+/// Reads the register, applies `op` and writes the result back. Returns the
+/// value written.
+pub fn modify_register<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    bus: u8,
+    addr: u8,
+    op: BitOp,
+) -> Result<u8> {
+    let value = op.apply(read_register(handle, bus, addr)?);
+    write_register(handle, bus, addr, value)?;
+    Ok(value)
+}
@@ -0,0 +1,203 @@
+use crate::descriptor;
+use crate::pins::{drive_pattern, replay_idcode_scan, sense_pins, ZIF_PINS};
+use crate::register;
+use crate::{firmware_version, peek, poke, FIRMWARE_DESCRIPTORS};
+use anyhow::{bail, Result};
+use rusb::{DeviceHandle, UsbContext};
+use std::fmt;
+
+// Echo command, answered with 12 34 (initialization.pcapng frame 240)
+const ECHO: u8 = 0x1b;
+const ECHO_REPLY: [u8; 2] = [0x12, 0x34];
+
+// Why the VCC and VPP checks are skipped
+const SUPPLY_SKIPPED: &str =
+    "routing a supply to a pin needs the pin driver setup, not captured yet";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Subsystem {
+    UsbLink,
+    Firmware,
+    PinDrivers,
+    Vcc,
+    Vpp,
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Subsystem::UsbLink => "USB link",
+            Subsystem::Firmware => "Firmware",
+            Subsystem::PinDrivers => "Pin drivers",
+            Subsystem::Vcc => "VCC generator",
+            Subsystem::Vpp => "VPP generator",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How a check went. Checks that would need commands nobody has captured
+/// yet are skipped rather than failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    Skipped(String),
+}
+
+#[derive(Debug)]
+pub struct Check {
+    pub subsystem: Subsystem,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug)]
+pub struct SelfTestReport {
+    pub checks: Vec<Check>,
+}
+
+impl SelfTestReport {
+    /// No check failed. Skipped checks don't count either way.
+    pub fn passed(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|c| !matches!(c.outcome, Outcome::Fail(_)))
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            match &check.outcome {
+                Outcome::Pass => writeln!(f, "{:<14} PASS", check.subsystem)?,
+                Outcome::Fail(reason) => {
+                    writeln!(f, "{:<14} FAIL {}", check.subsystem, reason)?
+                }
+                Outcome::Skipped(reason) => {
+                    writeln!(f, "{:<14} SKIPPED {}", check.subsystem, reason)?
+                }
+            }
+        }
+        write!(f, "{}", if self.passed() { "PASS" } else { "FAIL" })
+    }
+}
+
+/// Runs the checks the vendor software does at startup and reports each
+/// subsystem separately. Only captured commands are sent, and nothing is
+/// routed to the socket: the supply checks need the pin driver setup, which
+/// hasn't been captured, so they're skipped. Every check runs even if an
+/// earlier one fails.
+pub fn selftest<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+) -> Result<SelfTestReport> {
+    let mut checks = vec![];
+    let mut run = |subsystem, result: Result<()>| {
+        checks.push(Check {
+            subsystem,
+            outcome: match result {
+                Ok(()) => Outcome::Pass,
+                Err(e) => Outcome::Fail(e.to_string()),
+            },
+        })
+    };
+
+    run(Subsystem::UsbLink, usb_link(handle));
+    run(Subsystem::Firmware, firmware(handle));
+    run(Subsystem::PinDrivers, pin_drivers(handle));
+
+    for &subsystem in &[Subsystem::Vcc, Subsystem::Vpp] {
+        checks.push(Check {
+            subsystem,
+            outcome: Outcome::Skipped(SUPPLY_SKIPPED.to_string()),
+        });
+    }
+
+    Ok(SelfTestReport { checks })
+}
+
+fn usb_link<T: UsbContext>(handle: &mut DeviceHandle<T>) -> Result<()> {
+    poke(handle, &[ECHO])?;
+    let chunk = peek(handle)?;
+    if chunk.bytes[..chunk.len] != ECHO_REPLY {
+        bail!("echo answered {}", hex::encode(&chunk.bytes[..chunk.len]));
+    }
+    Ok(())
+}
+
+fn firmware<T: UsbContext>(handle: &mut DeviceHandle<T>) -> Result<()> {
+    let mismatches =
+        descriptor::check(handle, &descriptor::decode(FIRMWARE_DESCRIPTORS)?)?;
+    if let Some(first) = mismatches.first() {
+        bail!("descriptors differ from the upload: {}", first);
+    }
+
+    let version = firmware_version(handle)?;
+    if !version.bytes[..version.len].starts_with(b"GQ-4x4") {
+        bail!(
+            "unexpected version string {:?}",
+            String::from_utf8_lossy(&version.bytes[..version.len])
+        );
+    }
+
+    // The vendor software reads this at startup. Any answer means the
+    // firmware is dispatching commands past the basics.
+    let c0 = register::find_register("c0")?;
+    register::read_register(handle, c0.bus, c0.addr)?;
+
+    Ok(())
+}
+
+// Replays the CPLD's IDCODE read, then the `10 55555555` and five `14`s the
+// vendor software sends at startup, and expects the `ff` it got back for
+// every bank. What `10` and `14` do exactly isn't known (if `10` drove the
+// pins, `55` would read back as alternate lows), so this only checks the
+// board answers as it did in the capture.
+fn pin_drivers<T: UsbContext>(handle: &mut DeviceHandle<T>) -> Result<()> {
+    replay_idcode_scan(handle)?;
+    drive_pattern(handle, [0x55; 4])?;
+
+    let levels = sense_pins(handle)?;
+    let low: Vec<String> = (1..=ZIF_PINS)
+        .filter(|&p| !levels.is_high(p).unwrap_or(false))
+        .map(|p| p.to_string())
+        .collect();
+    if !low.is_empty() {
+        bail!("pins not answering ff as in the capture: {}", low.join(" "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(outcomes: Vec<Outcome>) -> SelfTestReport {
+        SelfTestReport {
+            checks: outcomes
+                .into_iter()
+                .map(|outcome| Check {
+                    subsystem: Subsystem::Vcc,
+                    outcome,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn skipped_checks_dont_fail() {
+        let r = report(vec![Outcome::Pass, Outcome::Skipped("x".into())]);
+        assert!(r.passed());
+        assert!(r.to_string().contains("SKIPPED x"));
+        assert!(r.to_string().ends_with("PASS"));
+    }
+
+    #[test]
+    fn failures_fail() {
+        let r = report(vec![
+            Outcome::Skipped("x".into()),
+            Outcome::Fail("y".into()),
+        ]);
+        assert!(!r.passed());
+        assert!(r.to_string().ends_with("FAIL"));
+    }
+}
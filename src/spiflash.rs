@@ -0,0 +1,471 @@
+use crate::bitbang::Spi;
+use crate::chip::{Chip, Signature, SpiFlash};
+use crate::progress::Tracker;
+use crate::voltage;
+use anyhow::{anyhow, bail, Result};
+use rusb::{DeviceHandle, UsbContext};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+const WRITE_STATUS: u8 = 0x01;
+const CHIP_ERASE: u8 = 0xc7;
+const PAGE_PROGRAM: u8 = 0x02;
+const READ: u8 = 0x03;
+const READ_STATUS: u8 = 0x05;
+const WRITE_ENABLE: u8 = 0x06;
+const READ_SFDP: u8 = 0x5a;
+const READ_ID: u8 = 0x9f;
+
+// Bytes per tracker update when reading
+const READ_CHUNK: usize = 256;
+// Every clock edge is a pin driver transfer and every sampled bit a sense,
+// about 7 USB transfers a bit, which comes to a few hundred bytes a second
+const BYTES_PER_SECOND: u64 = 300;
+// Largest part a 24 bit address reaches
+const MAX_SIZE: u64 = 1 << 24;
+
+const PROGRAM_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Erase granularities: the chip's smallest and largest erase types, and
+/// the whole chip.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Erase {
+    Sector,
+    Block,
+    Chip,
+}
+
+impl Erase {
+    // Size and opcode
+    fn command(self, chip: &Chip, flash: &SpiFlash) -> Result<(u32, u8)> {
+        match self {
+            Erase::Sector => flash.erase_types.first().copied(),
+            Erase::Block => flash.erase_types.last().copied(),
+            Erase::Chip => Some((chip.size, CHIP_ERASE)),
+        }
+        .ok_or_else(|| anyhow!("{} has no erase types", chip.name))
+    }
+
+    // Worst cases from the Winbond datasheets, with room for polling
+    fn timeout(self) -> Duration {
+        match self {
+            Erase::Sector => Duration::from_secs(1),
+            Erase::Block => Duration::from_secs(3),
+            Erase::Chip => Duration::from_secs(200),
+        }
+    }
+}
+
+/// Status register 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Status(pub u8);
+
+impl Status {
+    pub fn busy(self) -> bool {
+        self.0 & 0x01 > 0
+    }
+
+    pub fn write_enabled(self) -> bool {
+        self.0 & 0x02 > 0
+    }
+
+    /// BP0-BP2. How much of the array they cover depends on the part.
+    pub fn block_protect(self) -> u8 {
+        (self.0 >> 2) & 0x07
+    }
+
+    /// SRP0: the status register is locked while /WP is low
+    pub fn status_protect(self) -> bool {
+        self.0 & 0x80 > 0
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}: {}, write {}, block protect {}{}",
+            self.0,
+            if self.busy() { "busy" } else { "ready" },
+            if self.write_enabled() {
+                "enabled"
+            } else {
+                "disabled"
+            },
+            self.block_protect(),
+            if self.status_protect() {
+                ", status register protected"
+            } else {
+                ""
+            }
+        )
+    }
+}
+
+/// What a chip's Serial Flash Discoverable Parameters say about it. Only
+/// the basic flash parameter table is read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sfdp {
+    /// (major, minor) of the SFDP header
+    pub revision: (u8, u8),
+    /// Array size in bytes
+    pub size: u64,
+    /// (size in bytes, opcode) of each supported erase type
+    pub erase_types: Vec<(u32, u8)>,
+    /// Only in JESD216A and later tables
+    pub page_size: Option<u32>,
+}
+
+impl fmt::Display for Sfdp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "SFDP {}.{}", self.revision.0, self.revision.1)?;
+        writeln!(f, "Size: {} bytes", self.size)?;
+        for (size, opcode) in &self.erase_types {
+            writeln!(f, "Erase: {} bytes with {:02x}", size, opcode)?;
+        }
+        match self.page_size {
+            Some(size) => write!(f, "Page: {} bytes", size),
+            None => write!(f, "Page: not listed"),
+        }
+    }
+}
+
+const SFDP_SIGNATURE: &[u8] = b"SFDP";
+const BASIC_TABLE: u16 = 0xff00;
+
+/// Reads the manufacturer, memory type and capacity bytes.
+pub fn jedec_id<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+) -> Result<Signature> {
+    powered(handle, chip, |spi| {
+        let id = spi.command(&[READ_ID], 3)?;
+        Ok(Signature {
+            manufacturer: id[0],
+            device: u16::from_be_bytes([id[1], id[2]]),
+        })
+    })
+}
+
+/// Reads the first `len` bytes.
+pub fn read<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    len: usize,
+    tracker: &mut Tracker,
+) -> Result<Vec<u8>> {
+    powered(handle, chip, |spi| {
+        spi.select(true)?;
+        spi.write(&command(READ, 0))?;
+        let mut image = Vec::with_capacity(len);
+        while image.len() < len {
+            let chunk = READ_CHUNK.min(len - image.len());
+            image.extend(spi.read(chunk)?);
+            tracker.update(image.len() as u64, image.len() as u32 - 1)?;
+        }
+        spi.select(false)?;
+        Ok(image)
+    })
+}
+
+/// Programs `image` from address 0 a page at a time, skipping pages that
+/// are all `ff`. The chip must already be erased.
+pub fn program<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    flash: &SpiFlash,
+    image: &[u8],
+    tracker: &mut Tracker,
+) -> Result<()> {
+    let page_size = flash.page_size as usize;
+    powered(handle, chip, |spi| {
+        for (page, bytes) in image.chunks(page_size).enumerate() {
+            let addr = (page * page_size) as u32;
+            if bytes.iter().any(|&b| b != 0xff) {
+                spi.command(&[WRITE_ENABLE], 0)?;
+                spi.select(true)?;
+                spi.write(&command(PAGE_PROGRAM, addr))?;
+                spi.write(bytes)?;
+                spi.select(false)?;
+                wait(spi, PROGRAM_TIMEOUT)?;
+            }
+            let end = addr + bytes.len() as u32;
+            tracker.update(u64::from(end), end - 1)?;
+        }
+        Ok(())
+    })
+}
+
+/// Erases the sector, block or whole chip containing `address`. Returns the
+/// start and size of what was erased.
+pub fn erase<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    flash: &SpiFlash,
+    erase: Erase,
+    address: u32,
+) -> Result<(u32, u32)> {
+    if address >= chip.size {
+        bail!("{:#x} is past the end of {}", address, chip.name);
+    }
+    let (size, opcode) = erase.command(chip, flash)?;
+    let start = address - address % size;
+
+    powered(handle, chip, |spi| {
+        spi.command(&[WRITE_ENABLE], 0)?;
+        if erase == Erase::Chip {
+            spi.command(&[opcode], 0)?;
+        } else {
+            spi.command(&command(opcode, start), 0)?;
+        }
+        wait(spi, erase.timeout())
+    })?;
+    Ok((start, size))
+}
+
+pub fn status<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+) -> Result<Status> {
+    powered(handle, chip, read_status)
+}
+
+/// Sets BP0-BP2, keeping the rest of the status register. Returns the
+/// status read back.
+pub fn set_block_protect<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    bits: u8,
+) -> Result<Status> {
+    if bits > 7 {
+        bail!("Block protect is three bits, got {}", bits);
+    }
+    powered(handle, chip, |spi| {
+        let status = read_status(spi)?;
+        let value = (status.0 & !0x1c) | (bits << 2);
+        spi.command(&[WRITE_ENABLE], 0)?;
+        spi.command(&[WRITE_STATUS, value], 0)?;
+        wait(spi, PROGRAM_TIMEOUT)?;
+
+        let status = read_status(spi)?;
+        if status.block_protect() != bits {
+            bail!("Status register didn't take the write ({})", status);
+        }
+        Ok(status)
+    })
+}
+
+/// Reads and parses the SFDP header and basic flash parameter table.
+pub fn sfdp<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+) -> Result<Sfdp> {
+    powered(handle, chip, |spi| {
+        let header = read_sfdp(spi, 0, 8)?;
+        if header[..4] != *SFDP_SIGNATURE {
+            bail!("No SFDP signature, the chip may predate SFDP");
+        }
+        let count = usize::from(header[6]) + 1;
+        let headers = read_sfdp(spi, 8, count * 8)?;
+        let (pointer, len) = find_basic_table(&headers)?;
+        let table = read_sfdp(spi, pointer, len * 4)?;
+        parse_sfdp(&header, &table)
+    })
+}
+
+/// Reads a chip's JEDEC ID and SFDP, for parts the database doesn't have,
+/// and checks an entry can be built from them. `probe` is any entry with
+/// the right pinout and supply to read them with, see
+/// `chip::spi_flash_probe`.
+pub fn discover<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    probe: &Chip,
+) -> Result<(Signature, Sfdp)> {
+    let signature = jedec_id(handle, probe)?;
+    let sfdp = sfdp(handle, probe)?;
+    if sfdp.size > MAX_SIZE {
+        bail!(
+            "SFDP says {} bytes, 4 byte addressing isn't supported",
+            sfdp.size
+        );
+    }
+    if sfdp.erase_types.is_empty() {
+        bail!("SFDP lists no erase types");
+    }
+    Ok((signature, sfdp))
+}
+
+/// Roughly how long reading `size` bytes over bit-banged SPI takes.
+pub fn read_time(size: u64) -> Duration {
+    Duration::from_secs(size / BYTES_PER_SECOND)
+}
+
+/// Parses the SFDP header and the basic flash parameter table, both as read
+/// from the chip.
+pub fn parse_sfdp(header: &[u8], table: &[u8]) -> Result<Sfdp> {
+    if header.len() < 8 || &header[..4] != SFDP_SIGNATURE {
+        bail!("No SFDP signature");
+    }
+    let dwords: Vec<u32> = table
+        .chunks_exact(4)
+        .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect();
+    if dwords.len() < 9 {
+        bail!(
+            "Basic flash parameter table is only {} dwords",
+            dwords.len()
+        );
+    }
+
+    let density = dwords[1];
+    let bits = if density & 0x8000_0000 == 0 {
+        u64::from(density) + 1
+    } else {
+        1u64.checked_shl(density & 0x7fff_ffff)
+            .ok_or_else(|| anyhow!("Density {:#x} is out of range", density))?
+    };
+
+    let erase_types = [dwords[7], dwords[8]]
+        .iter()
+        .flat_map(|d| vec![d & 0xffff, d >> 16])
+        .filter(|t| t & 0xff != 0)
+        .map(|t| (1u32 << (t & 0x1f), (t >> 8) as u8))
+        .collect();
+
+    let page_size = dwords.get(10).map(|d| 1u32 << ((d >> 4) & 0x0f));
+
+    Ok(Sfdp {
+        revision: (header[5], header[4]),
+        size: bits / 8,
+        erase_types,
+        page_size,
+    })
+}
+
+// Pointer and length in dwords of the basic table
+fn find_basic_table(headers: &[u8]) -> Result<(u32, usize)> {
+    headers
+        .chunks_exact(8)
+        .find(|h| u16::from_be_bytes([h[7], h[0]]) == BASIC_TABLE)
+        .map(|h| (u32::from_le_bytes([h[4], h[5], h[6], 0]), usize::from(h[3])))
+        .ok_or_else(|| anyhow!("No basic flash parameter table"))
+}
+
+fn read_sfdp<T: UsbContext>(
+    spi: &mut Spi<T>,
+    addr: u32,
+    len: usize,
+) -> Result<Vec<u8>> {
+    let mut request = command(READ_SFDP, addr).to_vec();
+    request.push(0x00);
+    spi.command(&request, len)
+}
+
+fn read_status<T: UsbContext>(spi: &mut Spi<T>) -> Result<Status> {
+    Ok(Status(spi.command(&[READ_STATUS], 1)?[0]))
+}
+
+fn wait<T: UsbContext>(spi: &mut Spi<T>, timeout: Duration) -> Result<()> {
+    let start = Instant::now();
+    while read_status(spi)?.busy() {
+        if start.elapsed() > timeout {
+            bail!("Still busy after {:?}", timeout);
+        }
+    }
+    Ok(())
+}
+
+// Opcode and 24 bit address
+fn command(opcode: u8, addr: u32) -> [u8; 4] {
+    let a = addr.to_be_bytes();
+    [opcode, a[1], a[2], a[3]]
+}
+
+// Loads the pins and runs `f` with VCC at the chip's lowest level,
+// switching it off afterwards whatever happens
+fn powered<T: UsbContext, R>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    f: impl FnOnce(&mut Spi<T>) -> Result<R>,
+) -> Result<R> {
+    let level = *chip
+        .vcc
+        .first()
+        .ok_or_else(|| anyhow!("{} has no supply voltage", chip.name))?;
+    let mut spi = Spi::new(handle, &chip.pin_config()?)?;
+    voltage::set_vcc(spi.handle(), chip, level)?;
+    voltage::vcc_on(spi.handle())?;
+    let result = f(&mut spi);
+    voltage::vcc_off(spi.handle())?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SFDP 1.6 header with one parameter header
+    const HEADER: [u8; 8] = [b'S', b'F', b'D', b'P', 0x06, 0x01, 0x00, 0xff];
+
+    // Basic table of a 1 MB part with 4 KB (20), 32 KB (52) and 64 KB (d8)
+    // erases and 256 byte pages
+    fn table(dwords: usize) -> Vec<u8> {
+        let mut table = vec![0u32; dwords];
+        table[1] = 0x007f_ffff;
+        table[7] = 0x520f_200c;
+        table[8] = 0x0000_d810;
+        if dwords > 10 {
+            table[10] = 0x80;
+        }
+        table
+            .iter()
+            .flat_map(|d| d.to_le_bytes().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn parses_basic_table() {
+        let sfdp = parse_sfdp(&HEADER, &table(16)).unwrap();
+        assert_eq!(sfdp.revision, (1, 6));
+        assert_eq!(sfdp.size, 0x10_0000);
+        assert_eq!(
+            sfdp.erase_types,
+            vec![(0x1000, 0x20), (0x8000, 0x52), (0x1_0000, 0xd8)]
+        );
+        assert_eq!(sfdp.page_size, Some(256));
+    }
+
+    #[test]
+    fn page_size_only_in_longer_tables() {
+        assert_eq!(parse_sfdp(&HEADER, &table(9)).unwrap().page_size, None);
+    }
+
+    #[test]
+    fn density_as_power_of_two() {
+        let mut table = table(9);
+        table[4..8].copy_from_slice(&0x8000_0021u32.to_le_bytes());
+        assert_eq!(parse_sfdp(&HEADER, &table).unwrap().size, 1 << 30);
+
+        table[4..8].copy_from_slice(&0x8000_0040u32.to_le_bytes());
+        assert!(parse_sfdp(&HEADER, &table).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_header_and_short_table() {
+        let mut header = HEADER;
+        header[0] = b'X';
+        assert!(parse_sfdp(&header, &table(9)).is_err());
+        assert!(parse_sfdp(&HEADER[..4], &table(9)).is_err());
+        assert!(parse_sfdp(&HEADER, &table(9)[..32]).is_err());
+    }
+
+    #[test]
+    fn finds_basic_table() {
+        let headers = [
+            0x81, 0x00, 0x01, 0x02, 0x00, 0x01, 0x00, 0xff, //
+            0x00, 0x06, 0x01, 0x10, 0x30, 0x00, 0x00, 0xff,
+        ];
+        assert_eq!(find_basic_table(&headers).unwrap(), (0x30, 16));
+        assert!(find_basic_table(&headers[..8]).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use anyhow::{bail, Result};
+use std::fmt;
+
+/// A bit that didn't read the same on every pass.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnstableBit {
+    pub address: u32,
+    /// 0 is the least significant bit
+    pub bit: u8,
+    /// Passes that read the bit as 1
+    pub ones: usize,
+    pub passes: usize,
+}
+
+impl UnstableBit {
+    /// The voted value. A tie goes to 0: a cell loses charge towards
+    /// reading 1, so a bit that reads 0 at all was most likely programmed.
+    pub fn value(&self) -> bool {
+        self.ones * 2 > self.passes
+    }
+
+    /// Share of passes that agree with the vote.
+    pub fn confidence(&self) -> f64 {
+        let agreeing = if self.value() {
+            self.ones
+        } else {
+            self.passes - self.ones
+        };
+        agreeing as f64 / self.passes as f64
+    }
+}
+
+impl fmt::Display for UnstableBit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#06x} bit {}: 1 on {} of {} passes, voted {} ({:.0}%)",
+            self.address,
+            self.bit,
+            self.ones,
+            self.passes,
+            u8::from(self.value()),
+            self.confidence() * 100.0
+        )
+    }
+}
+
+/// Several reads of the same chip, voted into one image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stability {
+    /// Each bit as most passes read it
+    pub image: Vec<u8>,
+    pub passes: usize,
+    /// In address order
+    pub unstable: Vec<UnstableBit>,
+}
+
+impl Stability {
+    pub fn is_stable(&self) -> bool {
+        self.unstable.is_empty()
+    }
+
+    /// Addresses with at least one unstable bit.
+    pub fn unstable_bytes(&self) -> usize {
+        let mut addresses: Vec<u32> =
+            self.unstable.iter().map(|b| b.address).collect();
+        addresses.dedup();
+        addresses.len()
+    }
+}
+
+impl fmt::Display for Stability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_stable() {
+            return write!(
+                f,
+                "{} bytes, no bits flipped over {} passes",
+                self.image.len(),
+                self.passes
+            );
+        }
+        write!(
+            f,
+            "{} bits in {} bytes flipped over {} passes",
+            self.unstable.len(),
+            self.unstable_bytes(),
+            self.passes
+        )?;
+        for bit in &self.unstable {
+            write!(f, "\n{}", bit)?;
+        }
+        Ok(())
+    }
+}
+
+/// Votes each bit of `passes`, which must all be the same length.
+pub fn vote(passes: &[Vec<u8>]) -> Result<Stability> {
+    let len = match passes.first() {
+        Some(first) => first.len(),
+        None => bail!("Nothing to vote on"),
+    };
+    if passes.iter().any(|p| p.len() != len) {
+        bail!("Passes differ in length");
+    }
+
+    let mut image = vec![0; len];
+    let mut unstable = vec![];
+    for (address, byte) in image.iter_mut().enumerate() {
+        for bit in 0..8 {
+            let ones = passes
+                .iter()
+                .filter(|p| p[address] & (1 << bit) > 0)
+                .count();
+            let flipped = UnstableBit {
+                address: address as u32,
+                bit,
+                ones,
+                passes: passes.len(),
+            };
+            if flipped.value() {
+                *byte |= 1 << bit;
+            }
+            if ones != 0 && ones != passes.len() {
+                unstable.push(flipped);
+            }
+        }
+    }
+    Ok(Stability {
+        image,
+        passes: passes.len(),
+        unstable,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn agreeing_passes_are_stable() {
+        let stability = vote(&[vec![0x5a, 0xff], vec![0x5a, 0xff]]).unwrap();
+        assert!(stability.is_stable());
+        assert_eq!(stability.image, [0x5a, 0xff]);
+    }
+
+    #[test]
+    fn tie_votes_0() {
+        let stability = vote(&[vec![0x01], vec![0x00]]).unwrap();
+        assert_eq!(stability.image, [0x00]);
+        assert_eq!(
+            stability.unstable,
+            [UnstableBit {
+                address: 0,
+                bit: 0,
+                ones: 1,
+                passes: 2
+            }]
+        );
+        assert_eq!(stability.unstable[0].confidence(), 0.5);
+    }
+
+    #[test]
+    fn majority_wins() {
+        let stability =
+            vote(&[vec![0, 0x80], vec![0, 0x80], vec![0, 0x00]]).unwrap();
+        assert_eq!(stability.image, [0, 0x80]);
+        assert_eq!(stability.unstable.len(), 1);
+        assert_eq!(stability.unstable[0].address, 1);
+        assert_eq!(stability.unstable[0].bit, 7);
+        assert_eq!(stability.unstable_bytes(), 1);
+    }
+
+    #[test]
+    fn rejects_mismatched_passes() {
+        assert!(vote(&[]).is_err());
+        assert!(vote(&[vec![0; 2], vec![0; 3]]).is_err());
+    }
+}
@@ -0,0 +1,365 @@
+use crate::chip::Chip;
+use crate::poke;
+use anyhow::{anyhow, bail, Result};
+use rusb::{DeviceHandle, UsbContext};
+use std::fmt;
+
+// Level commands from the final stage of initialization.pcapng (frames
+// 672-712). The vendor software writes `1a 00 63` and later `1a 00 9d` as it
+// moves between chip families, and `42 05 87 0a 02 02 60` followed by
+// `... 76` before switching VCC on. We read the first as the VPP regulator
+// and the second as VCC.
+const SET_VPP: u8 = 0x1a;
+const SET_VCC: [u8; 6] = [0x42, 0x05, 0x87, 0x0a, 0x02, 0x02];
+// Follows the level writes in the capture, as `19 01`. `19 00` has never
+// been captured; `vpp_off` sends it on the assumption it's the opposite.
+const VPP_ENABLE: u8 = 0x19;
+
+// Levels whose regulator code has been checked with a meter on an empty
+// socket. None yet: the captured codes' voltages are assumptions and the
+// rest are fitted through them. Add a level here once it's been measured.
+const MEASURED_VCC: &[Vcc] = &[];
+const MEASURED_VPP: &[Vpp] = &[];
+
+/// Supply voltages the VCC regulator can be set to.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Vcc {
+    V3_0,
+    V3_3,
+    V3_6,
+    V4_5,
+    V4_75,
+    V5_0,
+    V5_25,
+    V5_5,
+    V6_25,
+}
+
+/// Programming voltages the VPP regulator can be set to.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Vpp {
+    V12_5,
+    V12_75,
+    V13_0,
+    V21_0,
+    V25_0,
+}
+
+impl Vcc {
+    pub const ALL: &'static [Vcc] = &[
+        Vcc::V3_0,
+        Vcc::V3_3,
+        Vcc::V3_6,
+        Vcc::V4_5,
+        Vcc::V4_75,
+        Vcc::V5_0,
+        Vcc::V5_25,
+        Vcc::V5_5,
+        Vcc::V6_25,
+    ];
+
+    pub fn millivolts(self) -> u32 {
+        match self {
+            Vcc::V3_0 => 3000,
+            Vcc::V3_3 => 3300,
+            Vcc::V3_6 => 3600,
+            Vcc::V4_5 => 4500,
+            Vcc::V4_75 => 4750,
+            Vcc::V5_0 => 5000,
+            Vcc::V5_25 => 5250,
+            Vcc::V5_5 => 5500,
+            Vcc::V6_25 => 6250,
+        }
+    }
+
+    /// Whether the level's regulator code has been checked with a meter,
+    /// see `MEASURED_VCC`. Unmeasured levels are never written.
+    pub fn is_measured(self) -> bool {
+        MEASURED_VCC.contains(&self)
+    }
+
+    // 0x60 and 0x76 are captured and taken as 5 V and 6.25 V, the rest are
+    // a linear fit through them (17.6 codes per volt). See `is_measured`.
+    fn code(self) -> u8 {
+        match self {
+            Vcc::V3_0 => 0x3d,
+            Vcc::V3_3 => 0x42,
+            Vcc::V3_6 => 0x47,
+            Vcc::V4_5 => 0x57,
+            Vcc::V4_75 => 0x5c,
+            Vcc::V5_0 => 0x60,
+            Vcc::V5_25 => 0x64,
+            Vcc::V5_5 => 0x69,
+            Vcc::V6_25 => 0x76,
+        }
+    }
+}
+
+impl Vpp {
+    pub const ALL: &'static [Vpp] =
+        &[Vpp::V12_5, Vpp::V12_75, Vpp::V13_0, Vpp::V21_0, Vpp::V25_0];
+
+    pub fn millivolts(self) -> u32 {
+        match self {
+            Vpp::V12_5 => 12500,
+            Vpp::V12_75 => 12750,
+            Vpp::V13_0 => 13000,
+            Vpp::V21_0 => 21000,
+            Vpp::V25_0 => 25000,
+        }
+    }
+
+    /// Whether the level's regulator code has been checked with a meter,
+    /// see `MEASURED_VPP`. Unmeasured levels are never written.
+    pub fn is_measured(self) -> bool {
+        MEASURED_VPP.contains(&self)
+    }
+
+    // 0x63 is captured and taken as 13 V, and 0x9d is taken as 21 V; the
+    // rest are a linear fit through them (7.25 codes per volt), and 0xba is
+    // extrapolated well past the 12-13 V codes. See `is_measured`.
+    fn code(self) -> u8 {
+        match self {
+            Vpp::V12_5 => 0x5f,
+            Vpp::V12_75 => 0x61,
+            Vpp::V13_0 => 0x63,
+            Vpp::V21_0 => 0x9d,
+            Vpp::V25_0 => 0xba,
+        }
+    }
+}
+
+impl fmt::Display for Vcc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        volts(f, self.millivolts())
+    }
+}
+
+impl fmt::Display for Vpp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        volts(f, self.millivolts())
+    }
+}
+
+fn volts(f: &mut fmt::Formatter<'_>, millivolts: u32) -> fmt::Result {
+    let s = format!("{}.{:03}", millivolts / 1000, millivolts % 1000);
+    write!(f, "{}V", s.trim_end_matches('0').trim_end_matches('.'))
+}
+
+/// Parses "5", "5V", "12.75" etc into a supported VCC level.
+pub fn parse_vcc(s: &str) -> Result<Vcc> {
+    let mv = parse_millivolts(s)?;
+    match Vcc::ALL.iter().find(|v| v.millivolts() == mv) {
+        Some(v) => Ok(*v),
+        None => bail!("Unsupported VCC level: {}", s),
+    }
+}
+
+/// Parses "12.5", "21V" etc into a supported VPP level.
+pub fn parse_vpp(s: &str) -> Result<Vpp> {
+    let mv = parse_millivolts(s)?;
+    match Vpp::ALL.iter().find(|v| v.millivolts() == mv) {
+        Some(v) => Ok(*v),
+        None => bail!("Unsupported VPP level: {}", s),
+    }
+}
+
+fn parse_millivolts(s: &str) -> Result<u32> {
+    let s = s.trim_end_matches(&['V', 'v'][..]);
+    let (whole, frac) = match s.find('.') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, ""),
+    };
+    if frac.len() > 3 {
+        bail!("Too many decimal places in {}", s);
+    }
+    let frac: u32 = format!("{:0<3}", frac).parse()?;
+    whole
+        .parse::<u32>()?
+        .checked_mul(1000)
+        .and_then(|mv| mv.checked_add(frac))
+        .ok_or_else(|| anyhow!("{} is out of range", s))
+}
+
+/// Checks `level` is safe for `chip`. Anything other than the chip's own
+/// supply voltages, or a level within its margins, is refused, and so are
+/// levels that haven't been measured.
+pub fn check_vcc(chip: &Chip, level: Vcc) -> Result<()> {
+    check_vcc_measured(level)?;
+    let margins = chip.vcc_margins();
+    if chip.vcc.contains(&level) {
+        return Ok(());
+    }
+    match margins {
+        Some((low, high)) if low <= level && level <= high => Ok(()),
+        Some((low, high)) => bail!(
+            "{} can't be run at {} VCC (allowed: {}, or {} to {} to verify)",
+            chip.name,
+            level,
+            list(chip.vcc),
+            low,
+            high
+        ),
+        None => bail!(
+            "{} can't be run at {} VCC (allowed: {})",
+            chip.name,
+            level,
+            list(chip.vcc)
+        ),
+    }
+}
+
+/// Checks `level` is safe for `chip`. VPP above the chip's programming
+/// voltage is always refused, lower is allowed, and levels that haven't
+/// been measured are refused for every chip.
+pub fn check_vpp(chip: &Chip, level: Vpp) -> Result<()> {
+    check_vpp_measured(level)?;
+    match chip.vpp {
+        None => bail!("{} doesn't take a programming voltage", chip.name),
+        Some(max) if level > max => bail!(
+            "{} would be damaged by {} VPP (programs at {})",
+            chip.name,
+            level,
+            max
+        ),
+        Some(_) => Ok(()),
+    }
+}
+
+fn check_vcc_measured(level: Vcc) -> Result<()> {
+    if !level.is_measured() {
+        bail!(
+            "{} VCC is unverified: its regulator code is fitted, not \
+             measured, check it with a meter on an empty socket first",
+            level
+        );
+    }
+    Ok(())
+}
+
+fn check_vpp_measured(level: Vpp) -> Result<()> {
+    if !level.is_measured() {
+        bail!(
+            "{} VPP is unverified: its regulator code is fitted, not \
+             measured, check it with a meter on an empty socket first",
+            level
+        );
+    }
+    Ok(())
+}
+
+fn list(levels: &[Vcc]) -> String {
+    levels
+        .iter()
+        .map(|l| l.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Sets the VCC regulator after checking the level against `chip`. This
+/// doesn't switch VCC on, see `vcc_on`.
+pub fn set_vcc<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    level: Vcc,
+) -> Result<()> {
+    check_vcc(chip, level)?;
+    write_vcc_level(handle, level)
+}
+
+/// Sets the VPP regulator after checking the level against `chip`. This
+/// doesn't switch VPP on, see `vpp_on`.
+pub fn set_vpp<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    chip: &Chip,
+    level: Vpp,
+) -> Result<()> {
+    check_vpp(chip, level)?;
+    write_vpp_level(handle, level)
+}
+
+// Not checked against a chip, for the self test and callers that already
+// did; unmeasured levels are still refused
+pub(crate) fn write_vcc_level<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    level: Vcc,
+) -> Result<()> {
+    check_vcc_measured(level)?;
+    let mut command = SET_VCC.to_vec();
+    command.push(level.code());
+    poke(handle, &command)?;
+    Ok(())
+}
+
+pub(crate) fn write_vpp_level<T: UsbContext>(
+    handle: &mut DeviceHandle<T>,
+    level: Vpp,
+) -> Result<()> {
+    check_vpp_measured(level)?;
+    poke(handle, &[SET_VPP, 0x00, level.code()])?;
+    Ok(())
+}
+
+pub fn vcc_on<T: UsbContext>(handle: &mut DeviceHandle<T>) -> Result<()> {
+    poke(handle, &[0x16])?;
+    Ok(())
+}
+
+pub fn vcc_off<T: UsbContext>(handle: &mut DeviceHandle<T>) -> Result<()> {
+    poke(handle, &[0x15])?;
+    Ok(())
+}
+
+pub fn vpp_on<T: UsbContext>(handle: &mut DeviceHandle<T>) -> Result<()> {
+    poke(handle, &[VPP_ENABLE, 0x01])?;
+    Ok(())
+}
+
+/// Sends `19 00`, which hasn't been captured, see `VPP_ENABLE`.
+pub fn vpp_off<T: UsbContext>(handle: &mut DeviceHandle<T>) -> Result<()> {
+    poke(handle, &[VPP_ENABLE, 0x00])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_millivolts() {
+        assert_eq!(parse_millivolts("5").unwrap(), 5000);
+        assert_eq!(parse_millivolts("5V").unwrap(), 5000);
+        assert_eq!(parse_millivolts("12.75").unwrap(), 12750);
+        assert_eq!(parse_millivolts("3.3v").unwrap(), 3300);
+        assert_eq!(parse_millivolts("0.005").unwrap(), 5);
+    }
+
+    #[test]
+    fn rejects_bad_millivolts() {
+        assert!(parse_millivolts("").is_err());
+        assert!(parse_millivolts("5.1234").is_err());
+        assert!(parse_millivolts("-5").is_err());
+        assert!(parse_millivolts("5.x").is_err());
+        assert!(parse_millivolts("4294968").is_err());
+    }
+
+    #[test]
+    fn parses_levels() {
+        assert_eq!(parse_vcc("4.75").unwrap(), Vcc::V4_75);
+        assert_eq!(parse_vpp("21V").unwrap(), Vpp::V21_0);
+        assert!(parse_vcc("4.8").is_err());
+        assert!(parse_vpp("12").is_err());
+        assert_eq!(Vpp::V12_75.to_string(), "12.75V");
+        assert_eq!(Vcc::V5_0.to_string(), "5V");
+    }
+
+    #[test]
+    fn unmeasured_levels_are_refused() {
+        for &level in Vcc::ALL {
+            assert_eq!(check_vcc_measured(level).is_ok(), level.is_measured());
+        }
+        for &level in Vpp::ALL {
+            assert_eq!(check_vpp_measured(level).is_ok(), level.is_measured());
+        }
+    }
+}